@@ -23,7 +23,8 @@ use std::{
     sync::Arc,
 };
 
-pub const BLOCK_NUM: usize = 0x4000;
+// 与 FileSystem::create 格式化的卷大小一致
+pub const BLOCK_NUM: usize = fat32::BLOCK_NUM as usize;
 pub const RESEVERD_SECTOR_NUM: usize = 32;
 pub const FAT_NUM: usize = 2;
 pub const FAT_SECTOR_NUM: usize = 128;
@@ -87,7 +88,7 @@ fn fs_pack() -> std::io::Result<()> {
     let ways = matche.get_one("ways to run").map(String::as_str).unwrap();
 
     // 创建虚拟块设备
-    // 打开虚拟块设备.这里我们在 Linux 上创建文件 ./target/fs.img 来新建一个虚拟块设备, 并将它的容量设置为 0x4000 个块.
+    // 打开虚拟块设备.这里我们在 Linux 上创建文件 ./target/fs.img 来新建一个虚拟块设备, 并将它的容量设置为 BLOCK_NUM 个块.
     // 在创建的时候需要将它的访问权限设置为可读可写.
     let block_file = Arc::new(BlockFile(RwLock::new({
         // 创建 / 打开文件, 设置权限
@@ -450,6 +451,11 @@ fn fs_pack() -> std::io::Result<()> {
                 let file_inode = file_inode.unwrap();
                 let (st_size, st_blksize, st_blocks, is_dir, time) = file_inode.stat();
                 println!("🐳 The size of {} is {} B.", name, st_size);
+                println!(
+                    "🐳 The allocated size of {} is {} B.",
+                    name,
+                    file_inode.allocated_size()
+                );
                 println!("🐳 The block size of {} is {} B.", name, st_blksize);
                 println!("🐳 The blocks of {} is {}.", name, st_blocks);
                 println!(
@@ -468,9 +474,10 @@ fn fs_pack() -> std::io::Result<()> {
                     println!("🐬 Get {} from fs.", name);
                     let file_name: Vec<&str> = name.split('/').collect();
                     let file_inode = curr_folder_inode.find(file_name).unwrap();
-                    let mut all_data: Vec<u8> = vec![0; file_inode.file_size() as usize];
-                    file_inode.read_at(0, &mut all_data);
-                    // 写入文件 保存到host文件系统中
+                    if file_inode.is_dir() {
+                        continue;
+                    }
+                    // 写入文件 保存到host文件系统中, 逐簇写入, 不读入整个文件
                     let mut target_file = StdFile::create(format!(
                         "{}{} {}",
                         target_path,
@@ -484,7 +491,9 @@ fn fs_pack() -> std::io::Result<()> {
                         name
                     ))
                     .unwrap();
-                    target_file.write_all(all_data.as_slice()).unwrap();
+                    file_inode
+                        .export_to(&mut |chunk| target_file.write_all(chunk).map_err(|_| ()))
+                        .unwrap();
                 }
             }
 
@@ -547,12 +556,12 @@ fn fs_pack() -> std::io::Result<()> {
                 // 清除所有文件 包括文件夹
                 while files.len() > 0 {
                     let inode = files.pop().unwrap();
-                    inode.clear();
+                    inode.clear().unwrap();
                 }
 
                 // 对于根目录要特殊处理目录项
                 let root_dir = Arc::clone(&root_inode);
-                root_dir.clear();
+                root_dir.clear().unwrap();
 
                 PATH.write().clear();
                 PATH.write().push_str(&format!("❂ {}   ~\n╰─❯ ", USER));
@@ -579,7 +588,7 @@ fn fs_pack() -> std::io::Result<()> {
             }
 
             "exit" => {
-                sync_all(); // fix bug: when exit, the data in block cache will not be written to disk
+                sync_all().expect("failed to sync block cache"); // fix bug: when exit, the data in block cache will not be written to disk
                 break;
             }
 
@@ -188,10 +188,22 @@
 // 2. FAT1 起始地址 = 保留扇区数 * 扇区大小
 // 3. 文件分配表区共保存了两个相同的文件分配表, 因为文件所占用的存储空间 (簇链) 及空闲空间的管理都是通过FAT实现的, 保存两个以便第一个损坏时, 还有第二个可用
 
+use alloc::{string::String, vec::Vec};
+
 use super::{
     LEAD_SIGNATURE, MAX_CLUSTER_FAT12, MAX_CLUSTER_FAT16, STRUCT_SIGNATURE, TRAIL_SIGNATURE,
 };
 
+// 引导扇区中的字符串字段以空格填充, 非 ASCII 字节以 '?' 代替
+fn ascii_trimmed(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| if b.is_ascii() { b as char } else { '?' })
+        .collect::<String>()
+        .trim_end_matches(' ')
+        .into()
+}
+
 /// BIOS Parameters
 /// *On-disk* data structure for partition information.
 #[derive(Debug, Copy, Clone)]
@@ -205,6 +217,7 @@ pub struct BIOSParameterBlock {
 
 /// We intend to realize fat32, so we don't need to care about fat12 and fat16.
 /// But we still reserve the fields of fat12 and fat16 for future maybe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FatType {
     FAT32,
     FAT16,
@@ -274,11 +287,48 @@ impl BIOSParameterBlock {
     }
 
     #[inline(always)]
-    /// Get FAT2 Offset
+    /// Get FAT2 Offset. Only meaningful when the volume has two FATs (see [`BIOSParameterBlock::fat_cnt`])
     pub fn fat2_offset(&self) -> usize {
         self.fat1_offset() + (self.bpb32.fat_sz32 as usize) * (self.basic_bpb.byts_per_sec as usize)
     }
 
+    #[inline(always)]
+    /// Get the offset of the `index`th FAT (0-based)
+    pub fn fat_offset(&self, index: usize) -> usize {
+        self.fat1_offset()
+            + index * (self.bpb32.fat_sz32 as usize) * (self.basic_bpb.byts_per_sec as usize)
+    }
+
+    /// Whether the FAT is mirrored at runtime into all FATs (ext_flags bit 7 clear)
+    pub fn is_fat_mirrored(&self) -> bool {
+        self.bpb32.ext_flags & 0x80 == 0
+    }
+
+    /// Index of the active FAT. Only meaningful when mirroring is disabled
+    pub fn active_fat_index(&self) -> usize {
+        // 活动 FAT 号超出 FAT 个数时视为 FAT1
+        let index = (self.bpb32.ext_flags & 0x0F) as usize;
+        if self.is_fat_mirrored() || index >= self.fat_cnt() {
+            0
+        } else {
+            index
+        }
+    }
+
+    /// Get the offset of the FAT that is read and written at runtime
+    pub fn active_fat_offset(&self) -> usize {
+        self.fat_offset(self.active_fat_index())
+    }
+
+    /// Offsets of the other FATs that must mirror every write to the active FAT
+    /// (empty when only one FAT is active)
+    pub fn mirror_fat_offsets(&self) -> Vec<usize> {
+        if !self.is_fat_mirrored() {
+            return Vec::new();
+        }
+        (1..self.fat_cnt()).map(|i| self.fat_offset(i)).collect()
+    }
+
     /// Get sector_per_cluster_usize as usize value
     pub fn sector_per_cluster(&self) -> usize {
         self.basic_bpb.sec_per_clus as usize
@@ -292,34 +342,29 @@ impl BIOSParameterBlock {
     /// The 32 in the above is the size of one FAT directory entry in bytes.
     /// Note also that this computation rounds up
     pub fn root_dir_sector_cnt(&self) -> usize {
-        ((self.basic_bpb.root_ent_cnt * 32) as usize + (self.basic_bpb.byts_per_sec - 1) as usize)
+        (self.basic_bpb.root_ent_cnt as usize * 32 + (self.basic_bpb.byts_per_sec - 1) as usize)
             / self.basic_bpb.byts_per_sec as usize
     }
 
     #[inline(always)]
     /// Total sectors of the data region
     pub fn data_sector_cnt(&self) -> usize {
-        // let mut fat_sz: usize = 0;
-        // if self.bpb.fat_sz16 != 0 {
-        //     fat_sz = self.bpb.fat_sz16 as usize;
-        // } else {
-        //     fat_sz = self.bpb32.fat_sz32 as usize;
-        // }
-        // let mut tot_sec: usize = 0;
-        // if self.bpb.tot_sec16 != 0 {
-        //     tot_sec = self.bpb.tot_sec16 as usize;
-        // } else {
-        //     tot_sec = self.bpb.tot_sec32 as usize;
-        // }
-        // tot_sec
-        //     - (self.bpb.rsvd_sec_cnt as usize)
-        //     - (self.bpb.num_fats as usize) * fat_sz
-        //     - self.root_dir_sector_cnt()
-
-        self.basic_bpb.tot_sec32 as usize
-            - (self.basic_bpb.rsvd_sec_cnt as usize)
-            - (self.basic_bpb.num_fats as usize) * (self.bpb32.fat_sz32 as usize)
-            - self.root_dir_sector_cnt()
+        // FAT12/16 使用 16 位的字段, 以便 fat_type() 能识别它们
+        let fat_sz = if self.basic_bpb.fat_sz16 != 0 {
+            self.basic_bpb.fat_sz16 as usize
+        } else {
+            self.bpb32.fat_sz32 as usize
+        };
+        let tot_sec = if self.basic_bpb.tot_sec16 != 0 {
+            self.basic_bpb.tot_sec16 as usize
+        } else {
+            self.basic_bpb.tot_sec32 as usize
+        };
+        // 损坏的 BPB 可能使结果为负, 此时视为 0
+        tot_sec
+            .saturating_sub(self.basic_bpb.rsvd_sec_cnt as usize)
+            .saturating_sub((self.basic_bpb.num_fats as usize) * fat_sz)
+            .saturating_sub(self.root_dir_sector_cnt())
     }
 
     /// The count of (data) clusters
@@ -349,6 +394,8 @@ impl BIOSParameterBlock {
         self.basic_bpb.sec_per_clus as usize * self.basic_bpb.byts_per_sec as usize
     }
 
+    /// The FAT type of the volume, determined by the count of data clusters alone as the
+    /// specification requires.
     pub fn fat_type(&self) -> FatType {
         if self.data_cluster_cnt() < MAX_CLUSTER_FAT12 {
             FatType::FAT12
@@ -363,6 +410,30 @@ impl BIOSParameterBlock {
         self.basic_bpb.byts_per_sec as usize
     }
 
+    /// Volume serial number (BS_VolID)
+    pub fn volume_serial(&self) -> u32 {
+        self.bpb32.bs_vol_id
+    }
+
+    /// OEM name of the tool that formatted the volume (e.g. "mk.fat32"), trailing spaces trimmed
+    pub fn oem_name(&self) -> String {
+        ascii_trimmed(&self.basic_bpb.bs_oem_name)
+    }
+
+    /// Volume label stored in the boot sector, trailing spaces trimmed.
+    ///
+    /// This copy is informational; the label shown by most systems is the root directory's
+    /// ATTR_VOLUME_ID entry.
+    pub fn volume_label_bpb(&self) -> String {
+        ascii_trimmed(&self.bpb32.bs_vol_lab)
+    }
+
+    /// File system type string (e.g. "FAT32"), trailing spaces trimmed. Informational only, it
+    /// does not determine the FAT type (see [`BIOSParameterBlock::fat_type`]).
+    pub fn fs_type_string(&self) -> String {
+        ascii_trimmed(&self.bpb32.bs_fil_sys_type)
+    }
+
     pub fn sectors_per_cluster(&self) -> usize {
         self.basic_bpb.sec_per_clus as usize
     }
@@ -761,3 +832,68 @@ impl FSInfo {
         self.nxt_free = start_cluster
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::BlockDevice, test_util::TestFs, FileSystem, FsError, BLOCK_SIZE, MAX_CLUSTER_FAT16,
+    };
+
+    // 卸载后修改 0 号扇区, 再尝试挂载
+    fn open_patched(patch: impl FnOnce(&mut [u8; BLOCK_SIZE])) -> Result<(), FsError> {
+        let t = TestFs::new();
+        drop(t.fs);
+        let mut boot = [0u8; BLOCK_SIZE];
+        t.device.read_blocks(&mut boot, 0, 1).unwrap();
+        patch(&mut boot);
+        t.device.write_blocks(&boot, 0, 1).unwrap();
+        FileSystem::try_open(t.device.clone()).map(|_| ())
+    }
+
+    #[test]
+    fn created_volume_is_fat32_by_cluster_count() {
+        let t = TestFs::new();
+        let bpb = t.fs.read().bpb();
+        assert!(bpb.data_cluster_cnt() >= MAX_CLUSTER_FAT16);
+        assert_eq!(bpb.fat_type(), FatType::FAT32);
+        // FAT 能容纳所有簇的表项
+        assert!(bpb.bpb32.fat_sz32 as usize * BLOCK_SIZE >= (bpb.data_cluster_cnt() + 2) * 4);
+    }
+
+    #[test]
+    fn fat16_range_volume_is_rejected() {
+        // FAT32 布局的 BPB, 但只有 0x4000 个扇区, 簇数落在 FAT16 范围
+        let result = open_patched(|boot| boot[32..36].copy_from_slice(&0x4000u32.to_le_bytes()));
+        assert_eq!(
+            result.err(),
+            Some(FsError::UnsupportedFatType(FatType::FAT16))
+        );
+    }
+
+    #[test]
+    fn zero_divisors_are_rejected() {
+        let result = open_patched(|boot| boot[13] = 0);
+        assert_eq!(result.err(), Some(FsError::InvalidBpb));
+        let result = open_patched(|boot| boot[11..13].fill(0));
+        assert_eq!(result.err(), Some(FsError::InvalidBpb));
+    }
+
+    #[test]
+    fn boot_sector_strings_are_trimmed() {
+        let t = TestFs::new();
+        let bpb = t.fs.read().bpb();
+        assert_eq!(bpb.oem_name(), "mk.fat32");
+        assert_eq!(bpb.volume_label_bpb(), "mkfs.fat32");
+        assert_eq!(bpb.fs_type_string(), "FAT32");
+        drop(t.fs);
+
+        // BS_VolLab (偏移 71) 中的非 ASCII 字节
+        let mut boot = [0u8; BLOCK_SIZE];
+        t.device.read_blocks(&mut boot, 0, 1).unwrap();
+        boot[71..82].copy_from_slice(b"DATA\xE9      ");
+        t.device.write_blocks(&boot, 0, 1).unwrap();
+        let fs = FileSystem::open(t.device.clone());
+        assert_eq!(fs.read().bpb().volume_label_bpb(), "DATA?");
+    }
+}
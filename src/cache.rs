@@ -9,7 +9,7 @@ use spin::{Mutex, RwLock};
 // use core::num::NonZeroUsize;
 // use alloc::collections::VecDeque;
 
-use super::device::BlockDevice;
+use super::device::{BlockDevice, DeviceErr};
 use super::{BLOCK_CACHE_LIMIT, BLOCK_SIZE};
 
 pub trait Cache {
@@ -27,7 +27,7 @@ pub trait Cache {
     ///
     /// - `block_ids`: block ids in this cache
     /// - `block_device`: The pointer to the block_device.
-    fn sync(&mut self);
+    fn sync(&mut self) -> Result<(), DeviceErr>;
 }
 
 pub struct BlockCache {
@@ -41,17 +41,15 @@ pub struct BlockCache {
 
 impl BlockCache {
     // load a block from the disk
-    pub fn new(block_id: usize, block_device: Arc<dyn BlockDevice>) -> Self {
+    pub fn new(block_id: usize, block_device: Arc<dyn BlockDevice>) -> Result<Self, DeviceErr> {
         let mut cache = vec![0 as u8; BLOCK_SIZE];
-        block_device
-            .read_blocks(&mut cache, block_id * BLOCK_SIZE, 1)
-            .unwrap();
-        Self {
+        block_device.read_blocks(&mut cache, block_id * BLOCK_SIZE, 1)?;
+        Ok(Self {
             cache,
             block_id,
             block_device,
             modified: false,
-        }
+        })
     }
 
     fn addr_of_offset(&self, offset: usize) -> usize {
@@ -90,26 +88,29 @@ impl Cache for BlockCache {
     }
 
     // write the content back to disk
-    fn sync(&mut self) {
+    fn sync(&mut self) -> Result<(), DeviceErr> {
         // TODO
         // 是否需要考虑引用计数
         if self.modified {
-            self.modified = false;
             self.block_device
-                .write_blocks(&self.cache, self.block_id * BLOCK_SIZE, 1)
-                .unwrap();
+                .write_blocks(&self.cache, self.block_id * BLOCK_SIZE, 1)?;
+            // 写回成功后才清除标记, 失败时下次 sync 会重试
+            self.modified = false;
         }
+        Ok(())
     }
 }
 
 impl Drop for BlockCache {
     fn drop(&mut self) {
-        self.sync()
+        // drop 中无法返回错误, 尽力写回
+        let _ = self.sync();
     }
 }
 
 pub struct BlockCacheManager {
-    lru: LruCache<usize, Arc<RwLock<BlockCache>>>,
+    // 以 (设备的 cache_id, block_id) 为键, 见 BlockDevice::cache_id
+    lru: LruCache<(u64, usize), Arc<RwLock<BlockCache>>>,
 }
 
 impl BlockCacheManager {
@@ -127,16 +128,17 @@ impl BlockCacheManager {
         &mut self,
         block_id: usize,
         block_device: Arc<dyn BlockDevice>,
-    ) -> Arc<RwLock<BlockCache>> {
+    ) -> Result<Arc<RwLock<BlockCache>>, DeviceErr> {
+        let key = (block_device.cache_id(), block_id);
         // if the block is already in lru_cache, just return the copy
-        if let Some(pair) = self.lru.get(&block_id) {
-            Arc::clone(pair)
+        if let Some(pair) = self.lru.get(&key) {
+            Ok(Arc::clone(pair))
         } else {
             // 如果不在 lru_cache 中, 就创建一个新的 block_cache
             let block_cache = Arc::new(RwLock::new(BlockCache::new(
                 block_id,
                 Arc::clone(&block_device),
-            )));
+            )?));
 
             // 如果 lru_cache 已经满了, 就把最久没有使用的 block_cache 写回磁盘(只有引用计数为 0 的时候才会 drop 写回磁盘)
             if self.lru.len() == BLOCK_CACHE_LIMIT {
@@ -144,20 +146,48 @@ impl BlockCacheManager {
                 if Arc::strong_count(peek_cache) == 1 {
                     // 如果 is_modified, 会写回磁盘
                     self.lru.pop_lru();
-                    self.lru.put(block_id, Arc::clone(&block_cache));
+                    self.lru.put(key, Arc::clone(&block_cache));
                 }
             } else {
                 // 否则直接插入
-                self.lru.put(block_id, Arc::clone(&block_cache));
+                self.lru.put(key, Arc::clone(&block_cache));
             }
-            block_cache
+            Ok(block_cache)
         }
     }
 
-    pub fn sync_all(&mut self) {
+    // 写回所有块, 返回第一个错误 (其余块仍会尝试写回)
+    pub fn sync_all(&mut self) -> Result<(), DeviceErr> {
+        let mut result = Ok(());
         for (_, block_cache) in self.lru.iter() {
-            block_cache.write().sync();
+            if let Err(e) = block_cache.write().sync() {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    // 写回并移除属于 cache_id 的所有块, 文件系统卸载时调用. 返回第一个写回错误
+    pub fn evict(&mut self, cache_id: u64) -> Result<(), DeviceErr> {
+        let keys: Vec<(u64, usize)> = self
+            .lru
+            .iter()
+            .map(|(key, _)| *key)
+            .filter(|(id, _)| *id == cache_id)
+            .collect();
+        let mut result = Ok(());
+        for key in keys {
+            if let Some(block_cache) = self.lru.pop(&key) {
+                if let Err(e) = block_cache.write().sync() {
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
         }
+        result
     }
 }
 
@@ -168,15 +198,32 @@ lazy_static! {
 }
 
 // used for external modules
+//
+// 读取设备失败时 panic, 需要处理错误时使用 try_get_block_cache
 pub fn get_block_cache(
     block_id: usize,
     block_device: Arc<dyn BlockDevice>,
 ) -> Arc<RwLock<BlockCache>> {
+    match try_get_block_cache(block_id, block_device) {
+        Ok(block_cache) => block_cache,
+        Err(e) => panic!("[fat32::cache] failed to load block {}: {}", block_id, e),
+    }
+}
+
+pub fn try_get_block_cache(
+    block_id: usize,
+    block_device: Arc<dyn BlockDevice>,
+) -> Result<Arc<RwLock<BlockCache>>, DeviceErr> {
     BLOCK_CACHE_MANAGER
         .lock()
         .get_block_cache(block_id, block_device)
 }
 
-pub fn sync_all() {
-    BLOCK_CACHE_MANAGER.lock().sync_all();
+pub fn sync_all() -> Result<(), DeviceErr> {
+    BLOCK_CACHE_MANAGER.lock().sync_all()
+}
+
+/// Write back and drop every cached block of the device with this [`BlockDevice::cache_id`]
+pub fn evict_cache(cache_id: u64) -> Result<(), DeviceErr> {
+    BLOCK_CACHE_MANAGER.lock().evict(cache_id)
 }
@@ -1,8 +1,14 @@
 //! Block device interface
 
+use alloc::{sync::Arc, vec};
 use core::any::Any;
+use core::fmt::{self, Display, Formatter};
 use core::marker::{Send, Sync};
 use core::result::Result;
+#[cfg(any(test, feature = "test-utils"))]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::BLOCK_SIZE;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DeviceErr {
@@ -10,6 +16,16 @@ pub enum DeviceErr {
     WriteError,
 }
 
+impl Display for DeviceErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            DeviceErr::ReadError => "failed to read block",
+            DeviceErr::WriteError => "failed to write block",
+        };
+        f.write_str(msg)
+    }
+}
+
 pub trait BlockDevice: Send + Sync + Any {
     /// Read block from BlockDevice
     ///
@@ -27,4 +43,424 @@ pub trait BlockDevice: Send + Sync + Any {
     /// - offset must be a multiple of BLOCK_SIZE
     /// - block_cnt = buf.len() / BLOCK_SIZE
     fn write_blocks(&self, buf: &[u8], offset: usize, _block_cnt: usize) -> Result<(), DeviceErr>;
+
+    /// Namespace of this device's blocks in the global block cache, which is keyed by
+    /// `(cache_id, block_id)`. Every mounted [`crate::FileSystem`] wraps its device with a
+    /// unique id, so implementations can keep the default.
+    fn cache_id(&self) -> u64 {
+        0
+    }
+}
+
+/// A [`BlockDevice`] that starts `base_block` blocks into another device, so a file system can
+/// live partway through a larger device (e.g. an MBR partition).
+pub struct OffsetDevice {
+    inner: Arc<dyn BlockDevice>,
+    base_block: usize,
+}
+
+impl OffsetDevice {
+    pub fn new(inner: Arc<dyn BlockDevice>, base_block: usize) -> Self {
+        Self { inner, base_block }
+    }
+
+    fn inner_offset(&self, offset: usize) -> usize {
+        assert!(
+            offset.is_multiple_of(BLOCK_SIZE),
+            "offset must be a multiple of BLOCK_SIZE"
+        );
+        self.base_block * BLOCK_SIZE + offset
+    }
+}
+
+impl BlockDevice for OffsetDevice {
+    fn read_blocks(
+        &self,
+        buf: &mut [u8],
+        offset: usize,
+        block_cnt: usize,
+    ) -> Result<(), DeviceErr> {
+        self.inner
+            .read_blocks(buf, self.inner_offset(offset), block_cnt)
+    }
+
+    fn write_blocks(&self, buf: &[u8], offset: usize, block_cnt: usize) -> Result<(), DeviceErr> {
+        self.inner
+            .write_blocks(buf, self.inner_offset(offset), block_cnt)
+    }
+}
+
+/// A [`BlockDevice`] wrapper that reads every written block back and compares it with what was
+/// written, returning [`DeviceErr::WriteError`] on mismatch. Catches silent write failures on
+/// unreliable media at the cost of an extra read per write.
+pub struct VerifyingDevice {
+    inner: Arc<dyn BlockDevice>,
+}
+
+impl VerifyingDevice {
+    pub fn new(inner: Arc<dyn BlockDevice>) -> Self {
+        Self { inner }
+    }
+}
+
+impl BlockDevice for VerifyingDevice {
+    fn read_blocks(
+        &self,
+        buf: &mut [u8],
+        offset: usize,
+        block_cnt: usize,
+    ) -> Result<(), DeviceErr> {
+        self.inner.read_blocks(buf, offset, block_cnt)
+    }
+
+    fn write_blocks(&self, buf: &[u8], offset: usize, block_cnt: usize) -> Result<(), DeviceErr> {
+        self.inner.write_blocks(buf, offset, block_cnt)?;
+        // 回读校验
+        let mut read_back = vec![0u8; buf.len()];
+        if self
+            .inner
+            .read_blocks(&mut read_back, offset, block_cnt)
+            .is_err()
+            || read_back != buf
+        {
+            return Err(DeviceErr::WriteError);
+        }
+        Ok(())
+    }
+}
+
+/// A RAM-backed [`BlockDevice`] of a fixed number of blocks, for tests.
+#[cfg(any(test, feature = "test-utils"))]
+pub struct MemBlockDevice(spin::RwLock<alloc::vec::Vec<u8>>);
+
+#[cfg(any(test, feature = "test-utils"))]
+impl MemBlockDevice {
+    pub fn new(block_cnt: usize) -> Self {
+        Self(spin::RwLock::new(alloc::vec![0u8; block_cnt * BLOCK_SIZE]))
+    }
+
+    pub fn block_cnt(&self) -> usize {
+        self.0.read().len() / BLOCK_SIZE
+    }
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+impl BlockDevice for MemBlockDevice {
+    fn read_blocks(
+        &self,
+        buf: &mut [u8],
+        offset: usize,
+        _block_cnt: usize,
+    ) -> Result<(), DeviceErr> {
+        let data = self.0.read();
+        match data.get(offset..offset + buf.len()) {
+            Some(src) => {
+                buf.copy_from_slice(src);
+                Ok(())
+            }
+            None => Err(DeviceErr::ReadError),
+        }
+    }
+
+    fn write_blocks(&self, buf: &[u8], offset: usize, _block_cnt: usize) -> Result<(), DeviceErr> {
+        let mut data = self.0.write();
+        match data.get_mut(offset..offset + buf.len()) {
+            Some(dst) => {
+                dst.copy_from_slice(buf);
+                Ok(())
+            }
+            None => Err(DeviceErr::WriteError),
+        }
+    }
+}
+
+/// A [`BlockDevice`] wrapper for testing error paths: it can be told to fail the Nth read or
+/// write (counting from 0), or every access touching a given block id.
+#[cfg(any(test, feature = "test-utils"))]
+pub struct FaultDevice {
+    inner: Arc<dyn BlockDevice>,
+    read_cnt: AtomicUsize,
+    write_cnt: AtomicUsize,
+    fail_read_at: spin::RwLock<Option<usize>>,
+    fail_write_at: spin::RwLock<Option<usize>>,
+    fail_block: spin::RwLock<Option<usize>>,
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+impl FaultDevice {
+    pub fn new(inner: Arc<dyn BlockDevice>) -> Self {
+        Self {
+            inner,
+            read_cnt: AtomicUsize::new(0),
+            write_cnt: AtomicUsize::new(0),
+            fail_read_at: spin::RwLock::new(None),
+            fail_write_at: spin::RwLock::new(None),
+            fail_block: spin::RwLock::new(None),
+        }
+    }
+
+    /// Fail the `n`th read from now on (0 = the next read)
+    pub fn fail_nth_read(&self, n: usize) {
+        let cnt = self.read_cnt.load(Ordering::SeqCst);
+        *self.fail_read_at.write() = Some(cnt + n);
+    }
+
+    /// Fail the `n`th write from now on (0 = the next write)
+    pub fn fail_nth_write(&self, n: usize) {
+        let cnt = self.write_cnt.load(Ordering::SeqCst);
+        *self.fail_write_at.write() = Some(cnt + n);
+    }
+
+    /// Fail every read and write that touches `block_id`
+    pub fn fail_block(&self, block_id: usize) {
+        *self.fail_block.write() = Some(block_id);
+    }
+
+    /// Number of `read_blocks` calls so far (including failed ones)
+    pub fn read_count(&self) -> usize {
+        self.read_cnt.load(Ordering::SeqCst)
+    }
+
+    /// Number of `write_blocks` calls so far (including failed ones)
+    pub fn write_count(&self) -> usize {
+        self.write_cnt.load(Ordering::SeqCst)
+    }
+
+    pub fn clear_faults(&self) {
+        *self.fail_read_at.write() = None;
+        *self.fail_write_at.write() = None;
+        *self.fail_block.write() = None;
+    }
+
+    fn hits_fail_block(&self, offset: usize, len: usize) -> bool {
+        match *self.fail_block.read() {
+            Some(block_id) => {
+                let start = offset / BLOCK_SIZE;
+                let end = (offset + len).div_ceil(BLOCK_SIZE);
+                (start..end).contains(&block_id)
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+impl BlockDevice for FaultDevice {
+    fn read_blocks(
+        &self,
+        buf: &mut [u8],
+        offset: usize,
+        block_cnt: usize,
+    ) -> Result<(), DeviceErr> {
+        let nth = self.read_cnt.fetch_add(1, Ordering::SeqCst);
+        if *self.fail_read_at.read() == Some(nth) || self.hits_fail_block(offset, buf.len()) {
+            return Err(DeviceErr::ReadError);
+        }
+        self.inner.read_blocks(buf, offset, block_cnt)
+    }
+
+    fn write_blocks(&self, buf: &[u8], offset: usize, block_cnt: usize) -> Result<(), DeviceErr> {
+        let nth = self.write_cnt.fetch_add(1, Ordering::SeqCst);
+        if *self.fail_write_at.read() == Some(nth) || self.hits_fail_block(offset, buf.len()) {
+            return Err(DeviceErr::WriteError);
+        }
+        self.inner.write_blocks(buf, offset, block_cnt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_util::{pattern, read_all, CacheGuard, TestFs},
+        Dir, FileSystem, VirtFileType,
+    };
+
+    // 写入 bad_block 时翻转第一个字节, 模拟静默写入错误的介质
+    struct CorruptingDevice {
+        inner: MemBlockDevice,
+        bad_block: usize,
+    }
+
+    impl BlockDevice for CorruptingDevice {
+        fn read_blocks(
+            &self,
+            buf: &mut [u8],
+            offset: usize,
+            block_cnt: usize,
+        ) -> Result<(), DeviceErr> {
+            self.inner.read_blocks(buf, offset, block_cnt)
+        }
+
+        fn write_blocks(
+            &self,
+            buf: &[u8],
+            offset: usize,
+            block_cnt: usize,
+        ) -> Result<(), DeviceErr> {
+            let mut data = buf.to_vec();
+            if offset / BLOCK_SIZE == self.bad_block {
+                data[0] ^= 0xFF;
+            }
+            self.inner.write_blocks(&data, offset, block_cnt)
+        }
+    }
+
+    #[test]
+    fn mem_device_round_trip() {
+        let device = MemBlockDevice::new(8);
+        assert_eq!(device.block_cnt(), 8);
+        let data = pattern(2 * BLOCK_SIZE, 1);
+        device.write_blocks(&data, 3 * BLOCK_SIZE, 2).unwrap();
+        let mut buf = vec![0u8; 2 * BLOCK_SIZE];
+        device.read_blocks(&mut buf, 3 * BLOCK_SIZE, 2).unwrap();
+        assert_eq!(buf, data);
+
+        // 超出设备末尾
+        let mut block = [0u8; BLOCK_SIZE];
+        assert_eq!(
+            device.read_blocks(&mut block, 8 * BLOCK_SIZE, 1),
+            Err(DeviceErr::ReadError)
+        );
+        assert_eq!(
+            device.write_blocks(&block, 8 * BLOCK_SIZE, 1),
+            Err(DeviceErr::WriteError)
+        );
+    }
+
+    #[test]
+    fn format_mem_device_and_reopen() {
+        let t = TestFs::new();
+        let data = pattern(3 * t.cluster_size() + 7, 2);
+        t.create_file("data.bin", &data);
+
+        let t = t.reopen();
+        let file = t.root().find(vec!["data.bin"]).unwrap();
+        assert_eq!(read_all(&file), data);
+    }
+
+    #[test]
+    fn fault_device_fails_nth_access() {
+        let fault = FaultDevice::new(Arc::new(MemBlockDevice::new(8)));
+        let mut block = [0u8; BLOCK_SIZE];
+
+        fault.fail_nth_read(1);
+        assert!(fault.read_blocks(&mut block, 0, 1).is_ok());
+        assert_eq!(
+            fault.read_blocks(&mut block, 0, 1),
+            Err(DeviceErr::ReadError)
+        );
+        assert!(fault.read_blocks(&mut block, 0, 1).is_ok());
+
+        fault.fail_nth_write(0);
+        assert_eq!(fault.write_blocks(&block, 0, 1), Err(DeviceErr::WriteError));
+        assert!(fault.write_blocks(&block, 0, 1).is_ok());
+
+        // 失败的访问也计数
+        assert_eq!(fault.read_count(), 3);
+        assert_eq!(fault.write_count(), 2);
+    }
+
+    #[test]
+    fn fault_device_fails_block() {
+        let fault = FaultDevice::new(Arc::new(MemBlockDevice::new(8)));
+        let mut blocks = [0u8; 2 * BLOCK_SIZE];
+
+        fault.fail_block(5);
+        assert!(fault.read_blocks(&mut blocks, 2 * BLOCK_SIZE, 2).is_ok());
+        // 多块访问中包含该块也会失败
+        assert_eq!(
+            fault.read_blocks(&mut blocks, 4 * BLOCK_SIZE, 2),
+            Err(DeviceErr::ReadError)
+        );
+        assert_eq!(
+            fault.write_blocks(&blocks[..BLOCK_SIZE], 5 * BLOCK_SIZE, 1),
+            Err(DeviceErr::WriteError)
+        );
+
+        fault.clear_faults();
+        assert!(fault.read_blocks(&mut blocks, 4 * BLOCK_SIZE, 2).is_ok());
+    }
+
+    #[test]
+    fn fault_device_fat_read_error_propagates() {
+        let t = TestFs::new();
+        let bpb = t.fs.read().bpb();
+        drop(t.fs);
+
+        // FAT[1] (卷状态) 所在的块读取失败
+        let fault = Arc::new(FaultDevice::new(t.device.clone()));
+        fault.fail_block(bpb.active_fat_offset() / BLOCK_SIZE);
+        assert_eq!(
+            FileSystem::try_open(fault).err(),
+            Some(crate::FsError::Device(DeviceErr::ReadError))
+        );
+    }
+
+    #[test]
+    fn offset_device_maps_to_base_block() {
+        let mem = Arc::new(MemBlockDevice::new(64));
+        let part = OffsetDevice::new(mem.clone(), 34);
+
+        let data = pattern(BLOCK_SIZE, 4);
+        part.write_blocks(&data, 0, 1).unwrap();
+        let mut raw = [0u8; BLOCK_SIZE];
+        mem.read_blocks(&mut raw, 34 * BLOCK_SIZE, 1).unwrap();
+        assert_eq!(&raw[..], &data[..]);
+
+        let data = pattern(BLOCK_SIZE, 5);
+        mem.write_blocks(&data, 36 * BLOCK_SIZE, 1).unwrap();
+        part.read_blocks(&mut raw, 2 * BLOCK_SIZE, 1).unwrap();
+        assert_eq!(&raw[..], &data[..]);
+
+        // 超出底层设备的访问仍然报错
+        assert_eq!(
+            part.read_blocks(&mut raw, 30 * BLOCK_SIZE, 1),
+            Err(DeviceErr::ReadError)
+        );
+    }
+
+    #[test]
+    fn verifying_device_detects_corrupt_block() {
+        let device = VerifyingDevice::new(Arc::new(CorruptingDevice {
+            inner: MemBlockDevice::new(8),
+            bad_block: 6,
+        }));
+        let block = [0x5Au8; BLOCK_SIZE];
+        assert!(device.write_blocks(&block, 5 * BLOCK_SIZE, 1).is_ok());
+        assert_eq!(
+            device.write_blocks(&block, 6 * BLOCK_SIZE, 1),
+            Err(DeviceErr::WriteError)
+        );
+        // 跨越损坏块的多块写入同样被发现
+        let blocks = [0x5Au8; 2 * BLOCK_SIZE];
+        assert_eq!(
+            device.write_blocks(&blocks, 6 * BLOCK_SIZE, 2),
+            Err(DeviceErr::WriteError)
+        );
+
+        let mut buf = [0u8; BLOCK_SIZE];
+        device.read_blocks(&mut buf, 5 * BLOCK_SIZE, 1).unwrap();
+        assert_eq!(buf, block);
+    }
+
+    #[test]
+    fn verifying_device_passes_through_file_system() {
+        let _cache = CacheGuard::shared();
+        let mem = Arc::new(MemBlockDevice::new(crate::BLOCK_NUM as usize));
+        let fs = FileSystem::create(Arc::new(VerifyingDevice::new(mem.clone())));
+        let data = pattern(5000, 3);
+        let file = fs
+            .read()
+            .root()
+            .create("v.bin", VirtFileType::File)
+            .unwrap();
+        assert_eq!(file.write_at(0, &data), data.len());
+        drop(file);
+        drop(fs);
+
+        let fs = FileSystem::open(mem);
+        let file = fs.read().root().find(vec!["v.bin"]).unwrap();
+        assert_eq!(read_all(&file), data);
+    }
 }
@@ -18,11 +18,12 @@
 //!
 //! 注意: Fat32 规定目录文件的大小为 0
 
-use alloc::{string::String, sync::Arc, vec::Vec};
+use alloc::{collections::BTreeSet, format, string::String, sync::Arc, vec::Vec};
 use core::{
     assert, assert_eq,
     clone::Clone,
-    convert::From,
+    fmt::{self, Display, Formatter},
+    iter::Iterator,
     option::Option,
     option::Option::{None, Some},
     result::Result,
@@ -31,13 +32,18 @@ use core::{
 use spin::RwLock;
 
 use super::{
-    entry::{LongDirEntry, ShortDirEntry},
-    generate_short_name, long_name_split, short_name_format, split_name_ext,
+    entry::{FatTimestamp, LongDirEntry, ShortDirEntry},
+    generate_short_name_n, glob_match, lfn_entry_count, lfn_name_eq_ignore_case,
+    lfn_name_from_parts, long_name_split, short_name_format, split_name_ext,
     vfs::{DirEntryPos, VirtFile, VirtFileType},
-    ALL_UPPER_CASE, ATTR_DIRECTORY, ATTR_LONG_NAME, DIRENT_SIZE, DIR_ENTRY_UNUSED, LAST_LONG_ENTRY,
-    NEW_VIR_FILE_CLUSTER,
+    ALL_UPPER_CASE, ATTR_DIRECTORY, ATTR_HIDDEN, ATTR_LONG_NAME, ATTR_SYSTEM, ATTR_VOLUME_ID,
+    DIRENT_SIZE, LAST_LONG_ENTRY, LONG_NAME_LEN_CAP, LONG_NAME_MAX_LEN, NEW_VIR_FILE_CLUSTER,
+    ROOT_DIR_ENTRY_CLUSTER,
 };
 
+// 长文件名最多占用的长名目录项个数
+const MAX_LFN_ENTRIES: usize = LONG_NAME_MAX_LEN.div_ceil(LONG_NAME_LEN_CAP);
+
 // TODO 虽然罗列了很多错误类型, 但是目前仅使用了部分
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DirError {
@@ -51,6 +57,83 @@ pub enum DirError {
     ListLFNIllegal,
     CreateFileError,
     MissingName,
+    DirNotEmpty,
+    IllegalMove,
+    NameTooLong,
+}
+
+impl Display for DirError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            DirError::NoMatchDir => "no such directory",
+            DirError::NoMatchFile => "no such file",
+            DirError::NoMatch => "no such file or directory",
+            DirError::IllegalChar => "illegal character in name",
+            DirError::DirHasExist => "directory already exists",
+            DirError::FileHasExist => "file already exists",
+            DirError::NotDir => "not a directory",
+            DirError::ListLFNIllegal => "illegal long name entry",
+            DirError::CreateFileError => "failed to create file",
+            DirError::MissingName => "missing name",
+            DirError::DirNotEmpty => "directory not empty",
+            DirError::IllegalMove => "cannot move into itself or move the root",
+            DirError::NameTooLong => "file name too long",
+        };
+        f.write_str(msg)
+    }
+}
+
+/// Why [`VirtFile::validate_entry_at`] rejected a directory entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryError {
+    /// The offset does not hold a live short entry (and no long entries precede it)
+    NotShortEntry,
+    /// The long entries before the short entry are not numbered n, n-1, .., 1 with the
+    /// last-entry flag on n
+    BadOrdinal,
+    /// A long entry's checksum differs from the short entry's name checksum
+    ChecksumMismatch,
+    /// Long entries are not followed by a short entry
+    OrphanLfn,
+}
+
+impl Display for EntryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            EntryError::NotShortEntry => "not a short directory entry",
+            EntryError::BadOrdinal => "long name entries out of order",
+            EntryError::ChecksumMismatch => "long name checksum mismatch",
+            EntryError::OrphanLfn => "long name entries without a short entry",
+        };
+        f.write_str(msg)
+    }
+}
+
+/// Malformed directory entries skipped while listing, as `(offset, error)` pairs
+pub type SkippedEntries = Vec<(usize, DirError)>;
+
+// scan_entries_to_end 的结果: (名字, 短目录项) 列表, 跳过的目录项, 最后一个未删除目录项之后的偏移
+type ScannedEntries = (Vec<(String, ShortDirEntry)>, SkippedEntries, usize);
+
+/// One entry of [`VirtFile::ls_long`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntryDetail {
+    pub name: String,
+    pub attr: u8,
+    pub file_size: u32,
+    pub first_cluster: u32,
+    pub modified: FatTimestamp,
+}
+
+/// Filters for [`VirtFile::ls_filtered`], the default hides everything optional
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LsOptions {
+    /// Include the "." and ".." entries of subdirectories
+    pub show_dot_entries: bool,
+    /// Include the volume label entry (ATTR_VOLUME_ID) of the root directory
+    pub show_volume_id: bool,
+    /// Include entries with ATTR_HIDDEN or ATTR_SYSTEM set
+    pub show_hidden: bool,
 }
 
 pub trait Dir {
@@ -87,7 +170,8 @@ impl Dir for VirtFile {
     fn remove(&self, path: Vec<&str>) -> Result<(), DirError> {
         match self.find(path) {
             Ok(file) => {
-                file.clear();
+                // 按路径找到的文件总有目录项, clear 不会失败
+                let _ = file.clear();
                 Ok(())
             }
             Err(e) => Err(e),
@@ -109,6 +193,308 @@ impl Dir for VirtFile {
 
     // Dir Functions
     fn create(&self, name: &str, file_type: VirtFileType) -> Result<VirtFile, DirError> {
+        self.create_entry(name, file_type, NEW_VIR_FILE_CLUSTER, 0)
+    }
+}
+
+/// Number of 32-byte directory entries `create` writes for `name`: its long entries plus the
+/// short entry. Names that fit 8.3 still get one long entry (as Linux does), so this is
+/// never less than 2.
+///
+/// ```
+/// use fat32::entries_needed;
+///
+/// assert_eq!(entries_needed("readme.txt"), 2);
+/// assert_eq!(entries_needed("a rather long file name.txt"), 4);
+/// ```
+pub fn entries_needed(name: &str) -> usize {
+    // 短文件名也会写一个长名目录项
+    let lfn_cnt = if fits_short_name(name) {
+        1
+    } else {
+        lfn_entry_count(name)
+    };
+    lfn_cnt + 1
+}
+
+// name 能否直接作为 8.3 短文件名, 否则需要生成 ~N 短文件名.
+// 8.3 名中最多一个 . (如 "archive.tar.gz" 需要生成 ARCHIV~1.GZ)
+fn fits_short_name(name: &str) -> bool {
+    let (name_, ext_) = split_name_ext(name);
+    name.is_ascii() && !name_.contains('.') && name_.len() <= 8 && ext_.len() <= 3
+}
+
+// 子目录开头的 "." 与 ".." 目录项, 列目录时默认不显示
+fn is_dot_or_dotdot(sde: &ShortDirEntry) -> bool {
+    sde.is_dot_entry() || sde.is_dotdot_entry()
+}
+
+// 短目录项中 11 字节的名字 (含 0x20 填充)
+fn raw_short_name(sde: &ShortDirEntry) -> [u8; 11] {
+    let mut key = [0u8; 11];
+    key.copy_from_slice(&sde.to_bytes_array()[..11]);
+    key
+}
+
+// 短文件名 (如 "LONGNA~1.TXT") 在短目录项中的 11 字节形式
+fn short_name_key(name: &str) -> [u8; 11] {
+    let (name, ext) = short_name_format(name);
+    let mut key = [0u8; 11];
+    key[..8].copy_from_slice(&name);
+    key[8..].copy_from_slice(&ext);
+    key
+}
+
+// 选取 short_names 中未被占用的最小 ~N 作为 long_name 的短文件名
+fn unique_short_name(long_name: &str, short_names: &BTreeSet<[u8; 11]>) -> String {
+    (1..)
+        .map(|n| generate_short_name_n(long_name, n))
+        .find(|short_name| !short_names.contains(&short_name_key(short_name)))
+        .unwrap()
+}
+
+/// Depth-first iterator over a directory tree, returned by [`VirtFile::walk`]
+pub struct Walk {
+    // 待遍历的目录: (相对路径, 目录, 下一个目录项的偏移)
+    stack: Vec<(String, VirtFile, usize)>,
+    // 已遍历目录的首簇号, 防止目录成环时无限遍历
+    visited: BTreeSet<u32>,
+}
+
+impl Iterator for Walk {
+    type Item = (String, VirtFile);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (prefix, dir, offset) = self.stack.last_mut()?;
+            let dirent = match dir.read_dir_from(*offset) {
+                Some(dirent) => dirent,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            *offset = dirent.offset;
+            if dirent.is_dot_entry() || dirent.is_dotdot_entry() {
+                continue;
+            }
+            let file = match dir.find_by_name(dirent.name.as_str()) {
+                Some(file) => file,
+                None => continue,
+            };
+            let path = if prefix.is_empty() {
+                dirent.name
+            } else {
+                format!("{}/{}", prefix, dirent.name)
+            };
+            if file.is_dir() && self.visited.insert(file.first_cluster() as u32) {
+                self.stack.push((path.clone(), file.clone(), 0));
+            }
+            return Some((path, file));
+        }
+    }
+}
+
+impl VirtFile {
+    // Dir Functions
+    /// 按名字查找目录中的文件
+    ///
+    /// 与规范一致, 同时在长名与短名空间中进行不区分大小写的比较,
+    /// 例如 "readme.txt", "README.TXT" 均可找到以 "ReadMe.txt" 创建的文件
+    pub fn find_by_name(&self, name: &str) -> Option<VirtFile> {
+        // 不是目录则退出
+        assert!(self.is_dir());
+        let (name, first_offset, sde_offset, sde) = self.find_entry(name)?;
+        // 存入长名目录项位置, 第一个 (序号最大) 在前
+        let lde_pos_vec: Vec<DirEntryPos> = (first_offset..sde_offset)
+            .step_by(DIRENT_SIZE)
+            .map(|offset| self.dir_entry_pos(offset).unwrap())
+            .collect();
+        Some(self.file_at(name, sde_offset, lde_pos_vec, &sde))
+    }
+
+    // 按名字查找目录项, 返回 (名字, 目录项组起始偏移, 短目录项偏移, 短目录项)
+    // 仅扫描目录项, 不构造 VirtFile
+    fn find_entry(&self, name: &str) -> Option<(String, usize, usize, ShortDirEntry)> {
+        let mut entry = LongDirEntry::empty();
+        // 逐项比较时不分配内存, 仅为匹配的目录项构造名字
+        let mut parts = [[0u16; LONG_NAME_LEN_CAP]; MAX_LFN_ENTRIES];
+        let mut index = 0;
+        // 扫描不超过目录已分配的簇
+        let dir_size = self.allocated_size();
+
+        while index < dir_size {
+            let read_size = self.read_lde_at(index, &mut entry);
+            // 读到 0x00 目录项, 其后不再有已分配的目录项
+            if read_size != DIRENT_SIZE || entry.is_empty() {
+                return None;
+            }
+            if entry.is_deleted() {
+                index += DIRENT_SIZE;
+                continue;
+            }
+            if entry.attr() == ATTR_LONG_NAME {
+                // 长文件名: 匹配完整的长文件名或其对应的短文件名
+                if let Some((cnt, sde, next_index)) = self.read_lfn_run(index, &mut parts) {
+                    if lfn_name_eq_ignore_case(&parts[..cnt], name) || sde.name_eq_ignore_case(name)
+                    {
+                        let long_name = lfn_name_from_parts(&parts[..cnt]);
+                        return Some((long_name, index, next_index - DIRENT_SIZE, sde));
+                    }
+                    index = next_index;
+                    continue;
+                }
+                // 孤立的长名目录项, 跳过
+                index += DIRENT_SIZE;
+                continue;
+            }
+            // 短文件名, 卷标目录项不是文件
+            let sde: ShortDirEntry = ShortDirEntry::from_bytes(&entry.to_bytes_array());
+            if !sde.is_volume_id() && sde.name_eq_ignore_case(name) {
+                return Some((sde.get_name_uppercase(), index, index, sde));
+            }
+            index += DIRENT_SIZE;
+        }
+        None
+    }
+
+    /// 路径是否存在 (与 find 的路径规则一致), 不构造最终匹配的 VirtFile
+    pub fn exists(&self, path: Vec<&str>) -> bool {
+        self.path_attr(path).is_some()
+    }
+
+    /// 路径是否存在且为目录
+    pub fn is_dir_path(&self, path: Vec<&str>) -> bool {
+        match self.path_attr(path) {
+            Some(attr) => attr & ATTR_DIRECTORY != 0,
+            None => false,
+        }
+    }
+
+    /// 路径是否存在且为文件
+    pub fn is_file_path(&self, path: Vec<&str>) -> bool {
+        match self.path_attr(path) {
+            Some(attr) => attr & ATTR_DIRECTORY == 0,
+            None => false,
+        }
+    }
+
+    // 返回路径最后一个分量的属性, 任一分量不存在 (或中间分量不是目录) 时立即返回 None
+    fn path_attr(&self, path: Vec<&str>) -> Option<u8> {
+        let components: Vec<&str> = path
+            .into_iter()
+            .filter(|name| !name.is_empty() && *name != ".")
+            .collect();
+        let (last, parents) = match components.split_last() {
+            Some(split) => split,
+            None => return Some(self.read_sde(|sde| sde.attr())),
+        };
+        let mut current = self.clone();
+        for name in parents {
+            if !current.is_dir() {
+                return None;
+            }
+            current = current.find_by_name(name)?;
+        }
+        if !current.is_dir() {
+            return None;
+        }
+        current.find_entry(last).map(|(_, _, _, sde)| sde.attr())
+    }
+
+    // 由 sde_offset 处的短目录项构造 VirtFile
+    fn file_at(
+        &self,
+        name: String,
+        sde_offset: usize,
+        lde_pos_vec: Vec<DirEntryPos>,
+        sde: &ShortDirEntry,
+    ) -> VirtFile {
+        let sde_pos = self.dir_entry_pos(sde_offset).unwrap();
+        let file_type = if sde.is_dir() {
+            VirtFileType::Dir
+        } else {
+            VirtFileType::File
+        };
+
+        let clus_chain = self.file_cluster_chain(sde_offset);
+
+        VirtFile::new(
+            name,
+            sde_pos,
+            lde_pos_vec,
+            Arc::clone(&self.fs),
+            Arc::new(RwLock::new(clus_chain)),
+            file_type,
+        )
+    }
+
+    /// 打开文件, 不存在时创建
+    ///
+    /// 已存在但类型不一致时返回 FileHasExist; 两种情况返回的 VirtFile 的 sde_pos/lde_pos 均有效.
+    /// truncate 为 true 时, 已存在的普通文件会被截断为 0 并释放其簇 (O_CREAT | O_TRUNC), 目录不受影响
+    pub fn open_or_create(
+        &self,
+        name: &str,
+        file_type: VirtFileType,
+        truncate: bool,
+    ) -> Result<VirtFile, DirError> {
+        if !self.is_dir() {
+            return Err(DirError::NotDir);
+        }
+        match self.find_by_name(name) {
+            Some(file) => {
+                if file.vir_file_type() == file_type {
+                    // 按名字找到的普通文件总有目录项, 截断不会失败
+                    if truncate && !file.is_dir() {
+                        let _ = file.truncate_to_zero();
+                    }
+                    Ok(file)
+                } else {
+                    Err(DirError::FileHasExist)
+                }
+            }
+            None => self.create(name, file_type),
+        }
+    }
+
+    /// 为已写好数据的簇链创建目录项, 不分配新簇 (用于镜像构建/导入)
+    ///
+    /// 簇链须以 EOC 结尾且能容纳 size 字节, 否则返回 CreateFileError;
+    /// 目录忽略 size, 其 "." 和 ".." 目录项会被重写
+    pub fn create_with_cluster(
+        &self,
+        name: &str,
+        file_type: VirtFileType,
+        first_cluster: u32,
+        size: usize,
+    ) -> Result<VirtFile, DirError> {
+        if !self.is_dir() {
+            return Err(DirError::NotDir);
+        }
+        let chain_len = match self.fs.read().chain_len_checked(first_cluster) {
+            Some(len) => len,
+            None => return Err(DirError::CreateFileError),
+        };
+        let size = if file_type == VirtFileType::Dir {
+            0
+        } else {
+            size
+        };
+        if size > chain_len * self.fs.read().cluster_size() {
+            return Err(DirError::CreateFileError);
+        }
+        self.create_entry(name, file_type, first_cluster, size as u32)
+    }
+
+    // 写入 name 的长/短目录项, 短目录项指向 first_cluster (NEW_VIR_FILE_CLUSTER 表示尚未分配簇)
+    fn create_entry(
+        &self,
+        name: &str,
+        file_type: VirtFileType,
+        first_cluster: u32,
+        file_size: u32,
+    ) -> Result<VirtFile, DirError> {
         // 检测同名文件
         assert!(self.is_dir());
         let option = self.find_by_name(name);
@@ -117,28 +503,120 @@ impl Dir for VirtFile {
                 return Err(DirError::FileHasExist);
             }
         }
-        let (name_, ext_) = split_name_ext(name);
         // 搜索空处
-        let mut entry_offset: usize;
-
-        match self.empty_entry_index() {
-            Ok(offset) => {
-                entry_offset = offset;
-            }
+        let entry_offset = match self.empty_entry_index(entries_needed(name)) {
+            Ok(offset) => offset,
             Err(e) => {
                 return Err(e);
             }
+        };
+        let mut short_names = self.short_names()?;
+        match self.write_entry_set(
+            entry_offset,
+            name,
+            file_type,
+            first_cluster,
+            file_size,
+            &mut short_names,
+        ) {
+            Ok((file, _)) => Ok(file),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 批量创建文件, 整个目录只扫描一次
+    ///
+    /// 新目录项依次追加在目录末尾 (不复用中间已删除的空位), 仅在当前簇写满时分配新簇.
+    /// 每一项的结果与 create 一致, 某项失败不影响其余各项
+    pub fn create_many(&self, entries: &[(&str, VirtFileType)]) -> Vec<Result<VirtFile, DirError>> {
+        let fail_all = |e: DirError| entries.iter().map(|_| Err(e)).collect();
+        if !self.is_dir() {
+            return fail_all(DirError::NotDir);
+        }
+        // 已有的名字 (长名与短名, 小写) 及是否为目录
+        let mut names: BTreeSet<(String, bool)> = BTreeSet::new();
+        let mut short_names: BTreeSet<[u8; 11]> = BTreeSet::new();
+        // 目录末尾的空闲位置, 其后的目录项均未使用
+        let mut offset = match self.scan_entries_to_end() {
+            Ok((list, _, end)) => {
+                for (name, sde) in list {
+                    let is_dir = sde.attr() & ATTR_DIRECTORY != 0;
+                    names.insert((name.to_lowercase(), is_dir));
+                    names.insert((sde.get_name_lowercase(), is_dir));
+                    short_names.insert(raw_short_name(&sde));
+                }
+                end
+            }
+            Err(e) => return fail_all(e),
+        };
+        entries
+            .iter()
+            .map(|&(name, file_type)| {
+                let key = (name.to_lowercase(), file_type == VirtFileType::Dir);
+                if names.contains(&key) {
+                    return Err(DirError::FileHasExist);
+                }
+                let (file, next_offset) = self.write_entry_set(
+                    offset,
+                    name,
+                    file_type,
+                    NEW_VIR_FILE_CLUSTER,
+                    0,
+                    &mut short_names,
+                )?;
+                offset = next_offset;
+                names.insert(key);
+                Ok(file)
+            })
+            .collect()
+    }
+
+    // 在 entry_offset 处写入 name 的目录项组并构造 VirtFile, 返回 (文件, 目录项组之后的偏移)
+    fn write_entry_set(
+        &self,
+        entry_offset: usize,
+        name: &str,
+        file_type: VirtFileType,
+        first_cluster: u32,
+        file_size: u32,
+        short_names: &mut BTreeSet<[u8; 11]>,
+    ) -> Result<(VirtFile, usize), DirError> {
+        if name.encode_utf16().count() > LONG_NAME_MAX_LEN {
+            return Err(DirError::NameTooLong);
+        }
+        let set_start = entry_offset;
+        let mut entry_offset = entry_offset;
+
+        // 先确保整段目录项 (可能跨越簇边界) 已分配, 避免写入长名目录项后分配失败留下孤立的长名目录项.
+        // 根目录与其他目录一样按簇链增长 (首簇为 bpb.root_cluster, 不会为 0)
+        let run_end = entry_offset + entries_needed(name) * DIRENT_SIZE;
+        let grow_cluster_cnt = if run_end > self.allocated_size() {
+            self.fs
+                .read()
+                .count_needed_clusters(run_end, self.first_cluster() as u32)
+        } else {
+            0
+        };
+        // 新目录还需要一个簇存放 "." 与 "..", 与目录的增长一起检查, 空间不足时不写入任何目录项
+        let new_dir_cluster_cnt =
+            (file_type == VirtFileType::Dir && first_cluster == NEW_VIR_FILE_CLUSTER) as usize;
+        if grow_cluster_cnt + new_dir_cluster_cnt > self.fs.read().free_cluster_cnt() {
+            return Err(DirError::CreateFileError);
+        }
+        if grow_cluster_cnt > 0 && self.incerase_size(run_end).is_err() {
+            return Err(DirError::CreateFileError);
         }
 
         // low -> high
         // lfn(n) -> lfn(n-1) -> .. -> lfn(1) -> sfn
         let mut sde: ShortDirEntry;
-        if name_.len() > 8 || ext_.len() > 3 {
+        if !fits_short_name(name) {
             // 长文件名
             // 生成短文件名及对应目录项
-            let short_name = generate_short_name(name);
+            let short_name = unique_short_name(name, short_names);
+            short_names.insert(short_name_key(&short_name));
             let (_name, _ext) = short_name_format(short_name.as_str());
-            sde = ShortDirEntry::new(NEW_VIR_FILE_CLUSTER, &_name, &_ext, file_type);
+            sde = ShortDirEntry::new(first_cluster, &_name, &_ext, file_type);
             sde.set_name_case(ALL_UPPER_CASE); // TODO
 
             // 长文件名拆分
@@ -161,28 +639,31 @@ impl Dir for VirtFile {
                     sde.gen_check_sum(),
                 );
                 // 写入长文件名目录项
-                let write_size = self.write_at(entry_offset, lde.as_bytes());
+                let write_size = self.write_at(entry_offset, &lde.to_bytes_array());
                 assert_eq!(write_size, DIRENT_SIZE);
                 // 更新写入位置
                 entry_offset += DIRENT_SIZE;
             }
         } else {
             // 短文件名
+            short_names.insert(short_name_key(name));
             let (_name, _ext) = short_name_format(name);
-            sde = ShortDirEntry::new(NEW_VIR_FILE_CLUSTER, &_name, &_ext, file_type);
+            sde = ShortDirEntry::new(first_cluster, &_name, &_ext, file_type);
             sde.set_name_case(ALL_UPPER_CASE); // TODO
 
             // Linux中文件创建都会创建一个长文件名目录项, 用于处理文件大小写问题
             let order: u8 = 1 | 0x40;
             let name_array = long_name_split(name)[0];
             let lde = LongDirEntry::new_form_name_slice(order, name_array, sde.gen_check_sum());
-            let write_size = self.write_at(entry_offset, lde.as_bytes());
+            let write_size = self.write_at(entry_offset, &lde.to_bytes_array());
             assert_eq!(write_size, DIRENT_SIZE);
             entry_offset += DIRENT_SIZE;
         }
 
+        sde.set_file_size(file_size);
+
         // 写短目录项(长文件名也是有短文件名目录项的)
-        let wirte_size = self.write_at(entry_offset, sde.as_bytes());
+        let wirte_size = self.write_at(entry_offset, &sde.to_bytes_array());
         assert_eq!(wirte_size, DIRENT_SIZE);
         assert!(
             self.first_cluster() >= 2,
@@ -190,193 +671,298 @@ impl Dir for VirtFile {
             self.first_cluster()
         );
 
-        // 验证
-        if let Some(file) = self.find_by_name(name) {
+        let lde_pos_vec: Vec<DirEntryPos> = (set_start..entry_offset)
+            .step_by(DIRENT_SIZE)
+            .map(|offset| self.dir_entry_pos(offset).unwrap())
+            .collect();
+        let file = self.file_at(String::from(name), entry_offset, lde_pos_vec, &sde);
+        {
             // 如果是目录类型, 需要创建.和..
             if file_type == VirtFileType::Dir {
                 // 先写入 .. 使得目录获取第一个簇 (否则 increase_size 不会分配簇而是直接返回, 导致 first_cluster 为 0, 进而 panic)
                 let (_name, _ext) = short_name_format("..");
-                let mut parent_sde = ShortDirEntry::new(
+                let parent_sde = ShortDirEntry::new(
                     self.first_cluster() as u32,
                     &_name,
                     &_ext,
                     VirtFileType::Dir,
                 );
                 // fat32 规定目录文件大小为 0, 不要更新目录文件的大小
-                file.write_at(DIRENT_SIZE, parent_sde.as_bytes_mut());
+                file.write_at(DIRENT_SIZE, &parent_sde.to_bytes_array());
 
                 let (_name, _ext) = short_name_format(".");
-                let mut self_sde = ShortDirEntry::new(
+                let self_sde = ShortDirEntry::new(
                     file.first_cluster() as u32,
                     &_name,
                     &_ext,
                     VirtFileType::Dir,
                 );
-                file.write_at(0, self_sde.as_bytes_mut());
+                file.write_at(0, &self_sde.to_bytes_array());
             }
-            Ok(file)
-        } else {
-            Err(DirError::CreateFileError)
         }
+        Ok((file, entry_offset + DIRENT_SIZE))
     }
-}
 
-impl VirtFile {
-    // Dir Functions
-    fn find_by_lfn(&self, name: &str) -> Option<VirtFile> {
-        let name_vec = long_name_split(name);
-        let name_cnt = name_vec.len();
-        //  在目录文件中的偏移
-        let mut index = 0;
-        let mut lde = LongDirEntry::empty();
-        let mut lde_pos_vec: Vec<DirEntryPos> = Vec::new();
-        let name_last = name_vec[name_cnt - 1].clone();
-        loop {
-            let mut read_size = self.read_at(index, lde.as_bytes_mut());
-            if read_size != DIRENT_SIZE {
-                return None;
+    /// 将自身重命名/移动为 new_parent 下的 new_name, 返回新的 VirtFile (self 随之失效)
+    ///
+    /// 目标已存在时: overwrite 为 false 返回 FileHasExist (类似 renameat2 的 NOREPLACE);
+    /// 为 true 时释放目标的簇并替换它 (类似 POSIX rename), 目标为目录时须为空目录.
+    /// 文件与目录不能互相覆盖. 目录的 ".." 会指向新的父目录
+    pub fn rename(
+        &self,
+        new_parent: &VirtFile,
+        new_name: &str,
+        overwrite: bool,
+    ) -> Result<VirtFile, DirError> {
+        if !new_parent.is_dir() {
+            return Err(DirError::NotDir);
+        }
+        // 根目录与通过首簇号打开的文件没有磁盘上的目录项
+        if self.sde_pos.cluster == ROOT_DIR_ENTRY_CLUSTER || self.detached_sde.is_some() {
+            return Err(DirError::IllegalMove);
+        }
+        // 目录不能移动到自身或其子目录下
+        if self.is_dir() {
+            let target = new_parent.first_cluster();
+            if target == self.first_cluster()
+                || self
+                    .walk()
+                    .any(|(_, file)| file.is_dir() && file.first_cluster() == target)
+            {
+                return Err(DirError::IllegalMove);
             }
+        }
 
-            // 先匹配最后一个长文件名目录项, 即长文件名的最后一块
-            if lde.attr() == ATTR_LONG_NAME // 防止为短文件名
-            && lde.name_utf16() == name_last
-            {
-                let mut order = lde.order();
-                if order & LAST_LONG_ENTRY == 0 || order == DIR_ENTRY_UNUSED {
-                    index += DIRENT_SIZE;
-                    continue;
+        // 只改变大小写时 find_by_name 找到的是自身
+        let mut replaced = None;
+        if let Some(dest) = new_parent.find_by_name(new_name) {
+            if dest.sde_pos != self.sde_pos {
+                if !overwrite {
+                    return Err(DirError::FileHasExist);
                 }
-                // 恢复 order为正确的次序值
-                order = order ^ LAST_LONG_ENTRY;
-                // 如果长文件名目录项数量对不上, 则跳过继续搜索
-                if order as usize != name_cnt {
-                    index += DIRENT_SIZE;
-                    continue;
-                }
-                // 如果 order 匹配通过, 开一个循环继续匹配长名目录项
-                let mut is_match = true;
-                for i in 1..order as usize {
-                    read_size = self.read_at(index + i * DIRENT_SIZE, lde.as_bytes_mut());
-                    if read_size != DIRENT_SIZE {
-                        return None;
-                    }
-                    // 匹配前一个名字段, 如果失败就退出
-                    if lde.name_utf16() != name_vec[name_cnt - 1 - i]
-                        || lde.attr() != ATTR_LONG_NAME
-                    {
-                        is_match = false;
-                        break;
-                    }
+                match (self.is_dir(), dest.is_dir()) {
+                    (false, true) => return Err(DirError::DirHasExist),
+                    (true, false) => return Err(DirError::NotDir),
+                    _ => {}
                 }
-                if is_match {
-                    // 如果成功, 读短目录项, 进行校验
-                    let checksum = lde.check_sum();
-                    let mut sde = ShortDirEntry::empty();
-                    let sde_offset = index + name_cnt * DIRENT_SIZE;
-                    read_size = self.read_at(sde_offset, sde.as_bytes_mut());
-                    if read_size != DIRENT_SIZE {
-                        return None;
-                    }
-                    if !sde.is_deleted() && checksum == sde.gen_check_sum() {
-                        let sde_pos = self.dir_entry_pos(sde_offset).unwrap();
-                        for i in 0..order as usize {
-                            // 存入长名目录项位置了, 第一个在栈顶
-                            let lde_pos = self.dir_entry_pos(index + i * DIRENT_SIZE);
-                            lde_pos_vec.push(lde_pos.unwrap());
+                if dest.is_dir() {
+                    match dest.ls() {
+                        Ok(names) => {
+                            if !names.is_empty() {
+                                return Err(DirError::DirNotEmpty);
+                            }
                         }
-                        let file_type = if sde.attr() == ATTR_DIRECTORY {
-                            VirtFileType::Dir
-                        } else {
-                            VirtFileType::File
-                        };
-
-                        let clus_chain = self.file_cluster_chain(sde_offset);
-
-                        return Some(VirtFile::new(
-                            String::from(name),
-                            sde_pos,
-                            lde_pos_vec,
-                            Arc::clone(&self.fs),
-                            Arc::new(RwLock::new(clus_chain)),
-                            file_type,
-                        ));
+                        Err(e) => return Err(e),
                     }
                 }
+                replaced = Some(dest);
             }
-            index += DIRENT_SIZE;
         }
+
+        // 先写入新的目录项 (名字过长或空间不足时什么也不写), 再删除旧目录项与被替换的目标,
+        // 任何一步失败都不会丢失原文件或目标. 此时旧目录项仍在, 因此不经过 create_entry 的同名检查
+        let old_sde = self.read_sde(|sde| *sde);
+        let entry_offset = new_parent.empty_entry_index(entries_needed(new_name))?;
+        let mut short_names = new_parent.short_names()?;
+        let (file, _) = new_parent.write_entry_set(
+            entry_offset,
+            new_name,
+            self.vir_file_type(),
+            old_sde.first_cluster(),
+            old_sde.file_size(),
+            &mut short_names,
+        )?;
+        file.modify_sde(|sde| sde.copy_metadata(&old_sde));
+        if let Some(dest) = replaced {
+            // 按名字找到的目标总有目录项, clear 不会失败
+            let _ = dest.clear();
+        }
+        self.delete_entries();
+        Ok(file)
     }
 
-    fn find_by_sfn(&self, name: &str) -> Option<VirtFile> {
-        let name = name.to_ascii_uppercase();
+    /// 递归创建目录 (mkdir -p), 返回最深一层的目录
+    ///
+    /// 与 find 一致, 路径相对于当前目录, 空的分量 (开头/结尾的 '/') 与 "." 会被跳过;
+    /// 路径已存在时直接返回, 路径中某一分量为文件时返回 NotDir
+    pub fn create_dir_all(&self, path: &str) -> Result<VirtFile, DirError> {
+        if !self.is_dir() {
+            return Err(DirError::NotDir);
+        }
+        let mut current = self.clone();
+        for name in path.split('/') {
+            if name.is_empty() || name == "." {
+                continue;
+            }
+            current = match current.find_by_name(name) {
+                Some(file) => {
+                    if !file.is_dir() {
+                        return Err(DirError::NotDir);
+                    }
+                    file
+                }
+                None => current.create(name, VirtFileType::Dir)?,
+            };
+        }
+        Ok(current)
+    }
 
+    // 查找可用目录项, 返回 offset, 簇不够也会返回相应的 offset
+    /// 搜索可容纳 cnt 个连续目录项的空位, 返回其起始偏移
+    ///
+    /// 已删除 (0xE5) 的目录项可被复用; 读到 0x00 目录项或目录文件末尾时,
+    /// 其后均为空闲, 当前连续空位 (若有) 即可使用
+    fn empty_entry_index(&self, cnt: usize) -> Result<usize, DirError> {
+        if !self.is_dir() {
+            return Err(DirError::NotDir);
+        }
         let mut sde = ShortDirEntry::empty();
         let mut index = 0;
-
+        // 当前连续空位的起始偏移及长度
+        let mut run_start = 0;
+        let mut run_len = 0;
         loop {
-            let read_size = self.read_at(index, sde.as_bytes_mut());
-
-            if read_size != DIRENT_SIZE {
-                return None;
+            let read_size = self.read_sde_at(index, &mut sde);
+            if read_size == 0 // 读到目录文件末尾 -> 超过 dir_size, 需要分配新簇 -> write_at 中处理 -> increase_size
+            || sde.is_empty()
+            {
+                if run_len > 0 {
+                    return Ok(run_start);
+                }
+                return Ok(index);
+            } else if sde.is_deleted() {
+                if run_len == 0 {
+                    run_start = index;
+                }
+                run_len += 1;
+                if run_len >= cnt {
+                    return Ok(run_start);
+                }
+            } else {
+                run_len = 0;
             }
+            index += DIRENT_SIZE;
+        }
+    }
 
-            // 判断名字是否一样
-            if !sde.is_deleted() && name == sde.get_name_uppercase() {
-                let sde_pos = self.dir_entry_pos(index).unwrap();
-                let lde_pos_vec: Vec<DirEntryPos> = Vec::new();
-                let file_type = if sde.attr() == ATTR_DIRECTORY {
-                    VirtFileType::Dir
-                } else {
-                    VirtFileType::File
-                };
-
-                let clus_chain = self.file_cluster_chain(index);
+    /// Depth-first traversal of the tree under this directory, yielding every descendant with
+    /// its path relative to `self` (components joined by `/`). `.` and `..` are skipped and a
+    /// directory reachable twice (a cycle) is only descended into once.
+    pub fn walk(&self) -> Walk {
+        let mut walk = Walk {
+            stack: Vec::new(),
+            visited: BTreeSet::new(),
+        };
+        if self.is_dir() {
+            walk.visited.insert(self.first_cluster() as u32);
+            walk.stack.push((String::new(), self.clone(), 0));
+        }
+        walk
+    }
 
-                return Some(VirtFile::new(
-                    String::from(name),
-                    sde_pos,
-                    lde_pos_vec,
-                    Arc::clone(&self.fs),
-                    Arc::new(RwLock::new(clus_chain)),
-                    file_type,
-                ));
+    /// 父目录, 由 ".." 目录项得到; 根目录返回 None
+    ///
+    /// 仅适用于目录: 普通文件没有 ".." 目录项, 无法由文件本身得到其父目录, 同样返回 None.
+    /// 返回的句柄与按路径打开的目录一致, 绑定其在祖父目录中的目录项
+    pub fn parent(&self) -> Option<VirtFile> {
+        if !self.is_dir() {
+            return None;
+        }
+        let root = self.fs.read().root();
+        let root_cluster = root.first_cluster() as u32;
+        if self.first_cluster() as u32 == root_cluster {
+            return None;
+        }
+        // ".." 指向根目录时其簇号为 0
+        let dir_at = |cluster: u32| {
+            if cluster == 0 || cluster == root_cluster {
+                Some((*root).clone())
             } else {
-                index += DIRENT_SIZE;
-                continue;
+                self.fs.read().file_from_cluster(cluster, true, 0).ok()
             }
+        };
+        let parent_cluster = self.dotdot_cluster()?;
+        let parent = dir_at(parent_cluster)?;
+        if parent.first_cluster() as u32 == root_cluster {
+            return Some(parent);
         }
+        // 在祖父目录中找到父目录的目录项
+        let grandparent = dir_at(parent.dotdot_cluster()?)?;
+        let (entries, _) = grandparent.scan_entries().ok()?;
+        let (name, _) = entries.iter().find(|(_, sde)| {
+            sde.is_dir() && !is_dot_or_dotdot(sde) && sde.first_cluster() == parent_cluster
+        })?;
+        grandparent.find_by_name(name)
     }
 
-    pub fn find_by_name(&self, name: &str) -> Option<VirtFile> {
-        // 不是目录则退出
-        assert!(self.is_dir());
-        let (name_, ext_) = split_name_ext(name);
-        if name_.len() > 8 || ext_.len() > 3 {
-            //长文件名
-            return self.find_by_lfn(name);
-        } else {
-            // 短文件名
-            return self.find_by_sfn(name);
+    // ".." 目录项记录的父目录首簇号, 父目录为根目录时为 0
+    fn dotdot_cluster(&self) -> Option<u32> {
+        let mut sde = ShortDirEntry::empty();
+        if self.read_sde_at(DIRENT_SIZE, &mut sde) != DIRENT_SIZE || !sde.is_dotdot_entry() {
+            return None;
         }
+        Some(sde.first_cluster())
     }
 
-    // 查找可用目录项, 返回 offset, 簇不够也会返回相应的 offset
-    fn empty_entry_index(&self) -> Result<usize, DirError> {
+    /// Entries of this directory (one level, `.`/`..` excluded) whose name matches `pattern`.
+    /// `*` matches any run of characters and `?` a single one; matching is case-insensitive.
+    pub fn find_glob(&self, pattern: &str) -> Vec<VirtFile> {
+        let mut files = Vec::new();
+        if !self.is_dir() {
+            return files;
+        }
+        let mut offset = 0;
+        while let Some(dirent) = self.read_dir_from(offset) {
+            offset = dirent.offset;
+            if dirent.is_dot_entry()
+                || dirent.is_dotdot_entry()
+                || !glob_match(pattern, &dirent.name)
+            {
+                continue;
+            }
+            if let Some(file) = self.find_by_name(dirent.name.as_str()) {
+                files.push(file);
+            }
+        }
+        files
+    }
+
+    /// Free the clusters at the tail of this directory that hold no live entries (only 0x00 or
+    /// 0xE5), keeping at least one cluster. Entries are not moved. Returns the number of clusters
+    /// freed.
+    pub fn trim_dir(&self) -> Result<usize, DirError> {
         if !self.is_dir() {
             return Err(DirError::NotDir);
         }
+        let first_cluster = self.first_cluster() as u32;
+        let clusters = self.fs.read().fat.read().get_all_cluster_id(first_cluster);
+        let cluster_size = self.fs.read().cluster_size();
+
+        // 从后向前找到最后一个含有效目录项的簇
+        let mut keep_cnt = 1;
         let mut sde = ShortDirEntry::empty();
-        let mut index = 0;
-        loop {
-            let read_size = self.read_at(index, sde.as_bytes_mut());
-            if read_size == 0 // 读到目录文件末尾 -> 超过 dir_size, 需要分配新簇 -> write_at 中处理 -> increase_size
-            || sde.is_empty()
-            {
-                return Ok(index);
-            } else {
-                index += DIRENT_SIZE;
+        'outer: for i in (1..clusters.len()).rev() {
+            for offset in (i * cluster_size..(i + 1) * cluster_size).step_by(DIRENT_SIZE) {
+                self.read_sde_at(offset, &mut sde);
+                if !sde.is_empty() && !sde.is_deleted() {
+                    keep_cnt = i + 1;
+                    break 'outer;
+                }
             }
         }
+
+        if keep_cnt == clusters.len() {
+            return Ok(0);
+        }
+        self.fs
+            .read()
+            .fat
+            .write()
+            .set_end_of_chain(clusters[keep_cnt - 1]);
+        let freed = clusters[keep_cnt..].to_vec();
+        let freed_cnt = freed.len();
+        self.fs.write().dealloc_cluster(freed);
+        Ok(freed_cnt)
     }
 
     pub fn vir_file_type(&self) -> VirtFileType {
@@ -387,19 +973,145 @@ impl VirtFile {
         }
     }
 
-    // 返回二元组, 第一个是文件名, 第二个是文件属性(文件或者目录)
+    // 按 opts 过滤 ls_with_attr 的结果
+    pub fn ls_filtered(&self, opts: LsOptions) -> Result<Vec<(String, u8)>, DirError> {
+        // ls_with_attr 不含卷标目录项, 这里直接使用 scan_entries
+        match self.scan_entries() {
+            Ok((entries, _)) => Ok(entries
+                .into_iter()
+                .filter(|(_, sde)| opts.show_dot_entries || !is_dot_or_dotdot(sde))
+                .map(|(name, sde)| (name, sde.attr()))
+                .filter(|(_, attr)| {
+                    if !opts.show_volume_id && attr & ATTR_VOLUME_ID != 0 {
+                        return false;
+                    }
+                    if !opts.show_hidden && attr & (ATTR_HIDDEN | ATTR_SYSTEM) != 0 {
+                        return false;
+                    }
+                    true
+                })
+                .collect()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // 返回二元组, 第一个是文件名, 第二个是文件属性(文件或者目录), 不含卷标目录项及 "." 与 ".."
     pub fn ls_with_attr(&self) -> Result<Vec<(String, u8)>, DirError> {
+        match self.ls_with_attr_checked() {
+            Ok((list, _)) => Ok(list),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 卷标目录项 (ATTR_VOLUME_ID, 仅出现在根目录) 记录的卷标, 不存在时返回 None
+    pub fn volume_label(&self) -> Option<String> {
+        let (entries, _) = self.scan_entries().ok()?;
+        entries
+            .iter()
+            .find(|(_, sde)| sde.is_volume_id())
+            .map(|(_, sde)| {
+                String::from_utf8_lossy(&raw_short_name(sde))
+                    .trim_end_matches(' ')
+                    .into()
+            })
+    }
+
+    /// Like [`VirtFile::ls_with_attr`], but also returns the malformed long-name entries that
+    /// were skipped, as `(offset, error)` pairs. A corrupt run never aborts the listing.
+    pub fn ls_with_attr_checked(&self) -> Result<(Vec<(String, u8)>, SkippedEntries), DirError> {
+        match self.scan_entries() {
+            Ok((entries, errors)) => Ok((
+                entries
+                    .into_iter()
+                    .filter(|(_, sde)| !sde.is_volume_id() && !is_dot_or_dotdot(sde))
+                    .map(|(name, sde)| (name, sde.attr()))
+                    .collect(),
+                errors,
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Detailed listing ("ls -l"): name, attributes, size, first cluster and modification time
+    /// of every entry except "." and "..", gathered in a single pass over the directory.
+    pub fn ls_long(&self) -> Result<Vec<DirEntryDetail>, DirError> {
+        match self.scan_entries() {
+            Ok((entries, _)) => Ok(entries
+                .into_iter()
+                .filter(|(_, sde)| !sde.is_volume_id() && !is_dot_or_dotdot(sde))
+                .map(|(name, sde)| DirEntryDetail {
+                    name,
+                    attr: sde.attr(),
+                    file_size: sde.file_size(),
+                    first_cluster: sde.first_cluster(),
+                    modified: sde.modified(),
+                })
+                .collect()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Entries of this directory ("." and ".." excluded) last written strictly after the FAT
+    /// `date`/`time` (same encoding as the directory entry fields), for incremental backups.
+    pub fn entries_modified_since(
+        &self,
+        date: u16,
+        time: u16,
+    ) -> Result<Vec<DirEntryDetail>, DirError> {
+        let since = FatTimestamp::from_raw(date, time);
+        let mut entries = self.ls_long()?;
+        entries.retain(|entry| entry.modified > since);
+        Ok(entries)
+    }
+
+    /// Like [`VirtFile::walk`], but only yields the descendants last written strictly after
+    /// `date`/`time`. Directories are still descended into when they are older themselves.
+    pub fn walk_modified_since(
+        &self,
+        date: u16,
+        time: u16,
+    ) -> impl Iterator<Item = (String, VirtFile)> {
+        let since = FatTimestamp::from_raw(date, time);
+        self.walk()
+            .filter(move |(_, file)| file.read_sde(|sde| sde.modified()) > since)
+    }
+
+    // 目录中已有的短文件名
+    fn short_names(&self) -> Result<BTreeSet<[u8; 11]>, DirError> {
+        let (list, _) = self.scan_entries()?;
+        Ok(list.iter().map(|(_, sde)| raw_short_name(sde)).collect())
+    }
+
+    /// 在该目录下创建 name 时短目录项将使用的名字 (如 `LONGNA~1.TXT`), 不创建文件
+    pub(crate) fn preview_short_name(&self, name: &str) -> Result<String, DirError> {
+        if fits_short_name(name) {
+            Ok(name.to_ascii_uppercase())
+        } else {
+            Ok(unique_short_name(name, &self.short_names()?))
+        }
+    }
+
+    // 遍历目录, 返回 (文件名, 短目录项) 列表及被跳过的异常目录项
+    fn scan_entries(&self) -> Result<(Vec<(String, ShortDirEntry)>, SkippedEntries), DirError> {
+        self.scan_entries_to_end()
+            .map(|(list, errors, _)| (list, errors))
+    }
+
+    // 同 scan_entries, 另外返回最后一个未删除目录项之后的偏移, 即 empty_entry_index(usize::MAX) 的结果
+    fn scan_entries_to_end(&self) -> Result<ScannedEntries, DirError> {
         if !self.is_dir() {
             return Err(DirError::NotDir);
         }
-        let mut list: Vec<(String, u8)> = Vec::new();
+        let mut list: Vec<(String, ShortDirEntry)> = Vec::new();
+        let mut errors: SkippedEntries = Vec::new();
         let mut entry = LongDirEntry::empty();
         let mut offset = 0usize;
+        let mut end = 0usize;
         loop {
-            let read_size = self.read_at(offset, entry.as_bytes_mut());
+            let read_size = self.read_lde_at(offset, &mut entry);
             // 读取完了
             if read_size != DIRENT_SIZE || entry.is_empty() {
-                return Ok(list);
+                return Ok((list, errors, end));
             }
             // 文件被标记删除则跳过
             if entry.is_deleted() {
@@ -408,24 +1120,1089 @@ impl VirtFile {
             }
             if entry.attr() != ATTR_LONG_NAME {
                 // 短文件名
-                let sde: ShortDirEntry = unsafe { core::mem::transmute(entry) };
-                list.push((sde.get_name_lowercase(), sde.attr()));
+                let sde: ShortDirEntry = ShortDirEntry::from_bytes(&entry.to_bytes_array());
+                list.push((sde.get_name_lowercase(), sde));
             } else {
                 // 长文件名
                 // 如果是长文件名目录项, 则必是长文件名最后的那一段
-                let mut name = String::new();
-                let order = entry.order() ^ LAST_LONG_ENTRY;
-                for _ in 0..order {
-                    name.insert_str(0, &entry.name().as_str());
-                    offset += DIRENT_SIZE;
-                    let read_size = self.read_at(offset, entry.as_bytes_mut());
-                    if read_size != DIRENT_SIZE || entry.is_empty() {
-                        return Err(DirError::ListLFNIllegal);
-                    }
+                if let Some((name, sde, next_offset)) = self.parse_lfn_run(offset) {
+                    list.push((name, sde));
+                    offset = next_offset;
+                    end = offset;
+                    continue;
                 }
-                list.push((name.clone(), entry.attr()));
+                // 孤立或不完整的长名目录项, 记录后跳过一个目录项继续
+                errors.push((offset, DirError::ListLFNIllegal));
             }
             offset += DIRENT_SIZE;
+            end = offset;
+        }
+    }
+
+    /// Offsets of the long entries in this directory that are not correctly associated with a
+    /// following short entry (broken ordinals, checksum mismatch, or a truncated run).
+    pub fn orphan_entries(&self) -> Vec<usize> {
+        let mut orphans = Vec::new();
+        if !self.is_dir() {
+            return orphans;
+        }
+        let mut entry = LongDirEntry::empty();
+        let mut parts = [[0u16; LONG_NAME_LEN_CAP]; MAX_LFN_ENTRIES];
+        let mut offset = 0usize;
+        loop {
+            let read_size = self.read_lde_at(offset, &mut entry);
+            if read_size != DIRENT_SIZE || entry.is_empty() {
+                return orphans;
+            }
+            if !entry.is_deleted() && entry.attr() == ATTR_LONG_NAME {
+                if let Some((_, _, next_offset)) = self.read_lfn_run(offset, &mut parts) {
+                    offset = next_offset;
+                    continue;
+                }
+                orphans.push(offset);
+            }
+            offset += DIRENT_SIZE;
+        }
+    }
+
+    /// Check that the short entry at `sde_offset` and the long entries right before it form a
+    /// valid set: ordinals n, n-1, .., 1 with the last-entry flag on n, and every checksum
+    /// equal to the short entry's [`ShortDirEntry::gen_check_sum`]. A short entry without long
+    /// entries is valid.
+    pub fn validate_entry_at(&self, sde_offset: usize) -> Result<(), EntryError> {
+        let mut lde = LongDirEntry::empty();
+        // 紧邻 offset 之前的目录项是否为未删除的长名目录项, 是则读入 lde
+        let lfn_before = |offset: usize, lde: &mut LongDirEntry| {
+            offset >= DIRENT_SIZE
+                && self.read_lde_at(offset - DIRENT_SIZE, lde) == DIRENT_SIZE
+                && !lde.is_free()
+                && lde.attr() == ATTR_LONG_NAME
+        };
+
+        let mut sde = ShortDirEntry::empty();
+        if !sde_offset.is_multiple_of(DIRENT_SIZE)
+            || self.read_sde_at(sde_offset, &mut sde) != DIRENT_SIZE
+            || sde.is_free()
+            || sde.attr() == ATTR_LONG_NAME
+        {
+            return Err(if lfn_before(sde_offset, &mut lde) {
+                EntryError::OrphanLfn
+            } else {
+                EntryError::NotShortEntry
+            });
+        }
+
+        // 向前逐个检查长名目录项, 第 k 个的序号应为 k, 遇到带结束标志的目录项为止
+        let check_sum = sde.gen_check_sum();
+        let mut offset = sde_offset;
+        let mut k = 0;
+        while lfn_before(offset, &mut lde) {
+            offset -= DIRENT_SIZE;
+            k += 1;
+            if k > MAX_LFN_ENTRIES || lde.lde_order() != k {
+                return Err(EntryError::BadOrdinal);
+            }
+            if lde.check_sum() != check_sum {
+                return Err(EntryError::ChecksumMismatch);
+            }
+            if lde.is_lde_end() {
+                return Ok(());
+            }
+        }
+        // 没有长名目录项, 或者缺少带结束标志的目录项
+        if k == 0 {
+            Ok(())
+        } else {
+            Err(EntryError::BadOrdinal)
+        }
+    }
+
+    // 解析 offset 处以最后一段长名目录项开始的目录项组
+    // 返回 (长文件名, 短目录项, 下一个目录项的偏移)
+    // 序号不连续, 校验和与短目录项不匹配或目录项组不完整时返回 None
+    pub(crate) fn parse_lfn_run(&self, offset: usize) -> Option<(String, ShortDirEntry, usize)> {
+        let mut parts = [[0u16; LONG_NAME_LEN_CAP]; MAX_LFN_ENTRIES];
+        let (cnt, sde, next_offset) = self.read_lfn_run(offset, &mut parts)?;
+        Some((lfn_name_from_parts(&parts[..cnt]), sde, next_offset))
+    }
+
+    // 同 parse_lfn_run, 但将各段长文件名 (最后一段在前) 读入 parts, 不分配内存
+    // 返回 (长名目录项个数, 短目录项, 下一个目录项的偏移)
+    // 超过 LONG_NAME_MAX_LEN 的目录项组视为损坏
+    fn read_lfn_run(
+        &self,
+        offset: usize,
+        parts: &mut [[u16; LONG_NAME_LEN_CAP]; MAX_LFN_ENTRIES],
+    ) -> Option<(usize, ShortDirEntry, usize)> {
+        let mut entry = LongDirEntry::empty();
+        let mut index = offset;
+        if self.read_lde_at(index, &mut entry) != DIRENT_SIZE || !entry.is_lde_end() {
+            return None;
+        }
+        let cnt = entry.lde_order();
+        if cnt > MAX_LFN_ENTRIES {
+            return None;
+        }
+        let check_sum = entry.check_sum();
+        for i in (1..=cnt).rev() {
+            let order = if i == cnt {
+                i as u8 | LAST_LONG_ENTRY
+            } else {
+                i as u8
+            };
+            if entry.attr() != ATTR_LONG_NAME
+                || entry.order() != order
+                || entry.check_sum() != check_sum
+            {
+                return None;
+            }
+            parts[cnt - i] = entry.name_utf16();
+            index += DIRENT_SIZE;
+            if self.read_lde_at(index, &mut entry) != DIRENT_SIZE {
+                return None;
+            }
+        }
+        // 长名目录项之后必须紧跟对应的短名目录项
+        if cnt == 0 || entry.attr() == ATTR_LONG_NAME || entry.is_free() {
+            return None;
+        }
+        let sde: ShortDirEntry = ShortDirEntry::from_bytes(&entry.to_bytes_array());
+        if sde.gen_check_sum() != check_sum {
+            return None;
+        }
+        Some((cnt, sde, index + DIRENT_SIZE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::BlockDevice,
+        test_util::{pattern, read_all, TestFs},
+        BLOCK_SIZE,
+    };
+
+    #[test]
+    fn rename_without_overwrite_keeps_both() {
+        let t = TestFs::new();
+        let a = t.create_file("a.txt", b"aaaa");
+        t.create_file("b.txt", b"bb");
+        let root = t.root();
+
+        assert_eq!(
+            a.rename(&root, "b.txt", false).err(),
+            Some(DirError::FileHasExist)
+        );
+        assert_eq!(read_all(&root.find(vec!["a.txt"]).unwrap()), b"aaaa");
+        assert_eq!(read_all(&root.find(vec!["b.txt"]).unwrap()), b"bb");
+    }
+
+    #[test]
+    fn rename_overwrite_replaces_file_of_other_length() {
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        let data = pattern(100, 15);
+        let a = t.create_file("a.txt", &data);
+        t.create_file("b.txt", &pattern(5 * cluster_size, 16));
+        let root = t.root();
+        let free = t.free_cluster_cnt();
+
+        let b = a.rename(&root, "b.txt", true).unwrap();
+        assert_eq!(b.file_size(), data.len());
+        assert_eq!(read_all(&b), data);
+        assert_eq!(root.ls().unwrap(), ["b.txt"]);
+        // 被替换的文件的簇已释放
+        assert_eq!(t.free_cluster_cnt(), free + 5);
+
+        let t = {
+            drop((a, b, root));
+            t.reopen()
+        };
+        let b = t.root().find(vec!["b.txt"]).unwrap();
+        assert_eq!(read_all(&b), data);
+    }
+
+    #[test]
+    fn rename_refuses_non_empty_directory() {
+        let t = TestFs::new();
+        let root = t.root();
+        let src = root.create("src", VirtFileType::Dir).unwrap();
+        let dest = root.create("dest", VirtFileType::Dir).unwrap();
+        dest.create("child.txt", VirtFileType::File).unwrap();
+
+        assert_eq!(
+            src.rename(&root, "dest", true).err(),
+            Some(DirError::DirNotEmpty)
+        );
+        assert_eq!(root.ls().unwrap(), ["src", "dest"]);
+        assert_eq!(dest.ls().unwrap(), ["child.txt"]);
+
+        // 空目录可以被替换, 移动后的目录 ".." 仍指向根目录
+        root.create("empty", VirtFileType::Dir).unwrap();
+        let src_cluster = src.first_cluster();
+        let moved = src.rename(&root, "empty", true).unwrap();
+        assert_eq!(moved.first_cluster(), src_cluster);
+        assert_eq!(root.ls().unwrap(), ["dest", "empty"]);
+        assert_eq!(
+            moved.parent().unwrap().first_cluster(),
+            root.first_cluster()
+        );
+    }
+
+    #[test]
+    fn failed_rename_keeps_source_and_target() {
+        let t = TestFs::new();
+        let root = t.root();
+        let dir = root.create("dir", VirtFileType::Dir).unwrap();
+        let src = t.create_file("src.bin", b"source");
+        let dest = dir.create("dest.bin", VirtFileType::File).unwrap();
+        assert_eq!(dest.write_at(0, b"target"), 6);
+
+        // 填满目录的簇, 使新目录项需要新的簇, 再占用所有空闲簇
+        let mut i = 0;
+        while dir.empty_entry_index(entries_needed("dest.bin")).unwrap() < dir.allocated_size() {
+            dir.create(&format!("f{}", i), VirtFileType::File).unwrap();
+            i += 1;
         }
+        let free = t.free_cluster_cnt();
+        assert!(t.fs.read().alloc_cluster_no_clear(free, 0).is_some());
+
+        assert_eq!(
+            src.rename(&dir, "dest.bin", true).err(),
+            Some(DirError::CreateFileError)
+        );
+        assert_eq!(read_all(&root.find(vec!["src.bin"]).unwrap()), b"source");
+        assert_eq!(read_all(&dir.find(vec!["dest.bin"]).unwrap()), b"target");
+
+        // 名字过长时同样保持原样
+        let long_name = "x".repeat(LONG_NAME_MAX_LEN + 1);
+        assert_eq!(
+            src.rename(&root, &long_name, false).err(),
+            Some(DirError::NameTooLong)
+        );
+        assert_eq!(read_all(&root.find(vec!["src.bin"]).unwrap()), b"source");
+    }
+
+    // 在 dir 的空闲位置写入一个卷标目录项
+    fn write_volume_label(dir: &VirtFile, label: &[u8; 11]) {
+        let mut sde = ShortDirEntry::new(0, &label[..8], &label[8..], VirtFileType::File);
+        sde.set_attr(ATTR_VOLUME_ID);
+        let offset = dir.empty_entry_index(1).unwrap();
+        dir.write_in_bounds_at(offset, &sde.to_bytes_array())
+            .unwrap();
+    }
+
+    #[test]
+    fn ls_filtered_hides_optional_entries() {
+        let t = TestFs::new();
+        let root = t.root();
+        let dir = root.create("dir", VirtFileType::Dir).unwrap();
+        // 卷标不在目录的第一个目录项
+        write_volume_label(&root, b"MYDISK     ");
+        dir.create("shown.txt", VirtFileType::File).unwrap();
+        dir.create("hidden.txt", VirtFileType::File)
+            .unwrap()
+            .set_hidden(true);
+        dir.create("system.sys", VirtFileType::File)
+            .unwrap()
+            .set_system(true);
+
+        let names = |dir: &VirtFile, opts| -> Vec<String> {
+            dir.ls_filtered(opts)
+                .unwrap()
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect()
+        };
+        assert_eq!(names(&dir, LsOptions::default()), ["shown.txt"]);
+        assert_eq!(
+            names(
+                &dir,
+                LsOptions {
+                    show_dot_entries: true,
+                    show_hidden: true,
+                    ..LsOptions::default()
+                }
+            ),
+            [".", "..", "shown.txt", "hidden.txt", "system.sys"]
+        );
+
+        assert_eq!(names(&root, LsOptions::default()), ["dir"]);
+        let with_label = names(
+            &root,
+            LsOptions {
+                show_volume_id: true,
+                ..LsOptions::default()
+            },
+        );
+        assert_eq!(with_label.len(), 2);
+        assert_eq!(root.volume_label().as_deref(), Some("MYDISK"));
+    }
+
+    #[test]
+    fn create_dir_all_creates_and_reuses_components() {
+        let t = TestFs::new();
+        let root = t.root();
+        let deep = root.create_dir_all("/a/b/./c/").unwrap();
+        assert!(deep.is_dir());
+        assert_eq!(
+            root.find(vec!["a", "b", "c"]).unwrap().inode(),
+            deep.inode()
+        );
+
+        // 已存在的路径直接返回同一目录
+        let again = root.create_dir_all("a/b/c").unwrap();
+        assert_eq!(again.inode(), deep.inode());
+        assert_eq!(root.ls().unwrap(), ["a"]);
+
+        root.find(vec!["a"])
+            .unwrap()
+            .create("f", VirtFileType::File)
+            .unwrap();
+        assert_eq!(root.create_dir_all("a/f/g").err(), Some(DirError::NotDir));
+    }
+
+    #[test]
+    fn open_or_create_opens_existing_entry() {
+        let t = TestFs::new();
+        let root = t.root();
+        let created = root
+            .open_or_create("log.txt", VirtFileType::File, false)
+            .unwrap();
+        assert_eq!(created.write_at(0, b"first"), 5);
+
+        let opened = root
+            .open_or_create("log.txt", VirtFileType::File, false)
+            .unwrap();
+        assert_eq!(opened.inode(), created.inode());
+        assert_eq!(read_all(&opened), b"first");
+        // 打开得到的句柄可以更新目录项
+        assert_eq!(opened.write_at(5, b" second"), 7);
+        assert_eq!(
+            read_all(&root.find(vec!["log.txt"]).unwrap()),
+            b"first second"
+        );
+
+        assert_eq!(
+            root.open_or_create("log.txt", VirtFileType::Dir, false)
+                .err(),
+            Some(DirError::FileHasExist)
+        );
+        assert_eq!(root.ls().unwrap(), ["log.txt"]);
+    }
+
+    #[test]
+    fn lookup_stops_at_end_of_directory_marker() {
+        let t = TestFs::new();
+        let dir = t.root().create("dir", VirtFileType::Dir).unwrap();
+        dir.create("A.TXT", VirtFileType::File).unwrap();
+        dir.create("B.TXT", VirtFileType::File).unwrap();
+        assert!(dir.find_by_name("B.TXT").is_some());
+
+        // 把 "." 与 ".." 之后的 A.TXT 改写为 0x00 目录项, 其后的 B.TXT 不再可见
+        dir.write_in_bounds_at(2 * DIRENT_SIZE, &[0; DIRENT_SIZE])
+            .unwrap();
+        assert!(dir.find_by_name("B.TXT").is_none());
+        // 不在已分配簇内的名字同样找不到
+        assert!(t.root().find_by_name("MISSING").is_none());
+    }
+
+    #[test]
+    fn create_reuses_deleted_entry_runs() {
+        let t = TestFs::new();
+        let dir = t.root().create("dir", VirtFileType::Dir).unwrap();
+        // 3 个长名目录项 + 短名目录项
+        dir.create("a rather long name for a file.txt", VirtFileType::File)
+            .unwrap();
+        dir.create("C.TXT", VirtFileType::File).unwrap();
+        dir.remove(vec!["a rather long name for a file.txt"])
+            .unwrap();
+
+        // 4 个已删除目录项依次被两个占 2 项的名字复用, 占 3 项的名字只能追加到末尾
+        dir.create("B.TXT", VirtFileType::File).unwrap();
+        dir.create("name13.chars", VirtFileType::File).unwrap();
+        dir.create("fifteen chars.x", VirtFileType::File).unwrap();
+
+        let mut names = Vec::new();
+        let mut offset = 0;
+        while let Some(ent) = dir.read_dir_from(offset) {
+            offset = ent.offset;
+            names.push(ent.name);
+        }
+        assert_eq!(
+            names,
+            [
+                ".",
+                "..",
+                "B.TXT",
+                "name13.chars",
+                "C.TXT",
+                "fifteen chars.x"
+            ]
+        );
+        assert_eq!(dir.allocated_size(), t.cluster_size());
+    }
+
+    #[test]
+    fn entry_run_across_cluster_boundary() {
+        let t = TestFs::new();
+        let root = t.root();
+        // 每簇 16 个目录项: ".", ".." 与 6 个各占 2 项的短名, 只剩 2 项
+        let filled = |name: &str| {
+            let dir = root.create(name, VirtFileType::Dir).unwrap();
+            for i in 0..6 {
+                dir.create(&format!("F{}.TXT", i), VirtFileType::File)
+                    .unwrap();
+            }
+            assert_eq!(dir.allocated_size(), t.cluster_size());
+            dir
+        };
+        let fits = filled("fits");
+        let full = filled("full");
+
+        // 需要 3 项的名字跨越簇边界
+        fits.create("fifteen chars.x", VirtFileType::File).unwrap();
+        assert_eq!(fits.allocated_size(), 2 * t.cluster_size());
+        assert!(fits.find_by_name("fifteen chars.x").is_some());
+
+        let free = t.free_cluster_cnt();
+        assert!(t.fs.read().alloc_cluster_no_clear(free, 0).is_some());
+        assert_eq!(
+            full.create("fifteen chars.x", VirtFileType::File).err(),
+            Some(DirError::CreateFileError)
+        );
+        // 没有写入孤立的长名目录项
+        let (list, skipped) = full.ls_with_attr_checked().unwrap();
+        assert!(skipped.is_empty());
+        assert_eq!(list.len(), 6);
+        assert_eq!(full.allocated_size(), t.cluster_size());
+    }
+
+    #[test]
+    fn walk_is_depth_first_with_relative_paths() {
+        let t = TestFs::new();
+        let root = t.root();
+        let a = root.create("a", VirtFileType::Dir).unwrap();
+        let c = root.create("c.txt", VirtFileType::File).unwrap();
+        a.create("x.txt", VirtFileType::File).unwrap();
+        let b = a.create("b", VirtFileType::Dir).unwrap();
+        b.create("y.txt", VirtFileType::File).unwrap();
+
+        let paths: Vec<String> = root.walk().map(|(path, _)| path).collect();
+        assert_eq!(paths, ["a", "a/x.txt", "a/b", "a/b/y.txt", "c.txt"]);
+
+        let (path, file) = a.walk().nth(1).unwrap();
+        assert_eq!(path, "b");
+        assert_eq!(file.inode(), b.inode());
+        assert_eq!(c.walk().count(), 0);
+    }
+
+    #[test]
+    fn find_glob_matches_wildcards_ignoring_case() {
+        let t = TestFs::new();
+        let root = t.root();
+        for name in [
+            "notes.txt",
+            "Readme.TXT",
+            "a1.dat",
+            "ab.dat",
+            "abc.dat",
+            "foobar",
+            "Foo.rs",
+            "barfoo",
+        ] {
+            root.create(name, VirtFileType::File).unwrap();
+        }
+        root.create("dir.txt", VirtFileType::Dir).unwrap();
+
+        let glob = |pattern: &str| -> Vec<String> {
+            root.find_glob(pattern)
+                .iter()
+                .map(|file| file.name().into())
+                .collect()
+        };
+        assert_eq!(glob("*.txt"), ["notes.txt", "Readme.TXT", "dir.txt"]);
+        assert_eq!(glob("a?.dat"), ["a1.dat", "ab.dat"]);
+        assert_eq!(glob("FOO*"), ["foobar", "Foo.rs"]);
+        assert!(glob("*.md").is_empty());
+    }
+
+    #[test]
+    fn find_by_name_ignores_case() {
+        let t = TestFs::new();
+        let root = t.root();
+        let readme = root.create("ReadMe.txt", VirtFileType::File).unwrap();
+        for name in ["readme.txt", "README.TXT", "rEaDmE.tXt"] {
+            let found = root.find_by_name(name).unwrap();
+            assert_eq!(found.inode(), readme.inode());
+        }
+
+        // 长名与其短名都可以找到
+        let long = root
+            .create("A Long File Name.txt", VirtFileType::File)
+            .unwrap();
+        for name in ["a long file name.TXT", "alongf~1.txt"] {
+            assert_eq!(root.find_by_name(name).unwrap().inode(), long.inode());
+        }
+        assert!(root.find_by_name("readme.tx").is_none());
+    }
+
+    #[test]
+    fn trim_dir_frees_empty_tail_clusters() {
+        let t = TestFs::new();
+        let dir = t.root().create("dir", VirtFileType::Dir).unwrap();
+        // ".", ".." 与 20 个各占 2 项的短名, 共 42 项, 占 3 个簇
+        for i in 0..20 {
+            dir.create(&format!("F{}.TXT", i), VirtFileType::File)
+                .unwrap();
+        }
+        assert_eq!(dir.allocated_size(), 3 * t.cluster_size());
+        assert_eq!(dir.trim_dir(), Ok(0));
+
+        let free = t.free_cluster_cnt();
+        // 第一个簇之后的目录项全部删除
+        for i in 7..20 {
+            dir.remove(vec![format!("F{}.TXT", i).as_str()]).unwrap();
+        }
+        assert_eq!(dir.trim_dir(), Ok(2));
+        assert_eq!(dir.allocated_size(), t.cluster_size());
+        assert_eq!(t.free_cluster_cnt(), free + 2);
+        assert_eq!(dir.ls().unwrap().len(), 7);
+        assert!(dir.find_by_name("F6.TXT").is_some());
+
+        let file = dir.find_by_name("F0.TXT").unwrap();
+        assert_eq!(file.trim_dir(), Err(DirError::NotDir));
+    }
+
+    #[test]
+    fn create_with_cluster_adopts_written_chain() {
+        let t = TestFs::new();
+        let root = t.root();
+        let cluster_size = t.cluster_size();
+        let data = pattern(cluster_size + 100, 21);
+
+        // 先分配簇并直接写入数据, 再创建目录项
+        let first = t.fs.read().alloc_cluster_no_clear(2, 0).unwrap();
+        let clusters: Vec<u32> = t.fs.read().cluster_chain(first).collect();
+        let mut image = data.clone();
+        image.resize(2 * cluster_size, 0);
+        for (cluster, chunk) in clusters.iter().zip(image.chunks(cluster_size)) {
+            let blocks = t.fs.read().cluster_blocks(*cluster);
+            for (block_id, block) in blocks.zip(chunk.chunks(BLOCK_SIZE)) {
+                t.device
+                    .write_blocks(block, block_id * BLOCK_SIZE, 1)
+                    .unwrap();
+            }
+        }
+        let free = t.free_cluster_cnt();
+
+        let file = root
+            .create_with_cluster("imported.bin", VirtFileType::File, first, data.len())
+            .unwrap();
+        assert_eq!(file.first_cluster() as u32, first);
+        assert_eq!(read_all(&root.find(vec!["imported.bin"]).unwrap()), data);
+        assert_eq!(t.free_cluster_cnt(), free);
+
+        // 簇链容纳不下 size, 或簇链无效
+        assert_eq!(
+            root.create_with_cluster("big.bin", VirtFileType::File, first, 2 * cluster_size + 1)
+                .err(),
+            Some(DirError::CreateFileError)
+        );
+        let unallocated = t.fs.read().next_free_hint();
+        assert_eq!(
+            root.create_with_cluster("free.bin", VirtFileType::File, unallocated, 1)
+                .err(),
+            Some(DirError::CreateFileError)
+        );
+    }
+
+    #[test]
+    fn ls_long_reports_size_cluster_and_mtime() {
+        let t = TestFs::new();
+        let file = t.create_file("data.bin", &pattern(700, 2));
+        let dir = t.root().create("sub", VirtFileType::Dir).unwrap();
+        // 2024-05-17 13:45:30
+        for entry in [&file, &dir] {
+            entry.modify_sde(|sde| {
+                sde.set_last_write_date((44 << 9) | (5 << 5) | 17);
+                sde.set_last_write_time((13 << 11) | (45 << 5) | 15);
+            });
+        }
+
+        let details = t.root().ls_long().unwrap();
+        assert_eq!(details.len(), 2);
+        let modified = FatTimestamp {
+            year: 2024,
+            month: 5,
+            day: 17,
+            hour: 13,
+            minute: 45,
+            second: 30,
+        };
+        assert_eq!(
+            details[0],
+            DirEntryDetail {
+                name: "data.bin".into(),
+                attr: file.read_sde(|sde| sde.attr()),
+                file_size: 700,
+                first_cluster: file.first_cluster() as u32,
+                modified,
+            }
+        );
+        assert_eq!(details[1].name, "sub");
+        assert_ne!(details[1].attr & ATTR_DIRECTORY, 0);
+        assert_eq!(details[1].file_size, 0);
+        assert_eq!(details[1].first_cluster, dir.first_cluster() as u32);
+        assert_eq!(details[1].modified, modified);
+    }
+
+    #[test]
+    fn path_checks_distinguish_files_and_dirs() {
+        let t = TestFs::new();
+        let root = t.root();
+        root.create_dir_all("a/b").unwrap();
+        root.find(vec!["a", "b"])
+            .unwrap()
+            .create("f.txt", VirtFileType::File)
+            .unwrap();
+
+        assert!(root.exists(vec!["a", "b", "f.txt"]));
+        assert!(root.is_file_path(vec!["a", "", "b", ".", "F.TXT"]));
+        assert!(!root.is_dir_path(vec!["a", "b", "f.txt"]));
+        assert!(root.is_dir_path(vec!["a", "b"]));
+        assert!(!root.is_file_path(vec!["a"]));
+        // 空路径指当前目录
+        assert!(root.is_dir_path(vec![]));
+
+        assert!(!root.exists(vec!["a", "missing"]));
+        // 中间分量是文件
+        assert!(!root.exists(vec!["a", "b", "f.txt", "x"]));
+    }
+
+    #[test]
+    fn create_many_reports_each_entry() {
+        let t = TestFs::new();
+        let root = t.root();
+        root.create("old.txt", VirtFileType::File).unwrap();
+
+        let names: Vec<String> = (0..20).map(|i| format!("file number {}.txt", i)).collect();
+        let mut entries: Vec<(&str, VirtFileType)> = names
+            .iter()
+            .map(|name| (name.as_str(), VirtFileType::File))
+            .collect();
+        entries.push(("OLD.TXT", VirtFileType::File));
+        entries.push(("file number 3.txt", VirtFileType::File));
+        entries.push(("sub", VirtFileType::Dir));
+
+        let results = root.create_many(&entries);
+        assert_eq!(results.len(), 23);
+        assert!(results[..20].iter().all(Result::is_ok));
+        assert_eq!(results[20].as_ref().err(), Some(&DirError::FileHasExist));
+        assert_eq!(results[21].as_ref().err(), Some(&DirError::FileHasExist));
+
+        let sub = results[22].as_ref().unwrap();
+        assert!(sub.is_dir());
+        assert_eq!(
+            sub.ls_filtered(LsOptions {
+                show_dot_entries: true,
+                ..LsOptions::default()
+            })
+            .unwrap()
+            .len(),
+            2
+        );
+        for name in names.iter() {
+            assert!(root.find_by_name(name).is_some());
+        }
+        assert_eq!(root.ls().unwrap().len(), 22);
+    }
+
+    #[test]
+    fn create_many_scans_the_directory_once() {
+        use crate::{device::FaultDevice, FileSystem};
+
+        let t = TestFs::exclusive();
+        t.root().create("many", VirtFileType::Dir).unwrap();
+        t.root().create("each", VirtFileType::Dir).unwrap();
+        drop(t.fs);
+
+        // 每个名字占 6 个目录项, 200 个文件的目录大于块缓存, 逐个 create 每次扫描都会读设备
+        let names: Vec<String> = (0..200)
+            .map(|i| {
+                format!(
+                    "a rather long file name used for counting device reads {:03}.txt",
+                    i
+                )
+            })
+            .collect();
+        let fault = Arc::new(FaultDevice::new(t.device.clone()));
+        let fs = FileSystem::open(fault.clone());
+        let root = fs.read().root();
+        let many = root.find_by_name("many").unwrap();
+        let each = root.find_by_name("each").unwrap();
+
+        let entries: Vec<(&str, VirtFileType)> = names
+            .iter()
+            .map(|name| (name.as_str(), VirtFileType::File))
+            .collect();
+        let reads = fault.read_count();
+        assert!(many.create_many(&entries).iter().all(Result::is_ok));
+        let many_reads = fault.read_count() - reads;
+
+        let reads = fault.read_count();
+        for name in names.iter() {
+            each.create(name, VirtFileType::File).unwrap();
+        }
+        let each_reads = fault.read_count() - reads;
+
+        assert!(
+            many_reads * 10 < each_reads,
+            "create_many read {} blocks, create {}",
+            many_reads,
+            each_reads
+        );
+        assert_eq!(many.ls().unwrap().len(), 200);
+    }
+
+    // 文件短目录项中的名字, 如 "LONGFI~1.TXT"
+    fn short_name_of(file: &VirtFile) -> String {
+        file.read_sde(|sde| sde.get_name_uppercase())
+    }
+
+    #[test]
+    fn short_names_get_unique_tails() {
+        let t = TestFs::new();
+        let root = t.root();
+        let fs = t.fs.read();
+        assert_eq!(
+            fs.preview_short_name(&root, "longfilename1.txt").unwrap(),
+            "LONGFI~1.TXT"
+        );
+        let first = root
+            .create("longfilename1.txt", VirtFileType::File)
+            .unwrap();
+        assert_eq!(short_name_of(&first), "LONGFI~1.TXT");
+        assert_eq!(
+            fs.preview_short_name(&root, "longfilename2.txt").unwrap(),
+            "LONGFI~2.TXT"
+        );
+        let second = root
+            .create("longfilename2.txt", VirtFileType::File)
+            .unwrap();
+        assert_eq!(short_name_of(&second), "LONGFI~2.TXT");
+        let third = root
+            .create("longfilename3.txt", VirtFileType::File)
+            .unwrap();
+        assert_eq!(short_name_of(&third), "LONGFI~3.TXT");
+
+        // 删除后空出的 ~1 被复用
+        root.remove(vec!["longfilename1.txt"]).unwrap();
+        let reused = root
+            .create("longfilename4.txt", VirtFileType::File)
+            .unwrap();
+        assert_eq!(short_name_of(&reused), "LONGFI~1.TXT");
+        assert!(root.find_by_name("longfilename4.txt").is_some());
+    }
+
+    #[test]
+    fn volume_label_entry_is_not_a_file() {
+        let t = TestFs::new();
+        let root = t.root();
+        assert_eq!(root.volume_label(), None);
+        write_volume_label(&root, b"MYDISK     ");
+        root.create("a.txt", VirtFileType::File).unwrap();
+
+        assert_eq!(root.volume_label().as_deref(), Some("MYDISK"));
+        assert_eq!(t.fs.read().volume_label().as_deref(), Some("MYDISK"));
+
+        // 列目录与查找均不包含卷标目录项
+        let names: Vec<String> = root
+            .ls_with_attr()
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, vec!["a.txt"]);
+        let details = root.ls_long().unwrap();
+        assert_eq!(details.len(), 1);
+        assert!(root.find_by_name("MYDISK").is_none());
+        let first = root.read_dir_from(0).unwrap();
+        assert_eq!(first.name, "a.txt");
+        assert!(root.read_dir_from(first.offset).is_none());
+    }
+
+    #[test]
+    fn emoji_name_survives_entry_boundary() {
+        let t = TestFs::new();
+        // 代理对恰好跨越第一个与第二个长名目录项 (第 13, 14 个 UTF-16 单元)
+        let name = "twelve chars\u{1F600}.txt";
+        assert_eq!(name.encode_utf16().position(|u| u == 0xD83D), Some(12));
+        assert_eq!(crate::lfn_entry_count(name), 2);
+        t.root().create(name, VirtFileType::File).unwrap();
+
+        let t = t.reopen();
+        let root = t.root();
+        let names: Vec<String> = root
+            .ls_with_attr()
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, vec![name]);
+        assert!(root.find_by_name(name).is_some());
+
+        // 长度按 UTF-16 单元计算: 128 个 emoji 占 256 个单元
+        let too_long: String = core::iter::repeat_n('\u{1F600}', 128).collect();
+        assert_eq!(
+            root.create(&too_long, VirtFileType::File).err(),
+            Some(DirError::NameTooLong)
+        );
+        let longest: String = core::iter::repeat_n('\u{1F600}', 127)
+            .chain(['a'])
+            .collect();
+        root.create(&longest, VirtFileType::File).unwrap();
+        assert!(root.find_by_name(&longest).is_some());
+    }
+
+    #[test]
+    fn open_or_create_truncates_existing_file() {
+        let t = TestFs::new();
+        let root = t.root();
+        let free = t.free_cluster_cnt();
+        t.create_file("log.txt", &pattern(2 * t.cluster_size(), 3));
+        let dir = root.create("dir", VirtFileType::Dir).unwrap();
+        dir.create("inner", VirtFileType::File).unwrap();
+
+        let file = root
+            .open_or_create("log.txt", VirtFileType::File, true)
+            .unwrap();
+        assert_eq!(file.file_size(), 0);
+        assert_eq!(file.first_cluster() as u32, NEW_VIR_FILE_CLUSTER);
+        // 只剩下目录占用的簇
+        assert_eq!(t.free_cluster_cnt(), free - 1);
+        assert_eq!(file.write_at(0, b"again"), 5);
+        assert_eq!(read_all(&file), b"again");
+
+        // 目录不受 truncate 影响
+        let dir = root.open_or_create("dir", VirtFileType::Dir, true).unwrap();
+        assert!(dir.find_by_name("inner").is_some());
+    }
+
+    #[test]
+    fn dot_entries_hidden_unless_requested() {
+        let t = TestFs::new();
+        let dir = t.root().create("sub", VirtFileType::Dir).unwrap();
+        assert!(dir.ls().unwrap().is_empty());
+        assert!(dir.ls_long().unwrap().is_empty());
+        dir.create("a.txt", VirtFileType::File).unwrap();
+        assert_eq!(dir.ls().unwrap(), vec!["a.txt"]);
+
+        let with_dots = LsOptions {
+            show_dot_entries: true,
+            ..Default::default()
+        };
+        let names: Vec<String> = dir
+            .ls_filtered(with_dots)
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, vec![".", "..", "a.txt"]);
+
+        // 目录项层面的判断
+        let dot = dir.read_dir_from(0).unwrap();
+        assert!(dot.is_dot_entry() && !dot.is_dotdot_entry());
+        let dotdot = dir.read_dir_from(dot.offset).unwrap();
+        assert!(dotdot.is_dotdot_entry() && !dotdot.is_dot_entry());
+        let (_, _, _, sde) = dir.find_entry("..").unwrap();
+        assert!(sde.is_dotdot_entry() && !sde.is_dot_entry());
+        let (_, _, _, sde) = dir.find_entry("a.txt").unwrap();
+        assert!(!sde.is_dot_entry() && !sde.is_dotdot_entry());
+    }
+
+    #[test]
+    fn parent_follows_dotdot_up_to_root() {
+        let t = TestFs::new();
+        let root = t.root();
+        let c = root.create_dir_all("a/b/c").unwrap();
+        let file = c.create("leaf.txt", VirtFileType::File).unwrap();
+
+        let b = c.parent().unwrap();
+        assert_eq!(b.name(), "b");
+        assert!(b.find_by_name("c").is_some());
+        let a = b.parent().unwrap();
+        assert_eq!(a.name(), "a");
+        // 父目录句柄绑定在祖父目录中的目录项, 可以修改
+        a.set_hidden(true);
+        assert!(root.find_by_name("a").unwrap().is_hidden());
+        let top = a.parent().unwrap();
+        assert_eq!(top.first_cluster(), root.first_cluster());
+
+        assert!(root.parent().is_none());
+        assert!(file.parent().is_none());
+    }
+
+    #[test]
+    fn multi_dot_name_gets_final_extension_alias() {
+        let t = TestFs::new();
+        let root = t.root();
+        assert_eq!(split_name_ext("archive.tar.gz"), ("archive.tar", "gz"));
+        assert_eq!(
+            root.preview_short_name("archive.tar.gz").unwrap(),
+            "ARCHIV~1.GZ"
+        );
+        let archive = root.create("archive.tar.gz", VirtFileType::File).unwrap();
+        assert_eq!(short_name_of(&archive), "ARCHIV~1.GZ");
+        // 只有一个 . 的短名不变
+        let tar = root.create("x.tar", VirtFileType::File).unwrap();
+        assert_eq!(short_name_of(&tar), "X.TAR");
+
+        drop((archive, tar, root));
+        let t = t.reopen();
+        let root = t.root();
+        assert!(root.find_by_name("archive.tar.gz").is_some());
+        assert!(root.find_by_name("ARCHIV~1.GZ").is_some());
+        let mut names = root.ls().unwrap();
+        names.sort();
+        assert_eq!(names, vec!["archive.tar.gz", "x.tar"]);
+    }
+
+    #[test]
+    fn modified_since_filters_by_write_time() {
+        let t = TestFs::new();
+        let root = t.root();
+        // FAT 日期: 年份自 1980 起, 月, 日
+        let date = |year: u16, month: u16, day: u16| (year - 1980) << 9 | month << 5 | day;
+        let set_mtime = |file: &VirtFile, date: u16, time: u16| {
+            file.modify_sde(|sde| {
+                sde.set_last_write_date(date);
+                sde.set_last_write_time(time);
+            })
+        };
+        let (old, since, new) = (date(2024, 1, 1), date(2024, 6, 1), date(2024, 9, 1));
+
+        set_mtime(&root.create("old.txt", VirtFileType::File).unwrap(), old, 0);
+        set_mtime(&root.create("new.txt", VirtFileType::File).unwrap(), new, 0);
+        // 与 since 相同的时间不算在之后
+        set_mtime(
+            &root.create("same.txt", VirtFileType::File).unwrap(),
+            since,
+            0x0800,
+        );
+        let sub = root.create("sub", VirtFileType::Dir).unwrap();
+        set_mtime(&sub, old, 0);
+        set_mtime(
+            &sub.create("fresh.txt", VirtFileType::File).unwrap(),
+            new,
+            0,
+        );
+        set_mtime(
+            &sub.create("stale.txt", VirtFileType::File).unwrap(),
+            old,
+            0,
+        );
+
+        let names: Vec<String> = root
+            .entries_modified_since(since, 0x0800)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, vec!["new.txt"]);
+
+        // 较旧的目录仍会被遍历
+        let paths: Vec<String> = root
+            .walk_modified_since(since, 0x0800)
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(paths, vec!["new.txt", "sub/fresh.txt"]);
+    }
+
+    #[test]
+    fn validate_entry_at_checks_the_lfn_set() {
+        let t = TestFs::new();
+        let root = t.root();
+        root.create("first.txt", VirtFileType::File).unwrap();
+        let long = "a rather long name for a file.txt";
+        root.create(long, VirtFileType::File).unwrap();
+        assert_eq!(entries_needed(long), 4);
+        // first.txt: 长名 0, 短名 32; long: 长名 64..160, 短名 160
+        let sde_offset = 5 * DIRENT_SIZE;
+        assert_eq!(root.validate_entry_at(DIRENT_SIZE), Ok(()));
+        assert_eq!(root.validate_entry_at(sde_offset), Ok(()));
+
+        // 修改一个字节后检查, 再恢复
+        let check_corrupt = |offset: usize, index: usize, value: u8, expected| {
+            let mut raw = [0u8; DIRENT_SIZE];
+            assert_eq!(root.read_at(offset, &mut raw), DIRENT_SIZE);
+            let mut bad = raw;
+            bad[index] = value;
+            root.write_in_bounds_at(offset, &bad).unwrap();
+            assert_eq!(root.validate_entry_at(sde_offset), Err(expected));
+            root.write_in_bounds_at(offset, &raw).unwrap();
+        };
+        // 长名目录项偏移 13 为校验和, 偏移 0 为序号
+        check_corrupt(3 * DIRENT_SIZE, 13, 0x00, EntryError::ChecksumMismatch);
+        check_corrupt(4 * DIRENT_SIZE, 0, 2, EntryError::BadOrdinal);
+        check_corrupt(2 * DIRENT_SIZE, 0, 3, EntryError::BadOrdinal);
+        assert_eq!(root.validate_entry_at(sde_offset), Ok(()));
+
+        assert_eq!(
+            root.validate_entry_at(4 * DIRENT_SIZE),
+            Err(EntryError::OrphanLfn)
+        );
+        assert_eq!(
+            root.validate_entry_at(2 * DIRENT_SIZE),
+            Err(EntryError::NotShortEntry)
+        );
+        assert_eq!(
+            root.validate_entry_at(6 * DIRENT_SIZE),
+            Err(EntryError::NotShortEntry)
+        );
+        assert_eq!(
+            root.validate_entry_at(DIRENT_SIZE + 1),
+            Err(EntryError::NotShortEntry)
+        );
+    }
+
+    #[test]
+    fn root_grows_and_mkdir_checks_space_up_front() {
+        const FILES: usize = 200;
+        let t = TestFs::new();
+        let root = t.root();
+        let entries_per_cluster = t.cluster_size() / DIRENT_SIZE;
+        for i in 0..FILES {
+            root.create(&format!("f{:03}.txt", i), VirtFileType::File)
+                .unwrap();
+        }
+        // 每个文件占 2 个目录项, 根目录恰好占满这些簇
+        let root_clusters = FILES * 2 / entries_per_cluster;
+        assert_eq!(root.allocated_size(), root_clusters * t.cluster_size());
+        assert!(root_clusters > 1);
+
+        // 只剩一个空闲簇: 新目录需要根目录增长一个簇, 外加自身的一个簇
+        let free = t.free_cluster_cnt();
+        assert!(t.fs.read().alloc_cluster_no_clear(free - 1, 0).is_some());
+        assert_eq!(
+            root.create("newdir", VirtFileType::Dir).err(),
+            Some(DirError::CreateFileError)
+        );
+        // 失败时没有写入任何目录项, 也没有分配簇
+        assert_eq!(t.free_cluster_cnt(), 1);
+        assert_eq!(root.ls().unwrap().len(), FILES);
+        assert!(root.find_by_name("newdir").is_none());
+        // 普通文件只需要根目录增长
+        root.create("last.txt", VirtFileType::File).unwrap();
+        assert_eq!(t.free_cluster_cnt(), 0);
+
+        drop(root);
+        let t = t.reopen();
+        let root = t.root();
+        assert_eq!(root.ls().unwrap().len(), FILES + 1);
+        assert!(root.find_by_name("f000.txt").is_some());
+        assert!(root.find_by_name("f199.txt").is_some());
+        assert!(root.find_by_name("last.txt").is_some());
     }
 }
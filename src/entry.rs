@@ -202,9 +202,9 @@ use core::{
 };
 
 use super::{
-    vfs::VirtFileType, ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_HIDDEN, ATTR_LONG_NAME, ATTR_READ_ONLY,
-    ATTR_SYSTEM, ATTR_VOLUME_ID, DIR_ENTRY_LAST_AND_UNUSED, DIR_ENTRY_UNUSED, LAST_LONG_ENTRY,
-    LONG_NAME_LEN_CAP, SPACE,
+    lfn_checksum, name_eq_ignore_case, vfs::VirtFileType, ATTR_ARCHIVE, ATTR_DIRECTORY,
+    ATTR_HIDDEN, ATTR_LONG_NAME, ATTR_READ_ONLY, ATTR_SYSTEM, ATTR_VOLUME_ID,
+    DIR_ENTRY_LAST_AND_UNUSED, DIR_ENTRY_UNUSED, LAST_LONG_ENTRY, LONG_NAME_LEN_CAP, SPACE,
 };
 
 #[allow(unused)]
@@ -356,20 +356,12 @@ impl ShortDirEntry {
         item
     }
 
+    /// Checksum of this entry's 8.3 name, see [`lfn_checksum`]
     pub fn gen_check_sum(&self) -> u8 {
         let mut name_: [u8; 11] = [0u8; 11];
-        let mut sum: u32 = 0;
-        for i in 0..8 {
-            name_[i] = self.name[i];
-        }
-        for i in 0..3 {
-            name_[i + 8] = self.extension[i];
-        }
-
-        for i in 0..11 {
-            sum = (((sum & 1) << 7) + (sum >> 1) + name_[i] as u32) & 0xFF;
-        }
-        sum as u8
+        name_[..8].copy_from_slice(&self.name);
+        name_[8..].copy_from_slice(&self.extension);
+        lfn_checksum(&name_)
     }
 
     pub fn name(&self) -> String {
@@ -426,6 +418,17 @@ impl ShortDirEntry {
     pub fn set_file_size(&mut self, file_size: u32) {
         self.file_size = file_size;
     }
+
+    // 复制属性与时间戳 (不含名字, 首簇号与文件大小), 用于重命名
+    pub(crate) fn copy_metadata(&mut self, other: &ShortDirEntry) {
+        self.attr = other.attr;
+        self.crt_time_tenth = other.crt_time_tenth;
+        self.crt_time = other.crt_time;
+        self.crt_date = other.crt_date;
+        self.lst_acc_date = other.lst_acc_date;
+        self.wrt_time = other.wrt_time;
+        self.wrt_date = other.wrt_date;
+    }
 }
 
 #[allow(unused)]
@@ -576,6 +579,12 @@ impl ShortDirEntry {
         self.nt_res = state;
     }
 
+    /// The raw DIR_NTRes byte, whose bits record how the case of the name was stored (see
+    /// [`crate::ALL_LOWER_CASE`])
+    pub fn nt_reserved(&self) -> u8 {
+        self.nt_res
+    }
+
     /// directory entry is free
     pub fn is_free(&self) -> bool {
         self.name[0] == DIR_ENTRY_UNUSED
@@ -633,14 +642,27 @@ impl ShortDirEntry {
     }
 
     pub fn is_dir(&self) -> bool {
-        self.attr == ATTR_DIRECTORY
+        self.attr & ATTR_DIRECTORY != 0 && self.attr != ATTR_LONG_NAME
     }
 
+    /// Whether this is a volume label entry (ATTR_VOLUME_ID) rather than a file
+    pub fn is_volume_id(&self) -> bool {
+        self.attr & ATTR_VOLUME_ID != 0
+    }
+
+    /// Whether this is the "." entry at the start of a subdirectory, referring to itself
+    pub fn is_dot_entry(&self) -> bool {
+        self.name == *b".       " && self.extension == *b"   "
+    }
+
+    /// Whether this is the ".." entry of a subdirectory, referring to its parent
+    pub fn is_dotdot_entry(&self) -> bool {
+        self.name == *b"..      " && self.extension == *b"   "
+    }
+
+    // 按属性位判断, 以免设置了只读/隐藏等属性位后判断错误
     pub fn is_file(&self) -> bool {
-        self.attr == ATTR_ARCHIVE
-            || self.attr == ATTR_HIDDEN
-            || self.attr == ATTR_SYSTEM
-            || self.attr == ATTR_READ_ONLY
+        self.attr & (ATTR_DIRECTORY | ATTR_VOLUME_ID) == 0
     }
 
     pub fn attr(&self) -> u8 {
@@ -652,25 +674,23 @@ impl ShortDirEntry {
     }
 
     pub fn get_name_uppercase(&self) -> String {
-        let mut name: String = String::new();
-        for i in 0..8 {
-            if self.name[i] == SPACE {
-                break;
-            } else {
-                name.push(self.name[i] as char);
-            }
-        }
-        for i in 0..3 {
-            if self.extension[i] == SPACE {
-                break;
-            } else {
-                if i == 0 {
-                    name.push('.');
-                }
-                name.push(self.extension[i] as char);
-            }
-        }
-        name
+        self.name_chars().collect()
+    }
+
+    // 短文件名 "NAME.EXT" 的字符, 不分配内存
+    fn name_chars(&self) -> impl Iterator<Item = char> + '_ {
+        let name = self.name.iter().take_while(|&&b| b != SPACE);
+        let ext = self.extension.iter().take_while(|&&b| b != SPACE);
+        // 有扩展名时才有 '.'
+        let dot = (self.extension[0] != SPACE).then_some('.');
+        name.map(|&b| b as char)
+            .chain(dot)
+            .chain(ext.map(|&b| b as char))
+    }
+
+    /// Whether the short name equals `name`, ignoring case, without allocating
+    pub(crate) fn name_eq_ignore_case(&self, name: &str) -> bool {
+        name_eq_ignore_case(self.name_chars(), name)
     }
 
     pub fn get_name_lowercase(&self) -> String {
@@ -689,9 +709,23 @@ impl ShortDirEntry {
         unsafe { &mut *(self as *mut ShortDirEntry as *mut [u8; 32]) }
     }
 
+    /// Encode the entry field by field into its on-disk (little-endian) bytes, without
+    /// referencing the packed struct's memory
     pub fn to_bytes_array(&self) -> [u8; 32] {
         let mut bytes = [0u8; 32];
-        bytes.copy_from_slice(self.as_bytes());
+        bytes[0..8].copy_from_slice(&{ self.name });
+        bytes[8..11].copy_from_slice(&{ self.extension });
+        bytes[11] = self.attr;
+        bytes[12] = self.nt_res;
+        bytes[13] = self.crt_time_tenth;
+        bytes[14..16].copy_from_slice(&{ self.crt_time }.to_le_bytes());
+        bytes[16..18].copy_from_slice(&{ self.crt_date }.to_le_bytes());
+        bytes[18..20].copy_from_slice(&{ self.lst_acc_date }.to_le_bytes());
+        bytes[20..22].copy_from_slice(&{ self.fst_clus_hi }.to_le_bytes());
+        bytes[22..24].copy_from_slice(&{ self.wrt_time }.to_le_bytes());
+        bytes[24..26].copy_from_slice(&{ self.wrt_date }.to_le_bytes());
+        bytes[26..28].copy_from_slice(&{ self.fst_clus_lo }.to_le_bytes());
+        bytes[28..32].copy_from_slice(&{ self.file_size }.to_le_bytes());
         bytes
     }
 
@@ -699,8 +733,30 @@ impl ShortDirEntry {
         unsafe { &*(self as *const ShortDirEntry as *const [u8; 32]) }
     }
 
+    /// Panics if `buf` is shorter than 32 bytes, see [`ShortDirEntry::from_bytes`]
     pub fn new_from_bytes(buf: &[u8]) -> Self {
-        unsafe { *(buf.as_ptr() as *const ShortDirEntry) }
+        Self::from_bytes(buf[..32].try_into().unwrap())
+    }
+
+    /// Decode an entry field by field from its on-disk (little-endian) bytes, so `buf` may sit
+    /// at any alignment
+    pub fn from_bytes(buf: &[u8; 32]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        Self {
+            name: buf[0..8].try_into().unwrap(),
+            extension: buf[8..11].try_into().unwrap(),
+            attr: buf[11],
+            nt_res: buf[12],
+            crt_time_tenth: buf[13],
+            crt_time: u16_at(14),
+            crt_date: u16_at(16),
+            lst_acc_date: u16_at(18),
+            fst_clus_hi: u16_at(20),
+            wrt_time: u16_at(22),
+            wrt_date: u16_at(24),
+            fst_clus_lo: u16_at(26),
+            file_size: u32::from_le_bytes(buf[28..32].try_into().unwrap()),
+        }
     }
 }
 
@@ -714,10 +770,27 @@ impl ShortDirEntry {
         self.crt_date = date;
     }
 
+    pub fn last_access_date(&self) -> u16 {
+        self.lst_acc_date
+    }
+
     pub fn set_last_access_date(&mut self, date: u16) {
         self.lst_acc_date = date;
     }
 
+    pub fn last_write_date(&self) -> u16 {
+        self.wrt_date
+    }
+
+    pub fn last_write_time(&self) -> u16 {
+        self.wrt_time
+    }
+
+    /// Decoded time of last write
+    pub fn modified(&self) -> FatTimestamp {
+        FatTimestamp::from_raw(self.wrt_date, self.wrt_time)
+    }
+
     pub fn set_last_write_time(&mut self, time: u16) {
         self.wrt_time = time;
     }
@@ -727,6 +800,33 @@ impl ShortDirEntry {
     }
 }
 
+/// A directory entry date and time decoded from the FAT on-disk encoding.
+///
+/// Fields are ordered so that comparing timestamps compares them chronologically.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct FatTimestamp {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl FatTimestamp {
+    // date: 7 位年 (自 1980) / 4 位月 / 5 位日; time: 5 位时 / 6 位分 / 5 位秒 (以 2 秒为单位)
+    pub fn from_raw(date: u16, time: u16) -> Self {
+        Self {
+            year: 1980 + (date >> 9),
+            month: ((date >> 5) & 0x0F) as u8,
+            day: (date & 0x1F) as u8,
+            hour: (time >> 11) as u8,
+            minute: ((time >> 5) & 0x3F) as u8,
+            second: ((time & 0x1F) * 2) as u8,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(packed)]
 /// Long Directory Entry
@@ -884,8 +984,37 @@ impl LongDirEntry {
         }
     }
 
+    /// Panics if `buf` is shorter than 32 bytes, see [`LongDirEntry::from_bytes`]
     pub fn new_form_bytes(buf: &[u8]) -> Self {
-        unsafe { *(buf.as_ptr() as *const Self) }
+        Self::from_bytes(buf[..32].try_into().unwrap())
+    }
+
+    /// Decode an entry field by field from its on-disk (little-endian) bytes, so `buf` may sit
+    /// at any alignment
+    pub fn from_bytes(buf: &[u8; 32]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        let mut name1 = [0u16; 5];
+        let mut name2 = [0u16; 6];
+        let mut name3 = [0u16; 2];
+        for (i, c) in name1.iter_mut().enumerate() {
+            *c = u16_at(1 + i * 2);
+        }
+        for (i, c) in name2.iter_mut().enumerate() {
+            *c = u16_at(14 + i * 2);
+        }
+        for (i, c) in name3.iter_mut().enumerate() {
+            *c = u16_at(28 + i * 2);
+        }
+        Self {
+            ord: buf[0],
+            name1,
+            attr: buf[11],
+            ldir_type: buf[12],
+            chk_sum: buf[13],
+            name2,
+            fst_clus_lo: u16_at(26),
+            name3,
+        }
     }
 
     pub fn attr(&self) -> u8 {
@@ -924,7 +1053,8 @@ impl LongDirEntry {
         let mut temp = [0xFF; 26];
         let mut index = 0;
 
-        for i in value.encode_utf16() {
+        // 一个长名目录项最多容纳 13 个 UTF-16 字符, 多余部分截断
+        for i in value.encode_utf16().take(LONG_NAME_LEN_CAP) {
             // u16 低 8 位
             let part1 = (i & 0xFF) as u8;
             // u16 高 8 位
@@ -1016,16 +1146,31 @@ impl LongDirEntry {
     }
 
     pub fn as_bytes_array(&self) -> [u8; 32] {
-        unsafe { core::ptr::read_unaligned(self as *const Self as *const [u8; 32]) }
+        self.to_bytes_array()
     }
 
     pub fn as_bytes_array_mut(&mut self) -> &mut [u8; 32] {
         unsafe { &mut *(self as *mut Self as *mut [u8; 32]) }
     }
 
+    /// Encode the entry field by field into its on-disk (little-endian) bytes, without
+    /// referencing the packed struct's memory
     pub fn to_bytes_array(&self) -> [u8; 32] {
-        let mut buf = [0; 32];
-        buf.copy_from_slice(self.as_bytes());
+        let mut buf = [0u8; 32];
+        buf[0] = self.ord;
+        for (i, c) in { self.name1 }.iter().enumerate() {
+            buf[1 + i * 2..3 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+        buf[11] = self.attr;
+        buf[12] = self.ldir_type;
+        buf[13] = self.chk_sum;
+        for (i, c) in { self.name2 }.iter().enumerate() {
+            buf[14 + i * 2..16 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+        buf[26..28].copy_from_slice(&{ self.fst_clus_lo }.to_le_bytes());
+        for (i, c) in { self.name3 }.iter().enumerate() {
+            buf[28 + i * 2..30 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
         buf
     }
 
@@ -1043,3 +1188,73 @@ pub(crate) enum NameType {
     SFN,
     LFN,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_entry_decodes_from_unaligned_bytes() {
+        let mut sde = ShortDirEntry::new(0x0012_3456, b"README  ", b"TXT", VirtFileType::File);
+        sde.set_file_size(0x0A0B_0C0D);
+        sde.set_last_write_date(0x5891);
+        // 从奇数偏移处解码
+        let mut buf = [0u8; 33];
+        buf[1..].copy_from_slice(sde.as_bytes());
+        let decoded = ShortDirEntry::new_from_bytes(&buf[1..]);
+        assert_eq!(decoded.to_bytes_array(), sde.to_bytes_array());
+        assert_eq!(decoded.first_cluster(), 0x0012_3456);
+        assert_eq!(decoded.file_size(), 0x0A0B_0C0D);
+        assert_eq!(decoded.last_write_date(), 0x5891);
+        assert_eq!(decoded.get_name_uppercase(), "README.TXT");
+    }
+
+    #[test]
+    fn long_entry_decodes_from_unaligned_bytes() {
+        let lde = LongDirEntry::new(0x42, 0x7A, "abcdefghijklm");
+        let mut buf = [0u8; 35];
+        buf[3..].copy_from_slice(lde.as_bytes());
+        let decoded = LongDirEntry::new_form_bytes(&buf[3..]);
+        assert_eq!(decoded.as_bytes_array(), lde.as_bytes_array());
+        assert_eq!(decoded.order(), 0x42);
+        assert_eq!(decoded.check_sum(), 0x7A);
+        assert_eq!(decoded.name(), "abcdefghijklm");
+    }
+
+    #[test]
+    fn short_entry_serializes_little_endian_fields() {
+        let mut sde = ShortDirEntry::new(0x0012_3456, b"DATA    ", b"BIN", VirtFileType::File);
+        sde.set_file_size(0x0A0B_0C0D);
+        let bytes = sde.to_bytes_array();
+        assert_eq!(&bytes[..11], b"DATA    BIN");
+        assert_eq!(bytes[11], ATTR_ARCHIVE);
+        // 首簇号高 16 位在偏移 20, 低 16 位在偏移 26
+        assert_eq!(bytes[20..22], [0x12, 0x00]);
+        assert_eq!(bytes[26..28], [0x56, 0x34]);
+        assert_eq!(bytes[28..32], [0x0D, 0x0C, 0x0B, 0x0A]);
+
+        let lde = LongDirEntry::new(0x41, 0x99, "ab");
+        let bytes = lde.to_bytes_array();
+        assert_eq!(bytes[0], 0x41);
+        assert_eq!(bytes[1..7], [b'a', 0, b'b', 0, 0, 0]);
+        assert_eq!(bytes[11], ATTR_LONG_NAME);
+        assert_eq!(bytes[13], 0x99);
+        // 名字结束后以 0xFFFF 填充
+        assert_eq!(bytes[7..9], [0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn short_name_compares_without_padding() {
+        let sde = ShortDirEntry::new(0, b"README  ", b"TXT", VirtFileType::File);
+        assert!(sde.name_eq_ignore_case("readme.txt"));
+        assert!(!sde.name_eq_ignore_case("readme"));
+        assert!(!sde.name_eq_ignore_case("readme.txt "));
+        assert_eq!(sde.get_name_uppercase(), "README.TXT");
+
+        // 无扩展名时没有 '.'
+        let sde = ShortDirEntry::new(0, b"MAKEFILE", b"   ", VirtFileType::File);
+        assert!(sde.name_eq_ignore_case("Makefile"));
+        assert!(!sde.name_eq_ignore_case("makefile."));
+        assert_eq!(sde.get_name_uppercase(), "MAKEFILE");
+    }
+}
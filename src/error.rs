@@ -0,0 +1,82 @@
+//! 统一的错误类型
+//!
+//! 各模块仍然返回自己的错误枚举, [`Error`] 通过 `From` 将它们合并,
+//! 便于调用方使用 `Result<T, fat32::Error>` 与 `?`.
+
+use core::fmt::{self, Display, Formatter};
+
+use super::{
+    dir::{DirError, EntryError},
+    fat::ClusterChainErr,
+    file::FileError,
+    fs::FsError,
+    DeviceErr,
+};
+
+/// Crate-level error wrapping the error of each module.
+///
+/// ```
+/// use fat32::{DirError, Error, FileError};
+///
+/// let err: Error = DirError::NoMatch.into();
+/// assert_eq!(format!("{}", err), "dir error: no such file or directory");
+/// assert_eq!(format!("{}", FileError::BufTooSmall), "buffer too small");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    Device(DeviceErr),
+    Dir(DirError),
+    Entry(EntryError),
+    File(FileError),
+    ClusterChain(ClusterChainErr),
+    Fs(FsError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Device(e) => write!(f, "device error: {}", e),
+            Error::Dir(e) => write!(f, "dir error: {}", e),
+            Error::Entry(e) => write!(f, "entry error: {}", e),
+            Error::File(e) => write!(f, "file error: {}", e),
+            Error::ClusterChain(e) => write!(f, "cluster chain error: {}", e),
+            Error::Fs(e) => write!(f, "fs error: {}", e),
+        }
+    }
+}
+
+impl From<DeviceErr> for Error {
+    fn from(e: DeviceErr) -> Self {
+        Error::Device(e)
+    }
+}
+
+impl From<DirError> for Error {
+    fn from(e: DirError) -> Self {
+        Error::Dir(e)
+    }
+}
+
+impl From<EntryError> for Error {
+    fn from(e: EntryError) -> Self {
+        Error::Entry(e)
+    }
+}
+
+impl From<FileError> for Error {
+    fn from(e: FileError) -> Self {
+        Error::File(e)
+    }
+}
+
+impl From<ClusterChainErr> for Error {
+    fn from(e: ClusterChainErr) -> Self {
+        Error::ClusterChain(e)
+    }
+}
+
+impl From<FsError> for Error {
+    fn from(e: FsError) -> Self {
+        Error::Fs(e)
+    }
+}
@@ -15,16 +15,19 @@ use alloc::vec::Vec;
 use core::{
     assert,
     clone::Clone,
-    fmt::Debug,
+    fmt::{self, Debug, Display, Formatter},
     iter::Iterator,
     option::Option,
     option::Option::{None, Some},
 };
 
+use spin::RwLock;
+
 use super::{
     cache::{get_block_cache, Cache},
     device::BlockDevice,
-    read_le_u32, BLOCK_SIZE, CLUSTER_MASK, END_OF_CLUSTER, NEW_VIR_FILE_CLUSTER,
+    read_le_u32, BAD_CLUSTER, BLOCK_SIZE, CLUSTER_MASK, END_OF_CLUSTER, EOC_MARK, FREE_CLUSTER,
+    NEW_VIR_FILE_CLUSTER,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -35,6 +38,18 @@ pub enum ClusterChainErr {
     NoneNextCluster,
 }
 
+impl Display for ClusterChainErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            ClusterChainErr::ReadError => "failed to read cluster chain",
+            ClusterChainErr::WriteError => "failed to write cluster chain",
+            ClusterChainErr::NonePreviousCluster => "no previous cluster",
+            ClusterChainErr::NoneNextCluster => "no next cluster",
+        };
+        f.write_str(msg)
+    }
+}
+
 #[derive(Clone)]
 /// Cluster Chain in FAT Table.
 ///
@@ -45,10 +60,8 @@ pub enum ClusterChainErr {
 //  根据 cluster_id 求出偏移时, 数据区以 cluster_size 为单位从 0 开始计算, cluster_id - 2
 pub struct ClusterChain {
     pub(crate) device: Arc<dyn BlockDevice>,
-    // FAT表的偏移, 也是 start_cluster 的第一个 sector 的偏移
-    // 目前仅指 FAT1, 可以通过 BIOSParameterBlock::fat1() 方法获取
-    // TODO 支持 FAT2
-    pub(crate) fat1_offset: usize, // read_only
+    // 活动 FAT 的偏移, 由 ext_flags 选择, 可以通过 BIOSParameterBlock::active_fat_offset() 获取
+    pub(crate) fat_offset: usize, // read_only
     // 簇号链表的起始簇号 (短目录项可以提供)
     pub(crate) start_cluster: u32, // 创建一次不再改变
     pub(crate) previous_cluster: Option<u32>,
@@ -80,7 +93,7 @@ impl ClusterChain {
     pub(crate) fn new(cluster: u32, device: Arc<dyn BlockDevice>, fat_offset: usize) -> Self {
         Self {
             device: Arc::clone(&device),
-            fat1_offset: fat_offset,
+            fat_offset,
             start_cluster: cluster,
             previous_cluster: None,
             current_cluster: 0,
@@ -132,16 +145,12 @@ impl Iterator for ClusterChain {
         let block_offset = offset / BLOCK_SIZE;
         let offset_left = offset % BLOCK_SIZE;
 
-        let block_id = self.fat1_offset / BLOCK_SIZE + block_offset;
-        let mut buffer = [0u8; BLOCK_SIZE];
+        let block_id = self.fat_offset / BLOCK_SIZE + block_offset;
 
-        get_block_cache(block_id, Arc::clone(&self.device))
+        // 只读取所需的 4 字节表项, 与 FATManager::get_next_cluster 一致
+        let next_cluster: u32 = get_block_cache(block_id, Arc::clone(&self.device))
             .read()
-            .read(0, |buf: &[u8; BLOCK_SIZE]| {
-                buffer.copy_from_slice(buf);
-            });
-
-        let next_cluster = read_le_u32(&buffer[offset_left..offset_left + 4]);
+            .read(offset_left, |&value: &u32| value);
         let next_cluster = if next_cluster >= END_OF_CLUSTER {
             None
         } else {
@@ -158,45 +167,170 @@ impl Iterator for ClusterChain {
     }
 }
 
+/// Iterator over the cluster ids of a cluster chain, in order. Returned by
+/// [`crate::fs::FileSystem::cluster_chain`].
+///
+/// Iteration stops at EOC, at an entry that is not a valid next cluster (free, reserved or bad),
+/// or after `limit` clusters, so a corrupted FAT with a loop cannot make it spin forever.
+pub struct ClusterIter {
+    fat: Arc<RwLock<FATManager>>,
+    next: Option<u32>,
+    limit: usize,
+}
+
+impl ClusterIter {
+    pub(crate) fn new(fat: Arc<RwLock<FATManager>>, first_cluster: u32, limit: usize) -> Self {
+        let next = if (2..BAD_CLUSTER).contains(&first_cluster) {
+            Some(first_cluster & CLUSTER_MASK)
+        } else {
+            None
+        };
+        Self { fat, next, limit }
+    }
+}
+
+impl Iterator for ClusterIter {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        let cluster = self.next?;
+        if self.limit == 0 {
+            self.next = None;
+            return None;
+        }
+        self.limit -= 1;
+
+        // 不使用 get_next_cluster, 其对无效表项会 panic
+        let fat = self.fat.read();
+        let (block_id, offset_in_block) = fat.cluster_id_pos(cluster);
+        let value: u32 = get_block_cache(block_id, Arc::clone(&fat.device))
+            .read()
+            .read(offset_in_block, |&value: &u32| value)
+            & CLUSTER_MASK;
+        self.next = if (2..BAD_CLUSTER).contains(&value) {
+            Some(value)
+        } else {
+            None
+        };
+        Some(cluster)
+    }
+}
+
+/// Iterator over the runs of free clusters as `(start, count)`, in cluster order. Returned by
+/// [`crate::fs::FileSystem::free_extents`]; the FAT is read lazily, one run per `next`.
+pub struct FreeExtents {
+    fat: Arc<RwLock<FATManager>>,
+    cluster: u32,
+    end: u32,
+}
+
+impl FreeExtents {
+    pub(crate) fn new(fat: Arc<RwLock<FATManager>>, data_cluster_cnt: usize) -> Self {
+        Self {
+            fat,
+            cluster: 2,
+            end: data_cluster_cnt as u32 + 2,
+        }
+    }
+
+    fn is_free(&self, cluster: u32) -> bool {
+        let fat = self.fat.read();
+        let (block_id, offset_in_block) = fat.cluster_id_pos(cluster);
+        let value: u32 = get_block_cache(block_id, Arc::clone(&fat.device))
+            .read()
+            .read(offset_in_block, |&value: &u32| value);
+        value & CLUSTER_MASK == FREE_CLUSTER
+    }
+}
+
+impl Iterator for FreeExtents {
+    type Item = (u32, u32);
+
+    fn next(&mut self) -> Option<(u32, u32)> {
+        while self.cluster < self.end && !self.is_free(self.cluster) {
+            self.cluster += 1;
+        }
+        if self.cluster >= self.end {
+            return None;
+        }
+        let start = self.cluster;
+        while self.cluster < self.end && self.is_free(self.cluster) {
+            self.cluster += 1;
+        }
+        Some((start, self.cluster - start))
+    }
+}
+
+/// One of the two FAT copies of a volume
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatCopy {
+    Fat1,
+    Fat2,
+}
+
+impl FatCopy {
+    pub fn index(&self) -> usize {
+        match self {
+            FatCopy::Fat1 => 0,
+            FatCopy::Fat2 => 1,
+        }
+    }
+}
+
 //  整个 Fat 表的簇号从 2 开始, 0 和 1 为保留簇号, 0 表示无效簇号, 1 表示最后一个簇号,
 //  在数据区以 cluster_size 为单位从 0 开始编号, 故根据 cluster_id 求出偏移时 cluster_id - 2
 //  通过 bpb.first_data_sector() 可得到从磁盘0号扇区开始编号的数据区的第一个扇区号(距离磁盘0号扇区的扇区数)
 //
-//  TODO 目前只做了FAT1 (FAT2相当于对FAT1的备份, 可以在每次打开文件系统时复制FAT1到FAT2)
+//  读写都针对由 ext_flags 选择的活动 FAT, 镜像模式下同时写入其他 FAT (见 mirror_offsets)
 //  TODO 将整个 FAT 放入内存中进行管理(查空块, 写簇, 簇链随机分配), 以提高性能
 pub struct FATManager {
     device: Arc<dyn BlockDevice>,
     recycled_cluster: VecDeque<u32>,
-    fat1_offset: usize,
+    // 活动 FAT 的偏移, 所有读写都针对活动 FAT
+    fat_offset: usize,
+    // 需要同步写入的其他 FAT 的偏移 (ext_flags bit 7 为 1 时为空)
+    mirror_offsets: Vec<usize>,
+    // 结束簇链时写入的值
+    eoc_value: u32,
+    // 新文件分配簇时开始查找的位置 (FSInfo 的 nxt_free, 打开时校验)
+    next_free_hint: u32,
 }
 
 impl FATManager {
-    pub fn open(fat_offset: usize, device: Arc<dyn BlockDevice>) -> Self {
+    pub fn open(
+        fat_offset: usize,
+        mirror_offsets: Vec<usize>,
+        device: Arc<dyn BlockDevice>,
+    ) -> Self {
         Self {
             device: Arc::clone(&device),
             recycled_cluster: VecDeque::new(),
-            fat1_offset: fat_offset,
+            fat_offset,
+            mirror_offsets,
+            eoc_value: EOC_MARK,
+            next_free_hint: 2,
         }
     }
 
-    pub fn new(fat_offset: usize, device: Arc<dyn BlockDevice>) -> Self {
+    pub fn new(
+        fat_offset: usize,
+        mirror_offsets: Vec<usize>,
+        device: Arc<dyn BlockDevice>,
+    ) -> Self {
         let fat = Self {
             device: Arc::clone(&device),
             recycled_cluster: VecDeque::new(),
-            fat1_offset: fat_offset,
+            fat_offset,
+            mirror_offsets,
+            eoc_value: EOC_MARK,
+            next_free_hint: 2,
         };
 
         // Initialize FAT1 Table
         // 由于簇号从 2 开始, 现在将簇号 0, 1 的内容填充方便找到正确的簇(防止误操作)
-        let block_id = fat.fat1_offset / BLOCK_SIZE;
-
-        assert!(fat.fat1_offset % BLOCK_SIZE == 0);
-        get_block_cache(block_id, Arc::clone(&device))
-            .write()
-            .modify(0, |buf: &mut [u32; 2]| {
-                buf[0] = END_OF_CLUSTER;
-                buf[1] = END_OF_CLUSTER;
-            });
+        assert!(fat.fat_offset.is_multiple_of(BLOCK_SIZE));
+        fat.set_next_cluster(0, END_OF_CLUSTER);
+        fat.set_next_cluster(1, END_OF_CLUSTER);
 
         fat
     }
@@ -213,7 +347,7 @@ impl FATManager {
         // 不需要 断言 index >= 2, 理由:
         // 1. fs::open 时对 fat_manager 预处理了
         // 2. 新建文件的 cluster_id = 0 会 panic
-        let offset = index as usize * 4 + self.fat1_offset;
+        let offset = index as usize * 4 + self.fat_offset;
         let block_id = offset / BLOCK_SIZE;
         let offset_in_block = offset % BLOCK_SIZE;
         (block_id, offset_in_block)
@@ -235,7 +369,7 @@ impl FATManager {
                     buffer.copy_from_slice(buf);
                 });
             for i in (offset..BLOCK_SIZE).step_by(4) {
-                if read_le_u32(&buffer[i..i + 4]) == 0 {
+                if read_le_u32(&buffer[i..]) == Some(0) {
                     done = true;
                     break;
                 } else {
@@ -258,10 +392,39 @@ impl FATManager {
         }
     }
 
+    // 与 blank_cluster 相同, 但以数据区末尾为界: 从 prev 之后开始找, 到末尾后从簇 2 继续找.
+    // prev 是簇链当前的末尾 (可能尚未在 FAT 中标记), 不在查找范围内, 以免簇链指向自身
+    pub(crate) fn blank_cluster_after(
+        &mut self,
+        prev: u32,
+        data_cluster_cnt: usize,
+    ) -> Option<u32> {
+        match self.recycled_cluster.pop_front() {
+            Some(cluster) => Some(cluster & CLUSTER_MASK),
+            None => {
+                let end = (data_cluster_cnt + 2) as u32;
+                self.first_free_in((prev + 1..end).chain(2..prev.min(end)))
+            }
+        }
+    }
+
+    // 与 blank_cluster_after 相同, 但 from 本身也在查找范围内, 用于从 nxt_free 提示开始分配
+    pub(crate) fn blank_cluster_from(&mut self, from: u32, data_cluster_cnt: usize) -> Option<u32> {
+        match self.recycled_cluster.pop_front() {
+            Some(cluster) => Some(cluster & CLUSTER_MASK),
+            None => self.first_free_from(from, data_cluster_cnt),
+        }
+    }
+
     pub fn recycle(&mut self, cluster: u32) {
         self.recycled_cluster.push_back(cluster);
     }
 
+    // 丢弃回收队列, FAT 表被整体改写后其中的簇可能已不再空闲
+    pub(crate) fn clear_recycled(&mut self) {
+        self.recycled_cluster.clear();
+    }
+
     // Query the next cluster of the specific cluster
     //
     // 最后一个簇的值, next_cluster 可能等于 EOC
@@ -283,6 +446,7 @@ impl FATManager {
     // Set the next cluster of the specific cluster
     //
     // 在磁盘的FAT表中的簇号 cluster(offset) 处写入 cluster 的 value(下一个簇号)
+    // FAT 镜像开启时, 同步写入其他 FAT
     pub fn set_next_cluster(&self, cluster: u32, next_cluster: u32) {
         let (block_id, offset_in_block) = self.cluster_id_pos(cluster);
         get_block_cache(block_id, Arc::clone(&self.device))
@@ -290,6 +454,27 @@ impl FATManager {
             .modify(offset_in_block, |value: &mut u32| {
                 *value = next_cluster;
             });
+        for mirror_offset in self.mirror_offsets.iter() {
+            let offset = cluster as usize * 4 + mirror_offset;
+            get_block_cache(offset / BLOCK_SIZE, Arc::clone(&self.device))
+                .write()
+                .modify(offset % BLOCK_SIZE, |value: &mut u32| {
+                    *value = next_cluster;
+                });
+        }
+    }
+
+    // 将 cluster 标记为簇链的最后一个簇
+    pub fn set_end_of_chain(&self, cluster: u32) {
+        self.set_next_cluster(cluster, self.eoc_value);
+    }
+
+    pub fn eoc_value(&self) -> u32 {
+        self.eoc_value
+    }
+
+    pub(crate) fn set_eoc_value(&mut self, value: u32) {
+        self.eoc_value = value;
     }
 
     // Get the ith cluster of a cluster chain
@@ -336,6 +521,77 @@ impl FATManager {
         }
     }
 
+    /// Count the free entries among the `data_cluster_cnt` data clusters (cluster 2 onwards)
+    pub fn count_free_clusters(&self, data_cluster_cnt: usize) -> usize {
+        let mut free = 0;
+        for cluster in 2..(data_cluster_cnt + 2) as u32 {
+            let (block_id, offset_in_block) = self.cluster_id_pos(cluster);
+            let value = get_block_cache(block_id, Arc::clone(&self.device))
+                .read()
+                .read(offset_in_block, |&value: &u32| value);
+            if value & CLUSTER_MASK == FREE_CLUSTER {
+                free += 1;
+            }
+        }
+        free
+    }
+
+    /// First cluster at which allocations without a nearby cluster (new files) start looking
+    pub fn next_free_hint(&self) -> u32 {
+        self.next_free_hint
+    }
+
+    pub(crate) fn set_next_free_hint(&mut self, cluster: u32) {
+        self.next_free_hint = cluster;
+    }
+
+    // 从 from 开始找第一个空闲簇, 到数据区末尾后从簇 2 继续找
+    pub(crate) fn first_free_from(&self, from: u32, data_cluster_cnt: usize) -> Option<u32> {
+        let end = (data_cluster_cnt + 2) as u32;
+        self.first_free_in((from..end).chain(2..from.min(end)))
+    }
+
+    fn first_free_in(&self, mut clusters: impl Iterator<Item = u32>) -> Option<u32> {
+        clusters.find(|&cluster| {
+            let (block_id, offset_in_block) = self.cluster_id_pos(cluster);
+            let value = get_block_cache(block_id, Arc::clone(&self.device))
+                .read()
+                .read(offset_in_block, |&value: &u32| value);
+            value & CLUSTER_MASK == FREE_CLUSTER
+        })
+    }
+
+    // 找到 len 个连续的空闲簇, 返回第一个簇号
+    pub fn find_free_run(&self, len: usize, data_cluster_cnt: usize) -> Option<u32> {
+        let mut run_start = 2;
+        let mut run_len = 0;
+        for cluster in 2..(data_cluster_cnt + 2) as u32 {
+            let (block_id, offset_in_block) = self.cluster_id_pos(cluster);
+            let value = get_block_cache(block_id, Arc::clone(&self.device))
+                .read()
+                .read(offset_in_block, |&value: &u32| value);
+            if value & CLUSTER_MASK != FREE_CLUSTER {
+                run_len = 0;
+                continue;
+            }
+            if run_len == 0 {
+                run_start = cluster;
+            }
+            run_len += 1;
+            if run_len == len {
+                return Some(run_start);
+            }
+        }
+        None
+    }
+
+    // 从回收队列中移除 [start, start + len) 中的簇, 这些簇已被直接分配
+    pub(crate) fn unrecycle_run(&mut self, start: u32, len: usize) {
+        let run = start..start + len as u32;
+        self.recycled_cluster
+            .retain(|cluster| !run.contains(cluster));
+    }
+
     pub fn cluster_chain_len(&self, start_cluster: u32) -> u32 {
         let mut curr_cluster = start_cluster;
         let mut len = 0;
@@ -350,3 +606,119 @@ impl FATManager {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_util::{pattern, read_all, TestFs},
+        Dir, FileSystem, VirtFileType,
+    };
+
+    fn read_entry(device: &dyn BlockDevice, fat_offset: usize, cluster: u32) -> u32 {
+        let offset = fat_offset + cluster as usize * 4;
+        let mut block = [0u8; BLOCK_SIZE];
+        device
+            .read_blocks(&mut block, offset / BLOCK_SIZE * BLOCK_SIZE, 1)
+            .unwrap();
+        read_le_u32(&block[offset % BLOCK_SIZE..]).unwrap() & 0x0FFF_FFFF
+    }
+
+    #[test]
+    fn unmirrored_fat_uses_active_fat_only() {
+        let t = TestFs::new();
+        drop(t.fs);
+
+        // BPB_ExtFlags (偏移 40): 第 7 位置位关闭镜像, 活动 FAT 为 FAT2
+        let mut boot = [0u8; BLOCK_SIZE];
+        t.device.read_blocks(&mut boot, 0, 1).unwrap();
+        boot[40..42].copy_from_slice(&0x81u16.to_le_bytes());
+        t.device.write_blocks(&boot, 0, 1).unwrap();
+
+        let fs = FileSystem::open(t.device.clone());
+        let bpb = fs.read().bpb();
+        assert!(!bpb.is_fat_mirrored());
+        assert_eq!(bpb.active_fat_offset(), bpb.fat_offset(1));
+        assert!(bpb.mirror_fat_offsets().is_empty());
+
+        let data = pattern(3 * fs.read().cluster_size(), 6);
+        let file = fs
+            .read()
+            .root()
+            .create("fat2.bin", VirtFileType::File)
+            .unwrap();
+        assert_eq!(file.write_at(0, &data), data.len());
+        let clusters: Vec<u32> = fs
+            .read()
+            .cluster_chain(file.first_cluster() as u32)
+            .collect();
+        assert_eq!(clusters.len(), 3);
+        drop(file);
+        drop(fs);
+
+        for (i, &cluster) in clusters.iter().enumerate() {
+            let expected = clusters.get(i + 1).copied().unwrap_or(EOC_MARK);
+            assert_eq!(
+                read_entry(t.device.as_ref(), bpb.fat_offset(1), cluster),
+                expected
+            );
+            // FAT1 没有被镜像写入
+            assert_eq!(read_entry(t.device.as_ref(), bpb.fat_offset(0), cluster), 0);
+        }
+
+        // 重新挂载后通过 FAT2 读回文件
+        let fs = FileSystem::open(t.device.clone());
+        let file = fs.read().root().find(vec!["fat2.bin"]).unwrap();
+        assert_eq!(read_all(&file), data);
+    }
+
+    #[test]
+    fn cluster_chain_follows_and_bounds_the_chain() {
+        let t = TestFs::new();
+        let file = t.create_file("chain.bin", &pattern(3 * t.cluster_size(), 2));
+        let first = file.first_cluster() as u32;
+        let fs = t.fs.read();
+
+        let clusters: Vec<u32> = fs.cluster_chain(first).collect();
+        let expected: Vec<u32> = (0..3)
+            .map(|i| fs.fat.read().get_cluster_at(first, i).unwrap())
+            .collect();
+        assert_eq!(clusters, expected);
+        assert_eq!(fs.cluster_chain(0).count(), 0);
+
+        // 成环的簇链也会结束
+        fs.fat.write().set_next_cluster(clusters[2], first);
+        assert_eq!(fs.cluster_chain(first).count(), fs.bpb().data_cluster_cnt());
+        // 指向空闲簇的表项结束迭代
+        fs.fat.write().set_next_cluster(clusters[1], FREE_CLUSTER);
+        assert_eq!(fs.cluster_chain(first).count(), 2);
+    }
+
+    #[test]
+    fn cluster_chain_steps_through_fat_entries() {
+        let t = TestFs::new();
+        let a = t.create_file("a.bin", &pattern(2 * t.cluster_size(), 1));
+        t.create_file("b.bin", &pattern(t.cluster_size(), 2));
+        // 追加使 a 的簇链不连续
+        assert_eq!(
+            a.write_at(a.file_size(), &pattern(2 * t.cluster_size(), 3)),
+            2 * t.cluster_size()
+        );
+
+        let first = a.first_cluster() as u32;
+        let (device, fat_offset) = {
+            let fs = t.fs.read();
+            (fs.device(), fs.bpb().active_fat_offset())
+        };
+        let steps: Vec<(u32, Option<u32>)> = ClusterChain::new(first, device, fat_offset)
+            .map(|chain| (chain.current_cluster, chain.next_cluster))
+            .collect();
+        let expected: Vec<u32> = t.fs.read().cluster_chain(first).collect();
+        let currents: Vec<u32> = steps.iter().map(|&(current, _)| current).collect();
+        assert_eq!(currents, expected);
+        // 每一步的 next_cluster 即下一步的簇号, 最后一个为 EOC
+        for (i, &(_, next)) in steps.iter().enumerate() {
+            assert_eq!(next, expected.get(i + 1).copied());
+        }
+    }
+}
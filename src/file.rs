@@ -7,6 +7,7 @@ use alloc::sync::Arc;
 use core::{
     clone::Clone,
     cmp::Ord,
+    fmt::{self, Display, Formatter},
     result::Result,
     result::Result::{Err, Ok},
 };
@@ -15,7 +16,7 @@ use super::{
     cache::{get_block_cache, Cache},
     get_needed_sector,
     vfs::VirtFile,
-    BLOCK_SIZE, NEW_VIR_FILE_CLUSTER,
+    BLOCK_SIZE, MAX_FILE_SIZE, NEW_VIR_FILE_CLUSTER,
 };
 
 pub trait File {
@@ -34,13 +35,35 @@ pub enum WriteType {
 pub enum FileError {
     BufTooSmall,
     WriteError,
+    ReadError,
     ReadOutOfBound,
     BadClusterChain,
+    NoSpace,
+    Unsupported,
+    FileTooLarge,
+}
+
+impl Display for FileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            FileError::BufTooSmall => "buffer too small",
+            FileError::WriteError => "failed to write file",
+            FileError::ReadError => "failed to read file",
+            FileError::ReadOutOfBound => "read out of bound",
+            FileError::BadClusterChain => "bad cluster chain",
+            FileError::NoSpace => "no space left on device",
+            FileError::Unsupported => "operation not supported",
+            FileError::FileTooLarge => "file too large",
+        };
+        f.write_str(msg)
+    }
 }
 
 impl File for VirtFile {
     /// Read File To Buffer, Return File Length
     fn read(&self, buf: &mut [u8]) -> Result<usize, FileError> {
+        let lock = self.file_lock();
+        let _guard = lock.as_ref().map(|lock| lock.read());
         let file_size = self.file_size();
         let spc = self.fs.read().sector_pre_cluster();
         let cluster_size = spc * BLOCK_SIZE;
@@ -57,7 +80,7 @@ impl File for VirtFile {
         let mut index = 0;
         clus_chain
             .map(|f| {
-                let offset_in_disk = self.fs.read().bpb.offset(f.current_cluster);
+                let start_block_id = self.fs.read().cluster_to_block(f.current_cluster);
 
                 let end = if (file_size - index) < cluster_size {
                     // 读取长度在一个簇之内
@@ -70,12 +93,11 @@ impl File for VirtFile {
                 };
 
                 for i in 0..block_cnt {
-                    assert!(offset_in_disk % BLOCK_SIZE == 0);
-                    let block_id = offset_in_disk / BLOCK_SIZE + i;
+                    let block_id = start_block_id + i;
                     let len = (BLOCK_SIZE).min(end - index);
                     let mut block_buffer = [0u8; BLOCK_SIZE];
 
-                    let device = self.fs.read().device();
+                    let device = Arc::clone(&self.device);
                     get_block_cache(block_id, device).read().read(
                         0,
                         |buffer: &[u8; BLOCK_SIZE]| {
@@ -92,27 +114,31 @@ impl File for VirtFile {
             })
             .last();
 
+        if file_size > 0 && self.fs.read().update_atime() {
+            self.touch_atime();
+        }
+
         Ok(file_size)
     }
 
     fn write(&self, buf: &[u8], write_type: WriteType) -> Result<usize, FileError> {
         let file_size = self.file_size();
-
-        let new_size: usize;
-
-        let write_size = match write_type {
-            WriteType::OverWritten => {
-                new_size = buf.len();
-                self.write_at(0, buf)
-            }
-            WriteType::Append => {
-                new_size = file_size + buf.len();
-                self.write_at(file_size, buf)
-            }
+        let end = match write_type {
+            WriteType::OverWritten => buf.len(),
+            WriteType::Append => file_size + buf.len(),
         };
+        if end > MAX_FILE_SIZE {
+            return Err(FileError::FileTooLarge);
+        }
 
-        self.set_file_size(new_size);
+        let offset = match write_type {
+            WriteType::OverWritten => 0,
+            WriteType::Append => file_size,
+        };
+        // 空间不足时返回 NoSpace, 不修改 file_size
+        self.write_all_at(offset, buf)?;
+        self.set_file_size(end);
 
-        Ok(write_size)
+        Ok(buf.len())
     }
 }
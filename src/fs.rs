@@ -1,21 +1,34 @@
-use alloc::{sync::Arc, vec::Vec};
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
 use core::{
     assert,
     clone::Clone,
+    fmt::{self, Display, Formatter},
+    ops::{Drop, Range},
     option::Option,
     option::Option::{None, Some},
+    sync::atomic::{AtomicU64, Ordering},
 };
 use spin::RwLock;
 
 use super::{
-    bpb::{BIOSParameterBlock, BasicBPB, FSInfo, BPB32},
-    cache::{get_block_cache, Cache},
-    device::BlockDevice,
+    bpb::{BIOSParameterBlock, BasicBPB, FSInfo, FatType, BPB32},
+    cache::{evict_cache, get_block_cache, sync_all, try_get_block_cache, Cache},
+    device::{BlockDevice, DeviceErr, OffsetDevice},
+    dir::DirError,
     entry::ShortDirEntry,
-    fat::FATManager,
-    vfs::VirtFileType,
-    BLOCK_NUM, BLOCK_SIZE, END_OF_CLUSTER, FREE_CLUSTER, NEW_VIR_FILE_CLUSTER, ROOT,
-    ROOT_DIR_CLUSTER,
+    fat::{ClusterChain, ClusterIter, FATManager, FatCopy, FreeExtents},
+    file::FileError,
+    mbr::MbrPartition,
+    vfs::{self, DirEntryPos, VirtFile, VirtFileType},
+    BAD_CLUSTER, BLOCK_NUM, BLOCK_SIZE, BOOT_CODE_LEN, BOOT_CODE_OFFSET, BOOT_SIGNATURE,
+    BOOT_SIGNATURE_OFFSET, CLN_SHUT_BIT_MASK_FAT32, CLUSTER_MASK, DETACHED_DIR_ENTRY_CLUSTER,
+    END_OF_CLUSTER, FAT_EPOCH_DATE, FREE_CLUSTER, FSINFO_UNKNOWN, HRD_ERR_BIT_MASK_FAT32,
+    MAX_CLUSTER_FAT16, NEW_VIR_FILE_CLUSTER, ROOT, ROOT_DIR_CLUSTER,
 };
 
 pub struct FileSystem {
@@ -24,6 +37,168 @@ pub struct FileSystem {
     pub(crate) bpb: BIOSParameterBlock, // read only
     pub(crate) fat: Arc<RwLock<FATManager>>,
     pub(crate) root_dir_entry: Arc<RwLock<ShortDirEntry>>, // 虚拟根目录项。根目录无目录项，引入以与其他文件一致
+    pub(crate) self_ref: Weak<RwLock<FileSystem>>,         // 指向自身, 用于由 &self 构造 VirtFile
+    pub(crate) fsinfo_dirty: RwLock<bool>,                 // 内存中的空闲簇数尚未写入 FSInfo
+    pub(crate) clock: Clock,
+    pub(crate) update_atime: bool, // 读文件时是否更新最后访问日期, 默认关闭以避免额外写入
+    pub(crate) read_only: bool,
+    // 打开时 FAT[1] 的状态: 上次未正常卸载 / 曾发生磁盘错误
+    pub(crate) was_dirty: bool,
+    pub(crate) had_io_errors: bool,
+    pub(crate) ordered_writes: bool, // 目录项引用新簇之前先写回 FAT, 见 set_ordered_writes
+    pub(crate) tracer: TracerSlot,   // 与 device 中的 TracedDevice 共享
+    // 已构造的根目录, 使用 Weak 以避免 VirtFile -> FileSystem 的循环引用
+    pub(crate) root: RwLock<Weak<VirtFile>>,
+    // 按 inode 登记的文件锁, 由该文件的所有句柄共享, 见 file_lock
+    pub(crate) file_locks: RwLock<BTreeMap<u64, Weak<RwLock<()>>>>,
+}
+
+/// Errors from opening a file system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    Device(DeviceErr),
+    /// The volume is FAT12 or FAT16; only FAT32 is supported
+    UnsupportedFatType(FatType),
+    /// The file system was marked read-only with [`FileSystem::set_read_only`]
+    ReadOnly,
+    /// More than [`BOOT_CODE_LEN`] bytes were passed to [`FileSystem::write_boot_code`]
+    BootCodeTooLong(usize),
+    /// Sector 0 does not end with the 0xAA55 boot signature (see [`FileSystem::try_open_strict`])
+    MissingBootSignature,
+    /// Sector 0 is not an MBR, or the requested entry is not a FAT32 partition
+    /// (see [`FileSystem::open_partition`])
+    PartitionNotFound,
+    /// The BPB has zero bytes per sector or sectors per cluster
+    InvalidBpb,
+    /// The FSInfo sector does not carry the FSInfo lead, struct and trail signatures
+    InvalidFsInfo,
+}
+
+impl Display for FsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FsError::Device(e) => write!(f, "{}", e),
+            FsError::UnsupportedFatType(t) => write!(f, "unsupported fat type {:?}", t),
+            FsError::ReadOnly => f.write_str("read-only file system"),
+            FsError::BootCodeTooLong(len) => {
+                write!(f, "boot code too long: {} > {} bytes", len, BOOT_CODE_LEN)
+            }
+            FsError::MissingBootSignature => f.write_str("missing boot sector signature"),
+            FsError::PartitionNotFound => f.write_str("fat32 partition not found"),
+            FsError::InvalidBpb => f.write_str("invalid bios parameter block"),
+            FsError::InvalidFsInfo => f.write_str("invalid fsinfo signature"),
+        }
+    }
+}
+
+impl From<DeviceErr> for FsError {
+    fn from(e: DeviceErr) -> Self {
+        FsError::Device(e)
+    }
+}
+
+/// Options for [`FileSystem::create_with`]; the default matches [`FileSystem::create`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FormatOptions {
+    /// Volume serial number (BS_VolID). Derived from the clock's date and time when `None`
+    pub volume_serial: Option<u32>,
+    /// Clock of the new file system, [`fat_epoch_clock`] when `None`
+    pub clock: Option<Clock>,
+    /// Number of FATs, 1 or 2. `None` means 2
+    pub num_fats: Option<u8>,
+    /// Reserved sector count (BPB_RsvdSecCnt), at least 7 since FSInfo is at sector 1 and the
+    /// backup boot sector at sector 6. `None` means 32
+    pub reserved_sectors: Option<u16>,
+    /// Sector count of the volume (BPB_TotSec32), at most the size of the device. `None` means
+    /// [`BLOCK_NUM`]
+    pub total_sectors: Option<u32>,
+    /// Sectors per cluster, a power of two up to 128. `None` picks the largest of 8, 4, 2 and 1
+    /// that still leaves the volume the 65525 clusters FAT32 requires
+    pub sectors_per_cluster: Option<u8>,
+}
+
+/// Returns the current `(date, time)` in FAT on-disk encoding.
+///
+/// - date: bits 15-9 year since 1980, bits 8-5 month (1-12), bits 4-0 day (1-31)
+/// - time: bits 15-11 hours, bits 10-5 minutes, bits 4-0 seconds / 2
+pub type Clock = fn() -> (u16, u16);
+
+/// The default [`Clock`]: no_std has no time source, so it always reports 1980-01-01 00:00:00.
+pub fn fat_epoch_clock() -> (u16, u16) {
+    (FAT_EPOCH_DATE, 0)
+}
+
+/// Hooks for observing cluster allocation and block I/O, e.g. to count cache misses where
+/// `println!` isn't available. Every method defaults to a no-op.
+pub trait FsTracer: Send + Sync {
+    /// `count` clusters were allocated as a chain starting at `cluster`
+    fn on_alloc(&self, _cluster: u32, _count: usize) {}
+    /// `clusters` were returned to the free pool
+    fn on_free(&self, _clusters: &[u32]) {}
+    /// A block was read from the device (a block cache miss)
+    fn on_block_read(&self, _block_id: usize) {}
+    /// A block was written to the device (a block cache write back)
+    fn on_block_write(&self, _block_id: usize) {}
+}
+
+/// The default [`FsTracer`], which ignores every event
+pub struct NoopTracer;
+
+impl FsTracer for NoopTracer {}
+
+// 可替换的 tracer, 由 FileSystem 与 TracedDevice 共享
+type TracerSlot = Arc<RwLock<Arc<dyn FsTracer>>>;
+
+// 块缓存 id, 每次 create/open 分配一个, 0 留给未挂载的设备
+static NEXT_CACHE_ID: AtomicU64 = AtomicU64::new(1);
+
+// 所有块设备读写都经过该包装, 以便在缓存加载/写回时通知 tracer.
+// 同时为设备分配唯一的块缓存 id, 同时挂载的多个文件系统的块缓存互不干扰
+struct TracedDevice {
+    inner: Arc<dyn BlockDevice>,
+    tracer: TracerSlot,
+    cache_id: u64,
+}
+
+impl TracedDevice {
+    fn wrap(inner: Arc<dyn BlockDevice>) -> (Arc<dyn BlockDevice>, TracerSlot) {
+        let tracer: TracerSlot = Arc::new(RwLock::new(Arc::new(NoopTracer)));
+        let device = Arc::new(Self {
+            inner,
+            tracer: Arc::clone(&tracer),
+            cache_id: NEXT_CACHE_ID.fetch_add(1, Ordering::Relaxed),
+        });
+        (device, tracer)
+    }
+}
+
+impl BlockDevice for TracedDevice {
+    fn read_blocks(
+        &self,
+        buf: &mut [u8],
+        offset: usize,
+        block_cnt: usize,
+    ) -> Result<(), DeviceErr> {
+        self.inner.read_blocks(buf, offset, block_cnt)?;
+        let tracer = self.tracer.read();
+        for i in 0..buf.len() / BLOCK_SIZE {
+            tracer.on_block_read(offset / BLOCK_SIZE + i);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, buf: &[u8], offset: usize, block_cnt: usize) -> Result<(), DeviceErr> {
+        self.inner.write_blocks(buf, offset, block_cnt)?;
+        let tracer = self.tracer.read();
+        for i in 0..buf.len() / BLOCK_SIZE {
+            tracer.on_block_write(offset / BLOCK_SIZE + i);
+        }
+        Ok(())
+    }
+
+    fn cache_id(&self) -> u64 {
+        self.cache_id
+    }
 }
 
 impl FileSystem {
@@ -39,21 +214,347 @@ impl FileSystem {
         self.bpb.bytes_per_sector() * self.bpb.sector_per_cluster()
     }
 
+    /// Preferred size of a single bulk read or write: one cluster, the unit in which file data
+    /// is laid out contiguously on disk.
+    pub fn optimal_io_size(&self) -> usize {
+        self.cluster_size()
+    }
+
+    /// Round `n` up to a multiple of the cluster size, e.g. to size a buffer for whole-cluster
+    /// I/O.
+    ///
+    /// ```
+    /// # use std::sync::{Arc, Mutex};
+    /// # use fat32::{BlockDevice, DeviceErr, FileSystem};
+    /// # struct Ram(Mutex<Vec<u8>>);
+    /// # impl BlockDevice for Ram {
+    /// #     fn read_blocks(&self, buf: &mut [u8], offset: usize, _: usize) -> Result<(), DeviceErr> {
+    /// #         buf.copy_from_slice(&self.0.lock().unwrap()[offset..offset + buf.len()]);
+    /// #         Ok(())
+    /// #     }
+    /// #     fn write_blocks(&self, buf: &[u8], offset: usize, _: usize) -> Result<(), DeviceErr> {
+    /// #         self.0.lock().unwrap()[offset..offset + buf.len()].copy_from_slice(buf);
+    /// #         Ok(())
+    /// #     }
+    /// # }
+    /// # let device = Arc::new(Ram(Mutex::new(vec![0u8; fat32::BLOCK_NUM as usize * 512])));
+    /// let fs = FileSystem::create(device);
+    /// let fs = fs.read();
+    /// let cluster_size = fs.cluster_size();
+    /// assert_eq!(fs.optimal_io_size(), cluster_size);
+    /// assert_eq!(fs.align_up_to_cluster(0), 0);
+    /// assert_eq!(fs.align_up_to_cluster(1), cluster_size);
+    /// assert_eq!(fs.align_up_to_cluster(cluster_size), cluster_size);
+    /// assert_eq!(fs.align_up_to_cluster(cluster_size + 1), 2 * cluster_size);
+    /// ```
+    pub fn align_up_to_cluster(&self, n: usize) -> usize {
+        n.next_multiple_of(self.cluster_size())
+    }
+
     pub fn first_data_sector(&self) -> usize {
         self.bpb.first_data_sector()
     }
 
+    /// Replace the clock used for directory entry timestamps (see [`Clock`])
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    /// Current `(date, time)` from the configured clock, in FAT encoding
+    pub fn now(&self) -> (u16, u16) {
+        (self.clock)()
+    }
+
+    /// Update a file's last-access date on every read (off by default)
+    pub fn set_update_atime(&mut self, enable: bool) {
+        self.update_atime = enable;
+    }
+
+    pub fn update_atime(&self) -> bool {
+        self.update_atime
+    }
+
+    /// Write every FAT change to the device before a directory entry is made to reference it,
+    /// so a power loss can leak clusters but never leave a file size or first cluster pointing
+    /// past its chain (off by default). Each barrier is a full [`FileSystem::sync`], so this
+    /// makes cluster-allocating writes much slower.
+    pub fn set_ordered_writes(&mut self, enable: bool) {
+        self.ordered_writes = enable;
+    }
+
+    pub fn ordered_writes(&self) -> bool {
+        self.ordered_writes
+    }
+
+    // 目录项引用新分配的簇之前调用, 见 set_ordered_writes
+    pub(crate) fn write_barrier(&self) -> Result<(), DeviceErr> {
+        if self.ordered_writes {
+            self.sync()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Value written to terminate cluster chains, in `0x0FFFFFF8..=0x0FFFFFFF`. Defaults to
+    /// [`crate::EOC_MARK`] (0x0FFFFFFF, as mkfs.fat writes); every value in the range is still read as
+    /// the end of a chain.
+    pub fn set_eoc_value(&mut self, value: u32) {
+        assert!(
+            (END_OF_CLUSTER..=CLUSTER_MASK).contains(&value),
+            "[fat32::set_eoc_value] {:#x} is not an end-of-chain value",
+            value
+        );
+        self.fat.write().set_eoc_value(value);
+    }
+
+    pub fn eoc_value(&self) -> u32 {
+        self.fat.read().eoc_value()
+    }
+
+    /// Install a [`FsTracer`] to receive allocation and block I/O events (see [`NoopTracer`])
+    pub fn set_tracer(&mut self, tracer: Arc<dyn FsTracer>) {
+        *self.tracer.write() = tracer;
+    }
+
+    /// Volume serial number, read from the boot sector on disk
+    pub fn volume_serial(&self) -> u32 {
+        get_block_cache(0, Arc::clone(&self.device))
+            .read()
+            .read(0, |bpb: &BIOSParameterBlock| bpb.volume_serial())
+    }
+
+    /// Overwrite the volume serial number in the boot sector. The copy returned by
+    /// [`FileSystem::bpb`] keeps the serial read at open/format time.
+    pub fn set_volume_serial(&self, id: u32) {
+        get_block_cache(0, Arc::clone(&self.device))
+            .write()
+            .modify(0, |bpb: &mut BIOSParameterBlock| bpb.bpb32.bs_vol_id = id);
+    }
+
+    /// Whether the volume should be checked before it is trusted: it was not cleanly unmounted
+    /// or the last driver hit a disk error, according to FAT[1] at open time.
+    pub fn needs_check(&self) -> bool {
+        self.was_dirty || self.had_io_errors
+    }
+
+    /// The clean-shutdown bit was clear at open time
+    pub fn was_dirty(&self) -> bool {
+        self.was_dirty
+    }
+
+    /// The hard-error bit was clear at open time
+    pub fn had_io_errors(&self) -> bool {
+        self.had_io_errors
+    }
+
+    /// Set (`dirty`) or clear the "not cleanly unmounted" state in FAT[1] of every FAT.
+    ///
+    /// Opening read-write marks the volume dirty and dropping the file system marks it clean
+    /// again, so a volume that was never unmounted reports [`FileSystem::needs_check`] on the
+    /// next open. Fails with `ReadOnly` on a read-only file system.
+    pub fn set_volume_dirty(&self, dirty: bool) -> Result<(), FsError> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        self.update_volume_flags(|value| {
+            if dirty {
+                value & !CLN_SHUT_BIT_MASK_FAT32
+            } else {
+                value | CLN_SHUT_BIT_MASK_FAT32
+            }
+        })
+    }
+
+    // 修改 FAT[1] 中的卷状态标记并写回每个 FAT
+    fn update_volume_flags(&self, f: impl FnOnce(u32) -> u32) -> Result<(), FsError> {
+        let fat = self.fat.read();
+        let (block_id, offset_in_block) = fat.cluster_id_pos(1);
+        let value = try_get_block_cache(block_id, Arc::clone(&self.device))?
+            .read()
+            .read(offset_in_block, |&value: &u32| value);
+        fat.set_next_cluster(1, f(value));
+        // 立即写回, 保证异常关机后磁盘上的标记是准确的
+        for index in 0..self.bpb.fat_cnt() {
+            let block_id = (self.bpb.fat_offset(index) + 4) / BLOCK_SIZE;
+            get_block_cache(block_id, Arc::clone(&self.device))
+                .write()
+                .sync()?;
+        }
+        Ok(())
+    }
+
+    // 先写回全部数据, 成功后才将卷标记为正常卸载, 以免写回失败或断电后卷被误认为是干净的.
+    // 写回失败时卷保持 dirty 标记, 下次挂载时会重新统计空闲簇
+    fn sync_and_mark_clean(&self) -> Result<(), FsError> {
+        self.sync()?;
+        self.set_volume_dirty(false)
+    }
+
+    /// Mark the file system read-only. Currently this guards [`FileSystem::write_sector`],
+    /// [`FileSystem::write_boot_code`] and the volume dirty state.
+    ///
+    /// Like a remount, switching a read-write file system to read-only flushes it and marks the
+    /// volume clean, and switching back marks it dirty again.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        if read_only == self.read_only {
+            return;
+        }
+        // 与 drop 一致, 这里无法返回错误; 写回失败时卷保持原来的标记
+        if read_only {
+            let _ = self.sync_and_mark_clean();
+        }
+        self.read_only = read_only;
+        if !read_only {
+            let _ = self.set_volume_dirty(true);
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Read block `block_id` through the block cache, so the result reflects pending
+    /// file system writes
+    pub fn read_sector(
+        &self,
+        block_id: usize,
+        buf: &mut [u8; BLOCK_SIZE],
+    ) -> Result<(), DeviceErr> {
+        try_get_block_cache(block_id, Arc::clone(&self.device))?
+            .read()
+            .read(0, |block: &[u8; BLOCK_SIZE]| buf.copy_from_slice(block));
+        Ok(())
+    }
+
+    /// Overwrite block `block_id` through the block cache. Nothing stops this from corrupting
+    /// the file system; it is meant for low-level tooling and recovery.
+    pub fn write_sector(&self, block_id: usize, buf: &[u8; BLOCK_SIZE]) -> Result<(), FsError> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        try_get_block_cache(block_id, Arc::clone(&self.device))?
+            .write()
+            .modify(0, |block: &mut [u8; BLOCK_SIZE]| block.copy_from_slice(buf));
+        Ok(())
+    }
+
+    /// The boot code area of the boot sector: the [`BOOT_CODE_LEN`] bytes between the end of
+    /// the BPB (offset [`BOOT_CODE_OFFSET`]) and the 0xAA55 signature
+    pub fn read_boot_code(&self) -> [u8; BOOT_CODE_LEN] {
+        let mut code = [0u8; BOOT_CODE_LEN];
+        get_block_cache(0, Arc::clone(&self.device))
+            .read()
+            .read(0, |block: &[u8; BLOCK_SIZE]| {
+                code.copy_from_slice(&block[BOOT_CODE_OFFSET..BOOT_CODE_OFFSET + BOOT_CODE_LEN])
+            });
+        code
+    }
+
+    /// Whether sector 0 ends with the 0xAA55 boot signature (bytes 510-511)
+    pub fn has_valid_boot_signature(&self) -> bool {
+        try_get_block_cache(0, Arc::clone(&self.device)).is_ok_and(|cache| {
+            cache.read().read(BOOT_SIGNATURE_OFFSET, |sig: &[u8; 2]| {
+                *sig == BOOT_SIGNATURE
+            })
+        })
+    }
+
+    /// Install boot code (e.g. a bootloader stub) in the boot sector, zero-filling the rest of
+    /// the area. The BPB and the 0xAA55 signature are left untouched.
+    pub fn write_boot_code(&self, code: &[u8]) -> Result<(), FsError> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if code.len() > BOOT_CODE_LEN {
+            return Err(FsError::BootCodeTooLong(code.len()));
+        }
+        try_get_block_cache(0, Arc::clone(&self.device))?
+            .write()
+            .modify(0, |block: &mut [u8; BLOCK_SIZE]| {
+                let area = &mut block[BOOT_CODE_OFFSET..BOOT_CODE_OFFSET + BOOT_CODE_LEN];
+                area[..code.len()].copy_from_slice(code);
+                area[code.len()..].fill(0);
+            });
+        Ok(())
+    }
+
+    /// Id of this file system's blocks in the global block cache, unique per create/open
+    pub fn cache_id(&self) -> u64 {
+        self.device.cache_id()
+    }
+
+    /// A copy of the boot sector's BIOS parameter block
+    pub fn bpb(&self) -> BIOSParameterBlock {
+        self.bpb
+    }
+
     pub fn free_cluster_cnt(&self) -> usize {
         *self.free_cluster_cnt.read()
     }
 
+    /// Update the in-memory free cluster count.
+    ///
+    /// FSInfo is not rewritten on every allocation; the count reaches disk on
+    /// [`FileSystem::persist_fsinfo`] (also called by `sync`). In between, the on-disk free count
+    /// is the "unknown" sentinel, so after an unclean shutdown it is recomputed from the FAT on
+    /// the next open. If the sentinel cannot be written, the hard-error bit in FAT[1] is cleared
+    /// instead, which also makes the next open recompute the count.
     pub fn set_free_clusters(&self, cnt: usize) {
+        *self.free_cluster_cnt.write() = cnt;
+        let mut dirty = self.fsinfo_dirty.write();
+        if !*dirty {
+            *dirty = true;
+            // 立即写回哨兵值, 保证异常关机后能发现计数已失效
+            let fsinfo = get_block_cache(self.bpb.fat_info_sector(), Arc::clone(&self.device));
+            let mut fsinfo = fsinfo.write();
+            fsinfo.modify(0, |fsinfo: &mut FSInfo| {
+                fsinfo.set_free_clusters(FSINFO_UNKNOWN)
+            });
+            let synced = fsinfo.sync().is_ok();
+            drop(fsinfo);
+            if !synced {
+                // 哨兵值未写入磁盘: 下次修改计数时重试, 并清除 FAT[1] 的硬错误位,
+                // 使下次挂载时重新统计空闲簇 (见 had_io_errors)
+                *dirty = false;
+                let _ = self.update_volume_flags(|value| value & !HRD_ERR_BIT_MASK_FAT32);
+            }
+        }
+    }
+
+    /// Compare the stored free cluster count with the number of free entries in the FAT,
+    /// returning `(stored, actual)`. With `fix`, a differing stored count is replaced by the
+    /// actual one (written to FSInfo on the next `sync`). Much cheaper than a full check, it only
+    /// scans the FAT.
+    pub fn reconcile_free_count(&self, fix: bool) -> (usize, usize) {
+        let stored = self.free_cluster_cnt();
+        let actual = self
+            .fat
+            .read()
+            .count_free_clusters(self.bpb.data_cluster_cnt());
+        if fix && stored != actual {
+            self.set_free_clusters(actual);
+        }
+        (stored, actual)
+    }
+
+    /// Write the in-memory free cluster count and next free hint to FSInfo
+    pub fn persist_fsinfo(&self) {
+        let mut dirty = self.fsinfo_dirty.write();
+        let cnt = self.free_cluster_cnt();
+        let next_free = self.next_free_hint();
         get_block_cache(self.bpb.fat_info_sector(), Arc::clone(&self.device))
             .write()
             .modify(0, |fsinfo: &mut FSInfo| {
-                fsinfo.set_free_clusters(cnt as u32)
+                fsinfo.set_free_clusters(cnt as u32);
+                fsinfo.set_next_free_cluster(next_free);
             });
-        *self.free_cluster_cnt.write() = cnt;
+        *dirty = false;
+    }
+
+    /// Persist FSInfo and write every dirty block cache back to the device.
+    pub fn sync(&self) -> Result<(), DeviceErr> {
+        self.persist_fsinfo();
+        sync_all()
     }
 
     pub fn first_sector_of_cluster(&self, cluster: u32) -> usize {
@@ -64,19 +565,90 @@ impl FileSystem {
         self.bpb.offset(cluster)
     }
 
+    /// First block id (in units of `BLOCK_SIZE`) of the data cluster `cluster`.
+    pub fn cluster_to_block(&self, cluster: u32) -> usize {
+        self.bpb.offset(cluster) / BLOCK_SIZE
+    }
+
+    /// All block ids covered by the data cluster `cluster`.
+    pub fn cluster_blocks(&self, cluster: u32) -> Range<usize> {
+        let start_block_id = self.cluster_to_block(cluster);
+        start_block_id..start_block_id + self.cluster_size() / BLOCK_SIZE
+    }
+
     pub fn root_sector_id(&self) -> usize {
         self.first_data_sector()
     }
 
+    /// Format `device` as a FAT32 volume of [`BLOCK_NUM`] sectors.
+    ///
+    /// FAT32 requires at least 65525 clusters, so the default volume has one sector per cluster;
+    /// use [`FileSystem::create_with`] for a larger volume or larger clusters. Volumes formatted
+    /// before this check (0x4000 sectors, 8 sectors per cluster) have too few clusters and are
+    /// rejected by [`FileSystem::try_open`] as FAT12.
     #[allow(unused)]
     pub fn create(device: Arc<dyn BlockDevice>) -> Arc<RwLock<Self>> {
+        Self::create_with(device, FormatOptions::default())
+    }
+
+    /// Format `device` like [`FileSystem::create`], with the given [`FormatOptions`]
+    pub fn create_with(device: Arc<dyn BlockDevice>, options: FormatOptions) -> Arc<RwLock<Self>> {
+        let (device, tracer) = TracedDevice::wrap(device);
+        let clock = options.clock.unwrap_or(fat_epoch_clock);
+        let num_fats = options.num_fats.unwrap_or(2);
+        assert!(
+            num_fats == 1 || num_fats == 2,
+            "[fat32::create] num_fats must be 1 or 2, got {}",
+            num_fats
+        );
+        let rsvd_sec_cnt = options.reserved_sectors.unwrap_or(32);
+        assert!(
+            rsvd_sec_cnt >= 7,
+            "[fat32::create] reserved_sectors must be at least 7, got {}",
+            rsvd_sec_cnt
+        );
+        // 与常见格式化工具类似, 默认由格式化时的日期时间生成卷序列号
+        let volume_serial = match options.volume_serial {
+            Some(serial) => serial,
+            None => {
+                let (date, time) = clock();
+                ((date as u32) << 16) | time as u32
+            }
+        };
+        let tot_sec32 = options.total_sectors.unwrap_or(BLOCK_NUM);
+        // 与 mkfs 相同的 FAT 大小估算 (FAT32 没有根目录区), 结果可能略大于实际所需
+        let fat_sz32 = |sec_per_clus: u8| {
+            let sectors = tot_sec32.saturating_sub(rsvd_sec_cnt as u32);
+            let per_fat_sector = (256 * sec_per_clus as u32 + num_fats as u32) / 2;
+            sectors.div_ceil(per_fat_sector)
+        };
+        let cluster_cnt = |sec_per_clus: u8| {
+            let meta = rsvd_sec_cnt as u32 + num_fats as u32 * fat_sz32(sec_per_clus);
+            (tot_sec32.saturating_sub(meta) / sec_per_clus as u32) as usize
+        };
+        // FAT 类型只由簇数决定, 默认取仍能满足 FAT32 簇数下限的最大簇
+        let sec_per_clus = match options.sectors_per_cluster {
+            Some(spc) => {
+                assert!(
+                    spc.is_power_of_two(),
+                    "[fat32::create] sectors_per_cluster must be a power of two, got {}",
+                    spc
+                );
+                spc
+            }
+            None => [8, 4, 2]
+                .into_iter()
+                .find(|&spc| cluster_cnt(spc) >= MAX_CLUSTER_FAT16)
+                .unwrap_or(1),
+        };
+        let fat_sz32 = fat_sz32(sec_per_clus);
         let basic_bpb = BasicBPB {
             bs_jmp_boot: [0xEB, 0x58, 0x90],
             bs_oem_name: *b"mk.fat32",
             byts_per_sec: BLOCK_SIZE as u16,
-            sec_per_clus: 8,
-            rsvd_sec_cnt: 32,
-            num_fats: 2,
+            sec_per_clus,
+            rsvd_sec_cnt,
+            num_fats,
             root_ent_cnt: 0,
             tot_sec16: 0,
             media: 0xF8,
@@ -84,10 +656,10 @@ impl FileSystem {
             sec_per_trk: 0,
             num_heads: 0,
             hidd_sec: 0,
-            tot_sec32: 0x4000 as u32,
+            tot_sec32,
         };
         let bpb32 = BPB32 {
-            fat_sz32: 64,
+            fat_sz32,
             ext_flags: 0,
             fs_ver: 0,
             root_clus: ROOT_DIR_CLUSTER,
@@ -97,11 +669,16 @@ impl FileSystem {
             bs_drv_num: 0x80,
             bs_reserved1: 0,
             bs_boot_sig: 0x29,
-            bs_vol_id: 0x12345678,
+            bs_vol_id: volume_serial,
             bs_vol_lab: *b"mkfs.fat32 ",
             bs_fil_sys_type: *b"FAT32   ",
         };
         let bpb = BIOSParameterBlock { basic_bpb, bpb32 };
+        assert!(
+            bpb.data_cluster_cnt() >= MAX_CLUSTER_FAT16,
+            "[fat32::create] too few clusters for FAT32: {}",
+            bpb.data_cluster_cnt()
+        );
         get_block_cache(0, Arc::clone(&device))
             .write()
             .modify(0, |b: &mut BIOSParameterBlock| *b = bpb);
@@ -110,21 +687,31 @@ impl FileSystem {
             lead_sig: 0x41615252,
             reserved1: [0u8; 480],
             struc_sig: 0x61417272,
-            free_count: BLOCK_NUM as u32 - 32 - 128 - 128,
+            // 根目录占用一个簇
+            free_count: bpb.data_cluster_cnt() as u32 - 1,
             nxt_free: 0xFFFFFFFF,
             reserved2: [0u8; 12],
             trail_sig: 0xAA550000,
         };
         let free_cluster_cnt = fsinfo.free_cluster_cnt() as usize;
+        get_block_cache(0, Arc::clone(&device))
+            .write()
+            .modify(BOOT_SIGNATURE_OFFSET, |sig: &mut [u8; 2]| {
+                *sig = BOOT_SIGNATURE
+            });
         get_block_cache(1, Arc::clone(&device))
             .write()
             .modify(0, |f: &mut FSInfo| *f = fsinfo);
 
-        let fat = FATManager::new(bpb.fat1_offset(), Arc::clone(&device));
+        let fat = FATManager::new(
+            bpb.active_fat_offset(),
+            bpb.mirror_fat_offsets(),
+            Arc::clone(&device),
+        );
 
         let root_dir_cluster = bpb.root_cluster();
         // Set root next cluster
-        fat.set_next_cluster(root_dir_cluster as u32, END_OF_CLUSTER);
+        fat.set_end_of_chain(root_dir_cluster as u32);
         let mut name_bytes = [0x20u8; 11];
         name_bytes[0] = ROOT;
         let root_dir_entry = ShortDirEntry::new_from_name_bytes(
@@ -133,35 +720,109 @@ impl FileSystem {
             VirtFileType::Dir,
         );
 
-        let fs = Arc::new(RwLock::new(Self {
-            device,
-            free_cluster_cnt: Arc::new(RwLock::new(free_cluster_cnt)),
-            bpb,
-            fat: Arc::new(RwLock::new(fat)),
-            root_dir_entry: Arc::new(RwLock::new(root_dir_entry)),
-        }));
+        let fs = Arc::new_cyclic(|self_ref| {
+            RwLock::new(Self {
+                device,
+                free_cluster_cnt: Arc::new(RwLock::new(free_cluster_cnt)),
+                bpb,
+                fat: Arc::new(RwLock::new(fat)),
+                root_dir_entry: Arc::new(RwLock::new(root_dir_entry)),
+                self_ref: self_ref.clone(),
+                fsinfo_dirty: RwLock::new(false),
+                clock,
+                update_atime: false,
+                read_only: false,
+                was_dirty: false,
+                had_io_errors: false,
+                ordered_writes: false,
+                tracer,
+                root: RwLock::new(Weak::new()),
+                file_locks: RwLock::new(BTreeMap::new()),
+            })
+        });
+        if let Err(e) = fs.read().set_volume_dirty(true) {
+            panic!("[fat32::create] {}", e);
+        }
 
         fs
     }
 
     pub fn open(device: Arc<dyn BlockDevice>) -> Arc<RwLock<Self>> {
-        let bpb = get_block_cache(0, Arc::clone(&device))
+        match Self::try_open(device) {
+            Ok(fs) => fs,
+            Err(e) => panic!("Error loading fat32! {}", e),
+        }
+    }
+
+    /// Like [`FileSystem::open`], but returns an error instead of panicking when the boot sector
+    /// or FSInfo cannot be read, or the volume is not FAT32.
+    pub fn try_open(device: Arc<dyn BlockDevice>) -> Result<Arc<RwLock<Self>>, FsError> {
+        Self::mount(device, false)
+    }
+
+    /// Like [`FileSystem::try_open`], but the file system starts read-only (see
+    /// [`FileSystem::set_read_only`]): neither mounting nor dropping it marks the volume dirty
+    /// or clean, so a volume that needs checking can be inspected without changing its state.
+    pub fn try_open_read_only(device: Arc<dyn BlockDevice>) -> Result<Arc<RwLock<Self>>, FsError> {
+        Self::mount(device, true)
+    }
+
+    fn mount(device: Arc<dyn BlockDevice>, read_only: bool) -> Result<Arc<RwLock<Self>>, FsError> {
+        let (device, tracer) = TracedDevice::wrap(device);
+        let bpb = try_get_block_cache(0, Arc::clone(&device))?
             .read()
             .read(0, |bpb: &BIOSParameterBlock| *bpb);
 
-        let free_cluster_cnt = get_block_cache(bpb.fat_info_sector(), Arc::clone(&device))
-            .read()
-            .read(0, |fsinfo: &FSInfo| {
-                assert!(
-                    fsinfo.check_signature(),
-                    "Error loading fat32! Illegal signature"
-                );
-                fsinfo.free_cluster_cnt() as usize
-            });
+        // 以下计算簇数时以二者为除数
+        if bpb.basic_bpb.byts_per_sec == 0 || bpb.basic_bpb.sec_per_clus == 0 {
+            return Err(FsError::InvalidBpb);
+        }
+        // 其余代码均假设 FAT32 布局 (4 字节 FAT 表项, 无固定根目录区)
+        let fat_type = bpb.fat_type();
+        if fat_type != FatType::FAT32 {
+            return Err(FsError::UnsupportedFatType(fat_type));
+        }
+
+        let (signature_ok, free_cluster_cnt, next_free) =
+            try_get_block_cache(bpb.fat_info_sector(), Arc::clone(&device))?
+                .read()
+                .read(0, |fsinfo: &FSInfo| {
+                    (
+                        fsinfo.check_signature(),
+                        fsinfo.free_cluster_cnt() as usize,
+                        fsinfo.next_free_cluster(),
+                    )
+                });
+        if !signature_ok {
+            return Err(FsError::InvalidFsInfo);
+        }
 
-        let fat = FATManager::open(bpb.fat1_offset(), Arc::clone(&device));
+        let mut fat = FATManager::open(
+            bpb.active_fat_offset(),
+            bpb.mirror_fat_offsets(),
+            Arc::clone(&device),
+        );
+        // nxt_free 只是提示: 为 0xFFFFFFFF (未知) 或不是有效的簇号时从簇 2 开始查找
+        let max_cluster = bpb.data_cluster_cnt() as u32 + 1;
+        if (2..=max_cluster).contains(&next_free) {
+            fat.set_next_free_hint(next_free);
+        }
         // let fat = FATManager::new(bpb.fat1_offset(), Arc::clone(&device));
 
+        // FAT[1] 的高位记录了卷的状态
+        let (block_id, offset_in_block) = fat.cluster_id_pos(1);
+        let volume_flags = try_get_block_cache(block_id, Arc::clone(&device))?
+            .read()
+            .read(offset_in_block, |&value: &u32| value);
+
+        // 计数未知 (上次未正常卸载), 超出范围, 或曾发生磁盘错误 (哨兵值可能未写入) 时, 扫描 FAT 重新统计
+        let had_io_errors = volume_flags & HRD_ERR_BIT_MASK_FAT32 == 0;
+        let free_cluster_cnt = if free_cluster_cnt > bpb.data_cluster_cnt() || had_io_errors {
+            fat.count_free_clusters(bpb.data_cluster_cnt())
+        } else {
+            free_cluster_cnt
+        };
+
         let root_dir_cluster = bpb.root_cluster();
         let mut name_bytes = [0x20u8; 11];
         name_bytes[0] = ROOT;
@@ -171,16 +832,67 @@ impl FileSystem {
             VirtFileType::Dir,
         );
 
-        Arc::new(RwLock::new(Self {
-            device,
-            free_cluster_cnt: Arc::new(RwLock::new(free_cluster_cnt)),
-            bpb,
-            fat: Arc::new(RwLock::new(fat)),
-            root_dir_entry: Arc::new(RwLock::new(root_dir_entry)),
-        }))
+        let fs = Arc::new_cyclic(|self_ref| {
+            RwLock::new(Self {
+                device,
+                free_cluster_cnt: Arc::new(RwLock::new(free_cluster_cnt)),
+                bpb,
+                fat: Arc::new(RwLock::new(fat)),
+                root_dir_entry: Arc::new(RwLock::new(root_dir_entry)),
+                self_ref: self_ref.clone(),
+                fsinfo_dirty: RwLock::new(false),
+                clock: fat_epoch_clock,
+                update_atime: false,
+                read_only,
+                was_dirty: volume_flags & CLN_SHUT_BIT_MASK_FAT32 == 0,
+                had_io_errors,
+                ordered_writes: false,
+                tracer,
+                root: RwLock::new(Weak::new()),
+                file_locks: RwLock::new(BTreeMap::new()),
+            })
+        });
+        // 挂载期间标记为未正常卸载, 卸载 (drop) 时清除
+        if !read_only {
+            fs.read().set_volume_dirty(true)?;
+        }
+        Ok(fs)
+    }
+
+    /// Like [`FileSystem::try_open`], but also fails with `MissingBootSignature` if sector 0
+    /// does not end with 0xAA55. [`FileSystem::open`] accepts such volumes since some tools
+    /// omit the signature.
+    pub fn try_open_strict(device: Arc<dyn BlockDevice>) -> Result<Arc<RwLock<Self>>, FsError> {
+        // 直接读取设备, 不让 0 号块以未包装的设备进入块缓存
+        let mut sector = [0u8; BLOCK_SIZE];
+        device.read_blocks(&mut sector, 0, 1)?;
+        if sector[BOOT_SIGNATURE_OFFSET..BOOT_SIGNATURE_OFFSET + 2] != BOOT_SIGNATURE {
+            return Err(FsError::MissingBootSignature);
+        }
+        Self::try_open(device)
+    }
+
+    /// Open the FAT32 file system in the `index`th entry of the MBR partition table on `device`.
+    ///
+    /// Fails with `PartitionNotFound` if sector 0 is not an MBR or that entry is not a FAT32
+    /// partition, and otherwise like [`FileSystem::try_open`].
+    /// Block ids of the returned file system are relative to the partition start.
+    pub fn open_partition(
+        device: Arc<dyn BlockDevice>,
+        index: usize,
+    ) -> Result<Arc<RwLock<Self>>, FsError> {
+        // 直接读取设备而不经过块缓存, 避免缓存中留下绝对编号的 0 号块
+        let mut sector = [0u8; BLOCK_SIZE];
+        device.read_blocks(&mut sector, 0, 1)?;
+        let part = MbrPartition::parse(&sector)
+            .and_then(|table| table.get(index).copied().flatten())
+            .filter(|part| part.is_fat32())
+            .ok_or(FsError::PartitionNotFound)?;
+        let part_device = OffsetDevice::new(device, part.start_lba as usize);
+        Self::try_open(Arc::new(part_device))
     }
 
-    fn clear_cluster(&self, cluster: u32) {
+    pub(crate) fn clear_cluster(&self, cluster: u32) {
         let block_id = self.first_sector_of_cluster(cluster);
         for i in 0..self.sector_pre_cluster() {
             get_block_cache(block_id + i, Arc::clone(&self.device))
@@ -193,21 +905,62 @@ impl FileSystem {
 
     // 成功返回第一个簇号, 失败返回None
     pub fn alloc_cluster(&self, num: usize, start_cluster: u32) -> Option<u32> {
+        self.alloc_cluster_with(num, start_cluster, true)
+    }
+
+    /// Like [`FileSystem::alloc_cluster`], but leaves the clusters' old contents in place.
+    /// Only for callers that will overwrite the whole clusters; directories must be zeroed.
+    pub fn alloc_cluster_no_clear(&self, num: usize, start_cluster: u32) -> Option<u32> {
+        self.alloc_cluster_with(num, start_cluster, false)
+    }
+
+    fn alloc_cluster_with(&self, num: usize, start_cluster: u32, clear: bool) -> Option<u32> {
         let free_cluster_cnt = self.free_cluster_cnt();
         if free_cluster_cnt < num {
             return None;
         }
 
-        let first_cluster_id = self.fat.write().blank_cluster(start_cluster);
+        let data_cluster_cnt = self.bpb.data_cluster_cnt();
+        // 新文件 (start_cluster 为 0) 没有可参考的位置, 从 nxt_free 提示开始查找
+        let first_cluster_id = if start_cluster < 2 {
+            let hint = self.next_free_hint();
+            self.fat
+                .write()
+                .blank_cluster_from(hint, data_cluster_cnt)?
+        } else {
+            self.fat
+                .write()
+                .blank_cluster_after(start_cluster, data_cluster_cnt)?
+        };
 
         assert!(first_cluster_id >= 2);
-        self.clear_cluster(first_cluster_id);
+        if clear {
+            self.clear_cluster(first_cluster_id);
+        }
 
         let mut curr_cluster_id = first_cluster_id;
         for _ in 1..num {
-            let cluster_id = self.fat.write().blank_cluster(curr_cluster_id);
+            let option = self
+                .fat
+                .write()
+                .blank_cluster_after(curr_cluster_id, data_cluster_cnt);
+            let cluster_id = match option {
+                Some(cluster_id) => cluster_id,
+                None => {
+                    // 空闲簇数与 FAT 不一致: 释放已经取得的簇, 当作空间不足
+                    self.fat.write().set_end_of_chain(curr_cluster_id);
+                    let taken = self.fat.read().get_all_cluster_id(first_cluster_id);
+                    let fat = self.fat.write();
+                    for cluster in taken {
+                        fat.set_next_cluster(cluster, FREE_CLUSTER);
+                    }
+                    return None;
+                }
+            };
             assert!(cluster_id >= 2);
-            self.clear_cluster(cluster_id);
+            if clear {
+                self.clear_cluster(cluster_id);
+            }
             self.fat
                 .write()
                 .set_next_cluster(curr_cluster_id, cluster_id);
@@ -216,15 +969,55 @@ impl FileSystem {
         }
 
         // self.clear_cluster(curr_cluster_id);
-        self.fat
-            .write()
-            .set_next_cluster(curr_cluster_id, END_OF_CLUSTER);
+        self.fat.write().set_end_of_chain(curr_cluster_id);
 
         self.set_free_clusters(free_cluster_cnt - num);
+        self.advance_free_hint(curr_cluster_id);
+        self.tracer.read().on_alloc(first_cluster_id, num);
 
         Some(first_cluster_id)
     }
 
+    // 下一次为新文件分配时从刚分配的最后一个簇之后开始查找, 卸载时写入 FSInfo 的 nxt_free
+    fn advance_free_hint(&self, last_cluster: u32) {
+        let max_cluster = self.bpb.data_cluster_cnt() as u32 + 1;
+        let hint = if last_cluster >= max_cluster {
+            2
+        } else {
+            last_cluster + 1
+        };
+        self.fat.write().set_next_free_hint(hint);
+    }
+
+    /// Cluster at which allocations for new files start looking for free clusters: the cluster
+    /// after the last one allocated, or at first the FSInfo "next free" hint if it was a valid
+    /// cluster number at open time, otherwise 2
+    pub fn next_free_hint(&self) -> u32 {
+        self.fat.read().next_free_hint()
+    }
+
+    /// Allocate `num` physically contiguous clusters linked as one chain, returning the first.
+    /// `None` if no free run is long enough.
+    pub fn alloc_contiguous(&self, num: usize) -> Option<u32> {
+        let free_cluster_cnt = self.free_cluster_cnt();
+        if num == 0 || free_cluster_cnt < num {
+            return None;
+        }
+        let mut fat = self.fat.write();
+        let start = fat.find_free_run(num, self.bpb.data_cluster_cnt())?;
+        fat.unrecycle_run(start, num);
+        for cluster in start..start + num as u32 - 1 {
+            fat.set_next_cluster(cluster, cluster + 1);
+        }
+        fat.set_end_of_chain(start + num as u32 - 1);
+        drop(fat);
+
+        self.set_free_clusters(free_cluster_cnt - num);
+        self.advance_free_hint(start + num as u32 - 1);
+        self.tracer.read().on_alloc(start, num);
+        Some(start)
+    }
+
     pub fn dealloc_cluster(&self, clusters: Vec<u32>) {
         let num = clusters.len();
         if num == 0 {
@@ -236,6 +1029,16 @@ impl FileSystem {
             self.fat.write().recycle(clusters[i]);
         }
         self.set_free_clusters(free_cluster_cnt + num);
+        self.tracer.read().on_free(&clusters);
+    }
+
+    /// Like [`FileSystem::dealloc_cluster`], but zeroes every freed cluster first so its old
+    /// data can no longer be read from the device.
+    pub fn dealloc_cluster_zeroed(&self, clusters: Vec<u32>) {
+        for &cluster in clusters.iter() {
+            self.clear_cluster(cluster);
+        }
+        self.dealloc_cluster(clusters);
     }
 
     pub fn count_needed_clusters(&self, new_size: usize, start_cluster: u32) -> usize {
@@ -255,11 +1058,1282 @@ impl FileSystem {
         }
     }
 
+    /// Iterate over the cluster ids of the chain starting at `first_cluster`, in order.
+    ///
+    /// Yields nothing for an empty file (`first_cluster` 0). The walk is bounded by the number
+    /// of data clusters on the volume, so a looping chain terminates.
+    pub fn cluster_chain(&self, first_cluster: u32) -> ClusterIter {
+        ClusterIter::new(
+            Arc::clone(&self.fat),
+            first_cluster,
+            self.bpb.data_cluster_cnt(),
+        )
+    }
+
+    /// Compare every FAT1 entry with the corresponding FAT2 entry.
+    ///
+    /// Returns the cluster numbers whose entries differ (ignoring the reserved high 4 bits).
+    /// A volume with a single FAT always passes.
+    pub fn verify_fat_mirror(&self) -> Result<(), Vec<u32>> {
+        if self.bpb.fat_cnt() < 2 {
+            return Ok(());
+        }
+        let fat1_offset = self.bpb.fat_offset(0);
+        let fat2_offset = self.bpb.fat_offset(1);
+        let mut diverging = Vec::new();
+        for cluster in 0..(self.bpb.data_cluster_cnt() + 2) as u32 {
+            let fat1 = self.read_fat_entry(fat1_offset, cluster) & CLUSTER_MASK;
+            let fat2 = self.read_fat_entry(fat2_offset, cluster) & CLUSTER_MASK;
+            if fat1 != fat2 {
+                diverging.push(cluster);
+            }
+        }
+        if diverging.is_empty() {
+            Ok(())
+        } else {
+            Err(diverging)
+        }
+    }
+
+    /// Overwrite the other FAT copy with `from` wherever they differ, returning the number of
+    /// entries rewritten.
+    ///
+    /// The free cluster count in FSInfo is not recomputed.
+    pub fn repair_fat_mirror(&self, from: FatCopy) -> usize {
+        let diverging = match self.verify_fat_mirror() {
+            Ok(()) => return 0,
+            Err(diverging) => diverging,
+        };
+        let src_offset = self.bpb.fat_offset(from.index());
+        let dst_offset = self.bpb.fat_offset(1 - from.index());
+        for cluster in diverging.iter() {
+            let value = self.read_fat_entry(src_offset, *cluster);
+            let offset = *cluster as usize * 4 + dst_offset;
+            get_block_cache(offset / BLOCK_SIZE, Arc::clone(&self.device))
+                .write()
+                .modify(offset % BLOCK_SIZE, |entry: &mut u32| {
+                    *entry = value;
+                });
+        }
+        // 活动 FAT 可能被改写, 回收队列中的簇不一定仍然空闲
+        self.fat.write().clear_recycled();
+        diverging.len()
+    }
+
+    fn read_fat_entry(&self, fat_offset: usize, cluster: u32) -> u32 {
+        let offset = cluster as usize * 4 + fat_offset;
+        get_block_cache(offset / BLOCK_SIZE, Arc::clone(&self.device))
+            .read()
+            .read(offset % BLOCK_SIZE, |entry: &u32| *entry)
+    }
+
+    /// The chain starting at `first_cluster` as runs of consecutive clusters, `(start, count)` in
+    /// chain order. The chain length is the sum of the counts; a single run means the file is
+    /// not fragmented.
+    pub fn chain_extents(&self, first_cluster: u32) -> Vec<(u32, u32)> {
+        let mut extents: Vec<(u32, u32)> = Vec::new();
+        for cluster in self.cluster_chain(first_cluster) {
+            match extents.last_mut() {
+                Some((start, count)) if *start + *count == cluster => *count += 1,
+                _ => extents.push((cluster, 1)),
+            }
+        }
+        extents
+    }
+
+    /// Runs of free clusters as `(start, count)`, showing how fragmented the free space is.
+    /// The FAT is scanned lazily, so this is cheap to stop early on large volumes.
+    pub fn free_extents(&self) -> FreeExtents {
+        FreeExtents::new(Arc::clone(&self.fat), self.bpb.data_cluster_cnt())
+    }
+
+    /// Length of the chain starting at `first_cluster`, or `None` unless every link is a valid
+    /// data cluster and the chain ends in EOC (a loop, free or bad entry fails the check).
+    pub fn chain_len_checked(&self, first_cluster: u32) -> Option<usize> {
+        let max_cluster = self.bpb.data_cluster_cnt() as u32 + 1;
+        let fat = self.fat.read();
+        let mut cluster = first_cluster;
+        for len in 1..=self.bpb.data_cluster_cnt() {
+            if !(2..=max_cluster).contains(&cluster) {
+                return None;
+            }
+            let (block_id, offset_in_block) = fat.cluster_id_pos(cluster);
+            let next = get_block_cache(block_id, Arc::clone(&self.device))
+                .read()
+                .read(offset_in_block, |&next: &u32| next)
+                & CLUSTER_MASK;
+            if next >= END_OF_CLUSTER {
+                return Some(len);
+            }
+            cluster = next;
+        }
+        None
+    }
+
+    /// Chains of clusters that are allocated in the FAT but reachable from no directory entry
+    /// (e.g. leaked by a crash between allocating clusters and writing the entry), each in chain
+    /// order.
+    ///
+    /// Clusters of a removed file that is still open are unreachable too, so this is meant to be
+    /// run when no such handles exist.
+    pub fn find_lost_chains(&self) -> Vec<Vec<u32>> {
+        // 从根目录出发可达的簇
+        let mut referenced: BTreeSet<u32> =
+            self.cluster_chain(self.bpb.root_cluster() as u32).collect();
+        for (_, file) in self.root().walk() {
+            referenced.extend(self.cluster_chain(file.first_cluster() as u32));
+        }
+        // 已分配但不可达的簇 -> 其 FAT 表项
+        let fat_offset = self.bpb.active_fat_offset();
+        let mut lost: BTreeMap<u32, u32> = BTreeMap::new();
+        for cluster in 2..(self.bpb.data_cluster_cnt() + 2) as u32 {
+            let next = self.read_fat_entry(fat_offset, cluster) & CLUSTER_MASK;
+            if next != FREE_CLUSTER && next != BAD_CLUSTER && !referenced.contains(&cluster) {
+                lost.insert(cluster, next);
+            }
+        }
+        // 先从链头 (不被其他丢失簇指向的簇) 出发, 余下的簇属于成环的链
+        let pointed: BTreeSet<u32> = lost.values().copied().collect();
+        let starts: Vec<u32> = lost
+            .keys()
+            .copied()
+            .filter(|cluster| !pointed.contains(cluster))
+            .chain(lost.keys().copied())
+            .collect();
+        let mut seen = BTreeSet::new();
+        let mut chains = Vec::new();
+        for start in starts {
+            let mut chain = Vec::new();
+            let mut cluster = start;
+            while lost.contains_key(&cluster) && seen.insert(cluster) {
+                chain.push(cluster);
+                cluster = lost[&cluster];
+            }
+            if !chain.is_empty() {
+                chains.push(chain);
+            }
+        }
+        chains
+    }
+
+    /// Free every chain found by [`FileSystem::find_lost_chains`], returning the number of
+    /// clusters reclaimed. The free cluster count is updated accordingly.
+    pub fn reclaim_lost(&self) -> usize {
+        let clusters: Vec<u32> = self.find_lost_chains().into_iter().flatten().collect();
+        let reclaimed = clusters.len();
+        self.dealloc_cluster(clusters);
+        reclaimed
+    }
+
+    /// Advisory lock shared by every handle of the file with inode `inode` (see
+    /// [`VirtFile::inode`]). File reads take the read side and mutations the write side, so
+    /// writes to one file are serialized without locking the whole file system.
+    pub(crate) fn file_lock(&self, inode: u64) -> Arc<RwLock<()>> {
+        // 锁通常已存在, 查找只需读锁
+        if let Some(lock) = self.file_locks.read().get(&inode).and_then(Weak::upgrade) {
+            return lock;
+        }
+        let mut locks = self.file_locks.write();
+        // 获取写锁期间可能已被其他线程插入
+        if let Some(lock) = locks.get(&inode).and_then(Weak::upgrade) {
+            return lock;
+        }
+        // 顺便移除已无人持有的锁
+        locks.retain(|_, lock| lock.strong_count() > 0);
+        let lock = Arc::new(RwLock::new(()));
+        locks.insert(inode, Arc::downgrade(&lock));
+        lock
+    }
+
     pub fn root_dir_entry(&self) -> Arc<RwLock<ShortDirEntry>> {
         self.root_dir_entry.clone()
     }
 
+    /// The root directory. The handle is cached: while one returned `Arc` is alive, later
+    /// calls return the same `Arc` instead of building a new `VirtFile`.
+    pub fn root(&self) -> Arc<VirtFile> {
+        if let Some(root) = self.root.read().upgrade() {
+            return root;
+        }
+        let mut cached = self.root.write();
+        // 获取写锁期间可能已被其他线程构造
+        if let Some(root) = cached.upgrade() {
+            return root;
+        }
+        let root = Arc::new(vfs::root(self.self_ref.upgrade().unwrap()));
+        *cached = Arc::downgrade(&root);
+        root
+    }
+
+    /// The volume label stored in the root directory's ATTR_VOLUME_ID entry, if there is one
+    /// (see [`BIOSParameterBlock::volume_label_bpb`] for the copy in the boot sector)
+    pub fn volume_label(&self) -> Option<String> {
+        self.root().volume_label()
+    }
+
+    /// The 8.3 alias `long_name` would get if created in `dir` now (e.g. `LONGNA~1.TXT`),
+    /// taking the directory's existing short names into account. Nothing is created.
+    pub fn preview_short_name(&self, dir: &VirtFile, long_name: &str) -> Result<String, DirError> {
+        dir.preview_short_name(long_name)
+    }
+
     pub fn device(&self) -> Arc<dyn BlockDevice> {
         Arc::clone(&self.device)
     }
+
+    /// Open a file or directory directly by its first cluster, without walking from root.
+    ///
+    /// The first cluster can be used as a stable inode-like handle: reads, writes and directory
+    /// listing work on the returned [`VirtFile`] as usual. `file_size` is the size to read the
+    /// file with (e.g. from the caller's own copy of the entry); it is ignored for directories.
+    ///
+    /// Fails with `BadClusterChain` unless `first_cluster` starts a valid chain ending in EOC
+    /// that can hold `file_size` bytes.
+    ///
+    /// Limitation: the handle is not bound to any directory entry (`sde_pos`/`lde_pos` are unknown),
+    /// so its short directory entry only lives in memory and size changes are not persisted.
+    /// Operations that need the on-disk entry (rename, `clear`, `truncate_to_zero`, defragment)
+    /// fail with `Unsupported` on it.
+    pub fn file_from_cluster(
+        &self,
+        first_cluster: u32,
+        is_dir: bool,
+        file_size: u32,
+    ) -> Result<VirtFile, FileError> {
+        let chain_len = self
+            .chain_len_checked(first_cluster)
+            .ok_or(FileError::BadClusterChain)?;
+        if !is_dir && file_size as usize > chain_len * self.cluster_size() {
+            return Err(FileError::BadClusterChain);
+        }
+        let fs = self.self_ref.upgrade().unwrap();
+        let file_type = if is_dir {
+            VirtFileType::Dir
+        } else {
+            VirtFileType::File
+        };
+
+        let cluster_chain = Arc::new(RwLock::new(ClusterChain::new(
+            first_cluster,
+            Arc::clone(&self.device),
+            self.bpb.active_fat_offset(),
+        )));
+
+        // 无目录项, 在内存中构造一个短目录项
+        let mut name_bytes = [0x20u8; 11];
+        name_bytes[0] = b'?';
+        let mut sde = ShortDirEntry::new_from_name_bytes(first_cluster, &name_bytes, file_type);
+        // fat32 规定目录文件大小为 0
+        if !is_dir {
+            sde.set_file_size(file_size);
+        }
+
+        let mut file = VirtFile::new(
+            String::new(),
+            DirEntryPos {
+                cluster: DETACHED_DIR_ENTRY_CLUSTER,
+                offset_in_cluster: 0,
+            },
+            Vec::new(),
+            fs,
+            cluster_chain,
+            file_type,
+        );
+        file.detached_sde = Some(Arc::new(RwLock::new(sde)));
+        Ok(file)
+    }
+}
+
+/// Dropping the last `Arc` of a file system flushes it and evicts its blocks from the block
+/// cache, so `Drop` may perform device I/O.
+///
+/// 块缓存目前是全局的, 这里会写回所有块缓存; drop 中无法返回错误, 需要处理错误时先调用 sync
+impl Drop for FileSystem {
+    fn drop(&mut self) {
+        // 只读时不写入设备
+        if !self.read_only {
+            let _ = self.sync_and_mark_clean();
+        }
+        // 卸载后移除本文件系统的块缓存, 避免旧块留在缓存中
+        let _ = evict_cache(self.cache_id());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::{FaultDevice, MemBlockDevice},
+        file::{File, FileError, WriteType},
+        read_le_u32,
+        test_util::{pattern, read_all, CacheGuard, TestFs},
+        Dir, FSINFO_UNKNOWN,
+    };
+
+    #[test]
+    fn open_reports_fsinfo_read_error() {
+        let t = TestFs::new();
+        let fsinfo_sector = t.fs.read().bpb().fat_info_sector();
+        drop(t.fs);
+
+        let fault = Arc::new(FaultDevice::new(t.device.clone()));
+        fault.fail_block(fsinfo_sector);
+        assert_eq!(
+            FileSystem::try_open(fault).err(),
+            Some(FsError::Device(DeviceErr::ReadError))
+        );
+    }
+
+    #[test]
+    fn open_rejects_bad_fsinfo_signature() {
+        let t = TestFs::new();
+        let fsinfo_sector = t.fs.read().bpb().fat_info_sector();
+        drop(t.fs);
+
+        // 破坏 FSI_LeadSig
+        let mut block = [0u8; BLOCK_SIZE];
+        t.device
+            .read_blocks(&mut block, fsinfo_sector * BLOCK_SIZE, 1)
+            .unwrap();
+        block[0] ^= 0xFF;
+        t.device
+            .write_blocks(&block, fsinfo_sector * BLOCK_SIZE, 1)
+            .unwrap();
+        assert_eq!(
+            FileSystem::try_open(t.device.clone()).err(),
+            Some(FsError::InvalidFsInfo)
+        );
+    }
+
+    #[test]
+    fn write_on_full_volume_returns_no_space() {
+        let t = TestFs::new();
+        let file = t.create_file("full.bin", b"hello");
+
+        // 占用剩余的全部空闲簇
+        let free = t.free_cluster_cnt();
+        assert!(t.fs.read().alloc_cluster_no_clear(free, 0).is_some());
+        assert_eq!(t.free_cluster_cnt(), 0);
+
+        let data = pattern(2 * t.cluster_size(), 7);
+        assert_eq!(file.write_at(file.file_size(), &data), 0);
+        assert_eq!(
+            file.write(&data, WriteType::Append),
+            Err(FileError::NoSpace)
+        );
+        // 只写入了首簇能容纳的部分
+        assert_eq!(file.file_size(), t.cluster_size());
+    }
+
+    // 卸载后把 FSInfo 的 nxt_free 改为 hint 再挂载
+    fn reopen_with_hint(t: TestFs, hint: u32) -> TestFs {
+        let fsinfo_sector = t.fs.read().bpb().fat_info_sector();
+        drop(t.fs);
+        let mut block = [0u8; BLOCK_SIZE];
+        t.device
+            .read_blocks(&mut block, fsinfo_sector * BLOCK_SIZE, 1)
+            .unwrap();
+        block[492..496].copy_from_slice(&hint.to_le_bytes());
+        t.device
+            .write_blocks(&block, fsinfo_sector * BLOCK_SIZE, 1)
+            .unwrap();
+        TestFs::open(t.device)
+    }
+
+    #[test]
+    fn new_file_starts_at_valid_hint() {
+        let t = reopen_with_hint(TestFs::new(), 100);
+        assert_eq!(t.fs.read().next_free_hint(), 100);
+        let file = t.create_file("hint.bin", &pattern(2 * t.cluster_size(), 8));
+        assert_eq!(file.first_cluster(), 100);
+        // 提示移到刚分配的簇之后
+        assert_eq!(t.fs.read().next_free_hint(), 102);
+    }
+
+    #[test]
+    fn unknown_or_out_of_range_hint_starts_at_cluster_2() {
+        for hint in [FSINFO_UNKNOWN, 0, 1, 0x0FFF_FFF0] {
+            let t = reopen_with_hint(TestFs::new(), hint);
+            assert_eq!(t.fs.read().next_free_hint(), 2);
+            let file = t.create_file("a.bin", b"data");
+            // 簇 2 是根目录
+            assert_eq!(file.first_cluster(), 3);
+        }
+    }
+
+    #[test]
+    fn hint_is_written_back_to_fsinfo() {
+        let t = TestFs::new();
+        let file = t.create_file("a.bin", &pattern(3 * t.cluster_size(), 9));
+        let last =
+            t.fs.read()
+                .cluster_chain(file.first_cluster() as u32)
+                .last();
+        drop(file);
+        let fsinfo_sector = t.fs.read().bpb().fat_info_sector();
+        let t = t.reopen();
+        assert_eq!(t.fs.read().next_free_hint(), last.unwrap() + 1);
+
+        drop(t.fs);
+        let mut block = [0u8; BLOCK_SIZE];
+        t.device
+            .read_blocks(&mut block, fsinfo_sector * BLOCK_SIZE, 1)
+            .unwrap();
+        assert_eq!(&block[492..496], &(last.unwrap() + 1).to_le_bytes());
+    }
+
+    #[test]
+    fn hint_at_last_cluster_wraps_to_start() {
+        let t = TestFs::new();
+        let max_cluster = t.fs.read().bpb().data_cluster_cnt() as u32 + 1;
+        let t = reopen_with_hint(t, max_cluster);
+        let file = t.create_file("tail.bin", &pattern(3 * t.cluster_size(), 10));
+        let clusters: Vec<u32> =
+            t.fs.read()
+                .cluster_chain(file.first_cluster() as u32)
+                .collect();
+        assert_eq!(clusters, [max_cluster, 3, 4]);
+        assert_eq!(t.fs.read().next_free_hint(), 5);
+    }
+
+    #[test]
+    fn out_of_sync_free_count_fails_allocation_cleanly() {
+        let t = TestFs::new();
+        let fs = t.fs.read();
+        // 只留下一个空闲簇, 但声称有两个
+        let free = fs.free_cluster_cnt();
+        assert!(fs.alloc_cluster_no_clear(free - 1, 0).is_some());
+        fs.set_free_clusters(2);
+        assert_eq!(fs.alloc_cluster(2, 0), None);
+        // 已取得的簇被释放, 没有形成指向自身的簇链
+        assert_eq!(fs.reconcile_free_count(true), (2, 1));
+        assert!(fs.alloc_cluster(1, 0).is_some());
+    }
+
+    #[test]
+    fn file_from_cluster_reads_with_given_size() {
+        let t = TestFs::new();
+        let data = pattern(3 * t.cluster_size() + 100, 11);
+        let first_cluster = t.create_file("inode.bin", &data).first_cluster() as u32;
+
+        let file =
+            t.fs.read()
+                .file_from_cluster(first_cluster, false, data.len() as u32)
+                .unwrap();
+        assert_eq!(file.file_size(), data.len());
+        assert_eq!(read_all(&file), data);
+    }
+
+    #[test]
+    fn file_from_cluster_rejects_bad_chains() {
+        let t = TestFs::new();
+        let first_cluster = t.create_file("a.bin", b"abc").first_cluster() as u32;
+        let max_cluster = t.fs.read().bpb().data_cluster_cnt() as u32 + 1;
+        let fs = t.fs.read();
+        // 保留簇号, 超出数据区, 空闲簇
+        for cluster in [0, 1, max_cluster + 1, first_cluster + 1] {
+            assert_eq!(
+                fs.file_from_cluster(cluster, false, 0).err(),
+                Some(FileError::BadClusterChain)
+            );
+        }
+        // 簇链容纳不下 file_size
+        let size = t.cluster_size() as u32 + 1;
+        assert_eq!(
+            fs.file_from_cluster(first_cluster, false, size).err(),
+            Some(FileError::BadClusterChain)
+        );
+    }
+
+    #[test]
+    fn detached_handle_cannot_be_cleared_or_truncated() {
+        let t = TestFs::new();
+        let data = pattern(100, 12);
+        let first_cluster = t.create_file("keep.bin", &data).first_cluster() as u32;
+        let free = t.free_cluster_cnt();
+
+        let file =
+            t.fs.read()
+                .file_from_cluster(first_cluster, false, data.len() as u32)
+                .unwrap();
+        assert_eq!(file.clear(), Err(FileError::Unsupported));
+        assert_eq!(file.truncate_to_zero(), Err(FileError::Unsupported));
+        assert_eq!(t.free_cluster_cnt(), free);
+
+        let file = t.root().find(vec!["keep.bin"]).unwrap();
+        assert_eq!(read_all(&file), data);
+    }
+
+    // 设备上 FAT1 中 FAT[1] 的正常卸载位
+    fn clean_bit_on_disk(device: &dyn BlockDevice, bpb: &BIOSParameterBlock) -> bool {
+        let offset = bpb.fat_offset(0) + 4;
+        let mut block = [0u8; BLOCK_SIZE];
+        device
+            .read_blocks(&mut block, offset / BLOCK_SIZE * BLOCK_SIZE, 1)
+            .unwrap();
+        let value = u32::from_le_bytes(block[offset % BLOCK_SIZE..][..4].try_into().unwrap());
+        value & CLN_SHUT_BIT_MASK_FAT32 != 0
+    }
+
+    #[test]
+    fn unclean_unmount_needs_check() {
+        let t = TestFs::new();
+        let bpb = t.fs.read().bpb();
+        assert!(!clean_bit_on_disk(t.device.as_ref(), &bpb));
+
+        let t = t.reopen();
+        assert!(!t.fs.read().needs_check());
+        // 模拟异常关机: 文件系统没有被 drop
+        core::mem::forget(Arc::clone(&t.fs));
+        drop(t.fs);
+        assert!(!clean_bit_on_disk(t.device.as_ref(), &bpb));
+
+        let fs = FileSystem::try_open(t.device.clone()).unwrap();
+        assert!(fs.read().needs_check());
+        assert!(fs.read().was_dirty());
+        drop(fs);
+        assert!(clean_bit_on_disk(t.device.as_ref(), &bpb));
+    }
+
+    #[test]
+    fn read_only_open_keeps_volume_state() {
+        // 格式化的结果在卸载时才全部写回设备
+        let t = TestFs::new().reopen();
+        let bpb = t.fs.read().bpb();
+        core::mem::forget(Arc::clone(&t.fs));
+        drop(t.fs);
+
+        for _ in 0..2 {
+            let fs = FileSystem::try_open_read_only(t.device.clone()).unwrap();
+            assert!(fs.read().is_read_only());
+            assert!(fs.read().needs_check());
+            assert_eq!(fs.read().set_volume_dirty(false), Err(FsError::ReadOnly));
+            drop(fs);
+            // 仍然标记为未正常卸载
+            assert!(!clean_bit_on_disk(t.device.as_ref(), &bpb));
+        }
+    }
+
+    #[test]
+    fn switching_to_read_only_marks_volume_clean() {
+        let t = TestFs::new().reopen();
+        let bpb = t.fs.read().bpb();
+        t.fs.write().set_read_only(true);
+        assert!(clean_bit_on_disk(t.device.as_ref(), &bpb));
+        t.fs.write().set_read_only(false);
+        assert!(!clean_bit_on_disk(t.device.as_ref(), &bpb));
+        t.fs.write().set_read_only(true);
+
+        // 只读期间异常关机, 下次打开不需要检查
+        core::mem::forget(Arc::clone(&t.fs));
+        drop(t.fs);
+        let fs = FileSystem::try_open(t.device.clone()).unwrap();
+        assert!(!fs.read().needs_check());
+    }
+
+    #[test]
+    fn failed_flush_keeps_volume_dirty() {
+        // 写回失败的块不能被其他测试的 sync 遇到
+        let t = TestFs::exclusive();
+        let bpb = t.fs.read().bpb();
+        drop(t.fs);
+        let fault = Arc::new(FaultDevice::new(t.device.clone()));
+        let fs = FileSystem::open(fault.clone());
+        let file = fs
+            .read()
+            .root()
+            .create("a.bin", VirtFileType::File)
+            .unwrap();
+        assert_eq!(file.write_at(0, b"unflushed"), 9);
+        fault.fail_block(fs.read().cluster_to_block(file.first_cluster() as u32));
+        drop(file);
+
+        // 切换为只读与卸载都在写回失败时保留 dirty 标记
+        fs.write().set_read_only(true);
+        assert!(!clean_bit_on_disk(t.device.as_ref(), &bpb));
+        fs.write().set_read_only(false);
+        drop(fs);
+        assert!(!clean_bit_on_disk(t.device.as_ref(), &bpb));
+    }
+
+    #[test]
+    fn failed_fsinfo_sentinel_is_recorded_as_io_error() {
+        let t = TestFs::exclusive();
+        let bpb = t.fs.read().bpb();
+        drop(t.fs);
+        let fault = Arc::new(FaultDevice::new(t.device.clone()));
+        let fs = FileSystem::open(fault.clone());
+        fault.fail_block(bpb.fat_info_sector());
+        let file = fs
+            .read()
+            .root()
+            .create("a.bin", VirtFileType::File)
+            .unwrap();
+        let data = pattern(3 * fs.read().cluster_size(), 7);
+        assert_eq!(file.write_at(0, &data), data.len());
+        drop(file);
+        // FAT 已写回而 FSInfo 仍是上次卸载时的计数, 然后断电
+        assert!(fs.read().sync().is_err());
+        fault.clear_faults();
+        core::mem::forget(Arc::clone(&fs));
+        drop(fs);
+
+        let fs = FileSystem::open(t.device.clone() as Arc<dyn BlockDevice>);
+        assert!(fs.read().had_io_errors());
+        let (stored, actual) = fs.read().reconcile_free_count(false);
+        assert_eq!(stored, actual);
+    }
+
+    #[test]
+    fn cluster_blocks_locate_file_data() {
+        let t = TestFs::new();
+        let data = pattern(2 * t.cluster_size(), 5);
+        let file = t.create_file("data.bin", &data);
+        let fs = t.fs.read();
+        fs.sync().unwrap();
+
+        let blocks_per_cluster = fs.cluster_size() / BLOCK_SIZE;
+        assert_eq!(fs.cluster_to_block(2), fs.first_data_sector());
+        assert_eq!(
+            fs.cluster_blocks(5),
+            fs.first_data_sector() + 3 * blocks_per_cluster
+                ..fs.first_data_sector() + 4 * blocks_per_cluster
+        );
+
+        // 设备上每个簇的块即文件内容
+        let mut on_disk = Vec::new();
+        for cluster in fs.cluster_chain(file.first_cluster() as u32) {
+            for block_id in fs.cluster_blocks(cluster) {
+                let mut block = [0u8; BLOCK_SIZE];
+                t.device
+                    .read_blocks(&mut block, block_id * BLOCK_SIZE, 1)
+                    .unwrap();
+                on_disk.extend_from_slice(&block);
+            }
+        }
+        assert_eq!(on_disk, data);
+    }
+
+    #[test]
+    fn drop_flushes_data_and_fsinfo() {
+        let t = TestFs::new();
+        let data = pattern(3 * t.cluster_size() + 7, 8);
+        drop(t.create_file("flushed.bin", &data));
+        let free = t.free_cluster_cnt();
+        let fsinfo_sector = t.fs.read().bpb().fat_info_sector();
+
+        let t = t.reopen();
+        // FSInfo 的 free_count (偏移 488) 已写回
+        let mut block = [0u8; BLOCK_SIZE];
+        t.device
+            .read_blocks(&mut block, fsinfo_sector * BLOCK_SIZE, 1)
+            .unwrap();
+        assert_eq!(read_le_u32(&block[488..]), Some(free as u32));
+        assert_eq!(t.free_cluster_cnt(), free);
+        let file = t.root().find(vec!["flushed.bin"]).unwrap();
+        assert_eq!(read_all(&file), data);
+    }
+
+    #[test]
+    fn dealloc_cluster_zeroed_wipes_data() {
+        let t = TestFs::new();
+        let fs = t.fs.read();
+        let free = fs.free_cluster_cnt();
+        let first = fs.alloc_cluster_no_clear(2, 0).unwrap();
+        let clusters = fs.fat.read().get_all_cluster_id(first);
+        let blocks: Vec<usize> = clusters
+            .iter()
+            .flat_map(|&cluster| fs.cluster_blocks(cluster))
+            .collect();
+        for &block_id in blocks.iter() {
+            let data = pattern(BLOCK_SIZE, block_id as u8);
+            t.device
+                .write_blocks(&data, block_id * BLOCK_SIZE, 1)
+                .unwrap();
+        }
+
+        fs.dealloc_cluster_zeroed(clusters);
+        fs.sync().unwrap();
+        assert_eq!(fs.free_cluster_cnt(), free);
+        for &block_id in blocks.iter() {
+            let mut block = [0xFFu8; BLOCK_SIZE];
+            t.device
+                .read_blocks(&mut block, block_id * BLOCK_SIZE, 1)
+                .unwrap();
+            assert_eq!(block, [0; BLOCK_SIZE]);
+        }
+    }
+
+    #[test]
+    fn repair_fat_mirror_fixes_diverging_entries() {
+        let t = TestFs::new();
+        let file = t.create_file("mirror.bin", &pattern(2 * t.cluster_size(), 1));
+        let cluster = file.first_cluster() as u32;
+        drop(file);
+        let t = t.reopen();
+        assert_eq!(t.fs.read().verify_fat_mirror(), Ok(()));
+
+        // 直接改写设备上 FAT2 中该文件首簇的表项
+        let fat2_offset = t.fs.read().bpb().fat_offset(1);
+        let t = {
+            let device = t.device.clone();
+            drop(t);
+            let offset = fat2_offset + cluster as usize * 4;
+            let mut block = [0u8; BLOCK_SIZE];
+            let block_offset = offset / BLOCK_SIZE * BLOCK_SIZE;
+            device.read_blocks(&mut block, block_offset, 1).unwrap();
+            block[offset % BLOCK_SIZE..][..4].copy_from_slice(&0u32.to_le_bytes());
+            device.write_blocks(&block, block_offset, 1).unwrap();
+            TestFs::open(device)
+        };
+
+        let fs = t.fs.read();
+        assert_eq!(fs.verify_fat_mirror(), Err(vec![cluster]));
+        assert_eq!(fs.repair_fat_mirror(FatCopy::Fat1), 1);
+        assert_eq!(fs.verify_fat_mirror(), Ok(()));
+        let file = fs.root().find(vec!["mirror.bin"]).unwrap();
+        assert_eq!(read_all(&file), pattern(2 * fs.cluster_size(), 1));
+    }
+
+    // 设备上 FSInfo 的 free_count (偏移 488)
+    fn fsinfo_free_count_on_disk(t: &TestFs) -> u32 {
+        let fsinfo_sector = t.fs.read().bpb().fat_info_sector();
+        let mut block = [0u8; BLOCK_SIZE];
+        t.device
+            .read_blocks(&mut block, fsinfo_sector * BLOCK_SIZE, 1)
+            .unwrap();
+        read_le_u32(&block[488..]).unwrap()
+    }
+
+    #[test]
+    fn free_count_reaches_fsinfo_on_sync_only() {
+        let t = TestFs::new().reopen();
+        let free = t.free_cluster_cnt();
+        assert_eq!(fsinfo_free_count_on_disk(&t), free as u32);
+
+        // 分配后设备上立即是哨兵值
+        drop(t.create_file("a.bin", &pattern(3 * t.cluster_size(), 3)));
+        assert_eq!(fsinfo_free_count_on_disk(&t), FSINFO_UNKNOWN);
+        t.fs.read().sync().unwrap();
+        assert_eq!(fsinfo_free_count_on_disk(&t), free as u32 - 3);
+
+        // FAT 已写回而 FSInfo 未更新时异常关机, 重新挂载后由 FAT 重新统计
+        drop(t.create_file("b.bin", &pattern(2 * t.cluster_size(), 4)));
+        sync_all().unwrap();
+        assert_eq!(fsinfo_free_count_on_disk(&t), FSINFO_UNKNOWN);
+        core::mem::forget(Arc::clone(&t.fs));
+        let device = t.device.clone();
+        drop(t);
+        let t = TestFs::open(device);
+        assert_eq!(t.free_cluster_cnt(), free - 5);
+    }
+
+    #[test]
+    fn chain_extents_of_fragmented_file() {
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        let a = t.create_file("a.bin", &pattern(2 * cluster_size, 1));
+        let b = t.create_file("b.bin", &pattern(cluster_size, 2));
+        assert_eq!(
+            a.write_at(2 * cluster_size, &pattern(2 * cluster_size, 3)),
+            2 * cluster_size
+        );
+
+        let fs = t.fs.read();
+        let first = a.first_cluster() as u32;
+        let b_cluster = b.first_cluster() as u32;
+        assert_eq!(b_cluster, first + 2);
+        // b 的簇把 a 分成两段
+        assert_eq!(fs.chain_extents(first), [(first, 2), (b_cluster + 1, 2)]);
+        assert_eq!(fs.chain_extents(b_cluster), [(b_cluster, 1)]);
+        assert!(fs.chain_extents(0).is_empty());
+    }
+
+    #[test]
+    fn volume_serial_from_options_clock_and_setter() {
+        let t = TestFs::with_options(FormatOptions {
+            volume_serial: Some(0xCAFE_F00D),
+            ..FormatOptions::default()
+        });
+        assert_eq!(t.fs.read().bpb().volume_serial(), 0xCAFE_F00D);
+        assert_eq!(t.fs.read().volume_serial(), 0xCAFE_F00D);
+
+        // 未指定时由格式化时刻生成
+        let t = TestFs::with_options(FormatOptions {
+            clock: Some(|| (0x5891, 0x6DEF)),
+            ..FormatOptions::default()
+        });
+        assert_eq!(t.fs.read().volume_serial(), 0x5891_6DEF);
+
+        t.fs.read().set_volume_serial(0x0102_0304);
+        assert_eq!(t.fs.read().volume_serial(), 0x0102_0304);
+        let t = t.reopen();
+        assert_eq!(t.fs.read().bpb().volume_serial(), 0x0102_0304);
+    }
+
+    #[test]
+    fn cluster_size_follows_volume_size() {
+        let _cache = CacheGuard::shared();
+        let format = |options: FormatOptions| {
+            let device = Arc::new(MemBlockDevice::new(2 * BLOCK_NUM as usize));
+            FileSystem::create_with(device, options).read().bpb()
+        };
+
+        // 两倍大小的卷每簇两个扇区仍满足 FAT32 的簇数下限
+        let bpb = format(FormatOptions {
+            total_sectors: Some(2 * BLOCK_NUM),
+            ..FormatOptions::default()
+        });
+        assert_eq!(bpb.total_sector_cnt(), 2 * BLOCK_NUM as usize);
+        assert_eq!(bpb.sector_per_cluster(), 2);
+        assert_eq!(bpb.fat_type(), FatType::FAT32);
+
+        let bpb = format(FormatOptions {
+            total_sectors: Some(2 * BLOCK_NUM),
+            sectors_per_cluster: Some(1),
+            ..FormatOptions::default()
+        });
+        assert_eq!(bpb.sector_per_cluster(), 1);
+
+        // 默认大小的卷每簇一个扇区
+        assert_eq!(format(FormatOptions::default()).sector_per_cluster(), 1);
+    }
+
+    #[test]
+    fn single_fat_volume() {
+        let two = TestFs::new().fs.read().bpb();
+        let t = TestFs::with_options(FormatOptions {
+            num_fats: Some(1),
+            ..FormatOptions::default()
+        });
+        let data = pattern(3 * t.cluster_size(), 5);
+        drop(t.create_file("one.bin", &data));
+        let t = t.reopen();
+
+        let bpb = t.fs.read().bpb();
+        assert_eq!(bpb.fat_cnt(), 1);
+        assert!(bpb.mirror_fat_offsets().is_empty());
+        // 数据区紧跟唯一的 FAT
+        assert!(bpb.first_data_sector() < two.first_data_sector());
+        assert_eq!(t.fs.read().verify_fat_mirror(), Ok(()));
+        let file = t.root().find(vec!["one.bin"]).unwrap();
+        assert_eq!(read_all(&file), data);
+    }
+
+    #[test]
+    fn sector_access_goes_through_the_cache() {
+        let t = TestFs::new();
+        let block_id = t.fs.read().cluster_to_block(t.fs.read().next_free_hint());
+        let data: [u8; BLOCK_SIZE] = pattern(BLOCK_SIZE, 17).try_into().unwrap();
+
+        t.fs.read().write_sector(block_id, &data).unwrap();
+        let mut buf = [0u8; BLOCK_SIZE];
+        t.fs.read().read_sector(block_id, &mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        t.fs.read().sync().unwrap();
+        let mut on_disk = [0u8; BLOCK_SIZE];
+        t.device
+            .read_blocks(&mut on_disk, block_id * BLOCK_SIZE, 1)
+            .unwrap();
+        assert_eq!(on_disk, data);
+
+        t.fs.write().set_read_only(true);
+        assert_eq!(
+            t.fs.read().write_sector(block_id, &[0; BLOCK_SIZE]),
+            Err(FsError::ReadOnly)
+        );
+        t.fs.read().read_sector(block_id, &mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    // 记录收到的事件
+    #[derive(Default)]
+    struct RecordingTracer {
+        allocs: spin::Mutex<Vec<(u32, usize)>>,
+        freed: spin::Mutex<Vec<u32>>,
+        reads: spin::Mutex<Vec<usize>>,
+        writes: spin::Mutex<Vec<usize>>,
+    }
+
+    impl FsTracer for RecordingTracer {
+        fn on_alloc(&self, cluster: u32, count: usize) {
+            self.allocs.lock().push((cluster, count));
+        }
+
+        fn on_free(&self, clusters: &[u32]) {
+            self.freed.lock().extend_from_slice(clusters);
+        }
+
+        fn on_block_read(&self, block_id: usize) {
+            self.reads.lock().push(block_id);
+        }
+
+        fn on_block_write(&self, block_id: usize) {
+            self.writes.lock().push(block_id);
+        }
+    }
+
+    #[test]
+    fn tracer_sees_allocation_and_block_io() {
+        let t = TestFs::exclusive();
+        let tracer = Arc::new(RecordingTracer::default());
+        t.fs.write().set_tracer(tracer.clone());
+
+        let file = t.create_file("traced.bin", &pattern(3 * t.cluster_size(), 9));
+        let clusters: Vec<u32> =
+            t.fs.read()
+                .cluster_chain(file.first_cluster() as u32)
+                .collect();
+        let allocated: usize = tracer.allocs.lock().iter().map(|&(_, count)| count).sum();
+        assert_eq!(allocated, 3);
+        assert_eq!(tracer.allocs.lock()[0].0, clusters[0]);
+
+        drop(file);
+        t.root().remove(vec!["traced.bin"]).unwrap();
+        assert_eq!(*tracer.freed.lock(), clusters);
+
+        // 未缓存的块从设备读取, sync 时写回
+        let block_id =
+            t.fs.read()
+                .cluster_to_block(t.fs.read().next_free_hint() + 100);
+        let mut buf = [0u8; BLOCK_SIZE];
+        t.fs.read().read_sector(block_id, &mut buf).unwrap();
+        assert!(tracer.reads.lock().contains(&block_id));
+        t.fs.read().sync().unwrap();
+        let data_block = t.fs.read().cluster_to_block(clusters[0]);
+        assert!(tracer.writes.lock().contains(&data_block));
+    }
+
+    #[test]
+    fn root_handle_is_cached_while_alive() {
+        let t = TestFs::new();
+        let root = t.root();
+        assert!(Arc::ptr_eq(&root, &t.root()));
+
+        // 缓存为 Weak: 所有 Arc 释放后根目录随之释放, 下次调用重新构造
+        let weak = Arc::downgrade(&root);
+        drop(root);
+        assert!(weak.upgrade().is_none());
+        let root = t.root();
+        assert!(root.is_dir());
+        assert!(t.fs.read().root.read().upgrade().is_some());
+    }
+
+    #[test]
+    fn free_extents_cover_every_free_cluster() {
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        let files: Vec<_> = (0..3)
+            .map(|i| t.create_file(&format!("f{}.bin", i), &pattern(cluster_size, i)))
+            .collect();
+        let hole = files[1].first_cluster() as u32;
+        files[1].clear().unwrap();
+
+        let extents: Vec<(u32, u32)> = t.fs.read().free_extents().collect();
+        let total: u32 = extents.iter().map(|&(_, cnt)| cnt).sum();
+        assert_eq!(total as usize, t.free_cluster_cnt());
+        assert!(extents.contains(&(hole, 1)));
+        // 按簇号递增, 相邻的空闲段之间至少隔一个已用簇
+        assert!(extents.windows(2).all(|w| w[0].0 + w[0].1 < w[1].0));
+
+        // 可以提前停止
+        assert_eq!(t.fs.read().free_extents().take(1).count(), 1);
+    }
+
+    #[test]
+    fn reconcile_free_count_repairs_a_wrong_count() {
+        let t = TestFs::new();
+        t.create_file("a.bin", &pattern(3 * t.cluster_size(), 1));
+        let actual = t.free_cluster_cnt();
+        assert_eq!(t.fs.read().reconcile_free_count(true), (actual, actual));
+
+        // 计数偏大时只报告不修改, 除非 fix
+        t.fs.read().set_free_clusters(actual + 7);
+        assert_eq!(
+            t.fs.read().reconcile_free_count(false),
+            (actual + 7, actual)
+        );
+        assert_eq!(t.free_cluster_cnt(), actual + 7);
+        assert_eq!(t.fs.read().reconcile_free_count(true), (actual + 7, actual));
+        assert_eq!(t.free_cluster_cnt(), actual);
+
+        // 修正后的计数在 sync 时写入 FSInfo
+        t.fs.read().sync().unwrap();
+        assert_eq!(fsinfo_free_count_on_disk(&t), actual as u32);
+    }
+
+    #[test]
+    fn format_with_custom_reserved_sectors() {
+        let t = TestFs::with_options(FormatOptions {
+            reserved_sectors: Some(64),
+            ..Default::default()
+        });
+        let data = pattern(3 * t.cluster_size(), 12);
+        t.create_file("a.bin", &data);
+        let t = t.reopen();
+        let fs = t.fs.read();
+        assert_eq!(fs.bpb().reserved_sector_cnt(), 64);
+        assert_eq!(fs.bpb().fat_offset(0), 64 * BLOCK_SIZE);
+        drop(fs);
+        assert_eq!(read_all(&t.root().find_by_name("a.bin").unwrap()), data);
+    }
+
+    #[test]
+    #[should_panic(expected = "reserved_sectors must be at least 7")]
+    fn format_rejects_too_few_reserved_sectors() {
+        TestFs::with_options(FormatOptions {
+            reserved_sectors: Some(6),
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn lost_chains_are_found_and_reclaimed() {
+        let t = TestFs::new();
+        let data = pattern(2 * t.cluster_size(), 13);
+        t.create_file("kept.bin", &data);
+        let dir = t.root().create("dir", VirtFileType::Dir).unwrap();
+        dir.create("inner.txt", VirtFileType::File)
+            .unwrap()
+            .write_at(0, b"inner");
+        let free = t.free_cluster_cnt();
+        assert!(t.fs.read().find_lost_chains().is_empty());
+
+        // 分配后未写目录项, 模拟崩溃泄漏的簇链
+        let first = t.fs.read().alloc_cluster_no_clear(3, 0).unwrap();
+        let leaked: Vec<u32> = t.fs.read().cluster_chain(first).collect();
+        assert_eq!(leaked.len(), 3);
+        assert_eq!(t.fs.read().find_lost_chains(), vec![leaked]);
+
+        assert_eq!(t.fs.read().reclaim_lost(), 3);
+        assert_eq!(t.free_cluster_cnt(), free);
+        assert!(t.fs.read().find_lost_chains().is_empty());
+        assert_eq!(read_all(&t.root().find_by_name("kept.bin").unwrap()), data);
+        assert_eq!(read_all(&dir.find_by_name("inner.txt").unwrap()), b"inner");
+    }
+
+    // 设备上 (而非缓存中) 活动 FAT 里 cluster 的表项
+    fn fat_entry_on_disk(t: &TestFs, cluster: u32) -> u32 {
+        let pos = t.fs.read().bpb().active_fat_offset() + cluster as usize * 4;
+        let mut block = [0u8; BLOCK_SIZE];
+        t.device
+            .read_blocks(&mut block, pos / BLOCK_SIZE * BLOCK_SIZE, 1)
+            .unwrap();
+        read_le_u32(&block[pos % BLOCK_SIZE..]).unwrap() & CLUSTER_MASK
+    }
+
+    #[test]
+    fn ordered_writes_flush_fat_before_entry_references_it() {
+        // 依赖新簇的 FAT 表项仍留在缓存中
+        let t = TestFs::exclusive();
+        let cluster_size = t.cluster_size();
+        assert!(!t.fs.read().ordered_writes());
+        let lazy = t.create_file("lazy.bin", &pattern(2 * cluster_size, 14));
+        assert_eq!(
+            fat_entry_on_disk(&t, lazy.first_cluster() as u32),
+            FREE_CLUSTER
+        );
+
+        t.fs.write().set_ordered_writes(true);
+        let data = pattern(2 * cluster_size, 15);
+        let file = t.create_file("ordered.bin", &data);
+        let first = file.first_cluster() as u32;
+        let second = t.fs.read().cluster_chain(first).nth(1).unwrap();
+        assert_eq!(fat_entry_on_disk(&t, first), second);
+        assert_ne!(fat_entry_on_disk(&t, second), FREE_CLUSTER);
+        assert_eq!(file.file_size(), data.len());
+        assert_eq!(read_all(&file), data);
+    }
+
+    #[test]
+    fn file_handles_keep_using_the_mounted_device() {
+        let t = TestFs::exclusive();
+        let file = t.create_file("early.bin", b"");
+        assert!(Arc::ptr_eq(&file.device, &t.fs.read().device()));
+
+        // 句柄在安装 tracer 之前创建, 缓存的设备仍会上报块 I/O
+        let tracer = Arc::new(RecordingTracer::default());
+        t.fs.write().set_tracer(tracer.clone());
+        let data = pattern(t.cluster_size(), 17);
+        assert_eq!(file.write_at(0, &data), data.len());
+        t.fs.read().sync().unwrap();
+        let data_block = t.fs.read().cluster_to_block(file.first_cluster() as u32);
+        assert!(tracer.writes.lock().contains(&data_block));
+        assert_eq!(read_all(&file), data);
+    }
+
+    #[test]
+    fn configured_eoc_value_terminates_new_chains() {
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        let tail_entry = |file: &VirtFile| {
+            let fs = t.fs.read();
+            let tail = fs
+                .cluster_chain(file.first_cluster() as u32)
+                .last()
+                .unwrap();
+            fs.read_fat_entry(fs.bpb().active_fat_offset(), tail)
+        };
+        assert_eq!(t.fs.read().eoc_value(), crate::EOC_MARK);
+        let default = t.create_file("default.bin", &pattern(2 * cluster_size, 18));
+        assert_eq!(tail_entry(&default), crate::EOC_MARK);
+
+        t.fs.write().set_eoc_value(END_OF_CLUSTER);
+        let data = pattern(3 * cluster_size, 19);
+        let custom = t.create_file("custom.bin", &data);
+        assert_eq!(tail_entry(&custom), END_OF_CLUSTER);
+        // 连续分配同样写入配置的值
+        let start = t.fs.read().alloc_contiguous(2).unwrap();
+        let fs = t.fs.read();
+        assert_eq!(
+            fs.read_fat_entry(fs.bpb().active_fat_offset(), start + 1),
+            END_OF_CLUSTER
+        );
+        drop(fs);
+
+        // 两种值都被识别为簇链结束
+        drop((default, custom));
+        let t = t.reopen();
+        let root = t.root();
+        assert_eq!(
+            root.find_by_name("default.bin").unwrap().allocated_size(),
+            2 * cluster_size
+        );
+        assert_eq!(read_all(&root.find_by_name("custom.bin").unwrap()), data);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not an end-of-chain value")]
+    fn eoc_value_must_be_in_range() {
+        let t = TestFs::new();
+        t.fs.write().set_eoc_value(BAD_CLUSTER);
+    }
+
+    #[test]
+    fn boot_code_round_trips_without_touching_bpb() {
+        let t = TestFs::new();
+        let serial = t.fs.read().bpb().volume_serial();
+        assert_eq!(t.fs.read().read_boot_code(), [0u8; BOOT_CODE_LEN]);
+
+        let code = pattern(100, 22);
+        t.fs.read().write_boot_code(&code).unwrap();
+        t.fs.read().sync().unwrap();
+        let t = t.reopen();
+        let stored = t.fs.read().read_boot_code();
+        assert_eq!(&stored[..100], &code[..]);
+        assert!(stored[100..].iter().all(|&b| b == 0));
+        assert_eq!(t.fs.read().bpb().volume_serial(), serial);
+        let mut boot = [0u8; BLOCK_SIZE];
+        t.device.read_blocks(&mut boot, 0, 1).unwrap();
+        assert_eq!(&boot[510..], &[0x55, 0xAA]);
+
+        // 较短的代码覆盖时其余部分清零
+        t.fs.read().write_boot_code(b"jmp").unwrap();
+        let stored = t.fs.read().read_boot_code();
+        assert_eq!(&stored[..3], b"jmp");
+        assert!(stored[3..].iter().all(|&b| b == 0));
+
+        assert_eq!(
+            t.fs.read().write_boot_code(&[0x90; BOOT_CODE_LEN + 1]),
+            Err(FsError::BootCodeTooLong(BOOT_CODE_LEN + 1))
+        );
+        t.fs.write().set_read_only(true);
+        assert_eq!(t.fs.read().write_boot_code(b"x"), Err(FsError::ReadOnly));
+    }
+
+    #[test]
+    fn boot_signature_checked_only_by_strict_open() {
+        let t = TestFs::new();
+        assert!(t.fs.read().has_valid_boot_signature());
+        t.fs.read().sync().unwrap();
+        drop(t.fs);
+        let mut boot = [0u8; BLOCK_SIZE];
+        t.device.read_blocks(&mut boot, 0, 1).unwrap();
+        assert_eq!(&boot[BOOT_SIGNATURE_OFFSET..], &BOOT_SIGNATURE);
+        assert!(FileSystem::try_open_strict(t.device.clone()).is_ok());
+
+        // 抹去签名; 经 FaultDevice 打开以免读到缓存中的 0 号块
+        boot[BOOT_SIGNATURE_OFFSET..].fill(0);
+        t.device.write_blocks(&boot, 0, 1).unwrap();
+        assert_eq!(
+            FileSystem::try_open_strict(Arc::new(FaultDevice::new(t.device.clone()))).err(),
+            Some(FsError::MissingBootSignature)
+        );
+        let fs = FileSystem::try_open(Arc::new(FaultDevice::new(t.device.clone()))).unwrap();
+        assert!(!fs.read().has_valid_boot_signature());
+    }
+
+    #[test]
+    fn mounted_volumes_do_not_share_cached_blocks() {
+        // 两个镜像的 100 号块内容不同
+        let images: Vec<Arc<MemBlockDevice>> = (0..2u8)
+            .map(|seed| {
+                let t = TestFs::new();
+                t.fs.read().sync().unwrap();
+                drop(t.fs);
+                t.device
+                    .write_blocks(&pattern(BLOCK_SIZE, seed), 100 * BLOCK_SIZE, 1)
+                    .unwrap();
+                t.device
+            })
+            .collect();
+        let a = TestFs::open(images[0].clone());
+        let b = TestFs::open(images[1].clone());
+        assert_ne!(a.fs.read().cache_id(), b.fs.read().cache_id());
+
+        let block_100 = |t: &TestFs| {
+            get_block_cache(100, t.fs.read().device())
+                .read()
+                .read(0, |block: &[u8; BLOCK_SIZE]| block.to_vec())
+        };
+        assert_eq!(block_100(&a), pattern(BLOCK_SIZE, 0));
+        assert_eq!(block_100(&b), pattern(BLOCK_SIZE, 1));
+    }
+
+    #[test]
+    fn identical_layouts_keep_their_own_data() {
+        // 格式化参数相同, 同名文件占用相同的块
+        let a = TestFs::new();
+        let b = TestFs::new();
+        let fa = a.create_file("same.bin", &pattern(2 * a.cluster_size(), 23));
+        let fb = b.create_file("same.bin", &pattern(2 * b.cluster_size(), 24));
+        assert_eq!(fa.first_cluster(), fb.first_cluster());
+
+        assert_eq!(read_all(&fa), pattern(2 * a.cluster_size(), 23));
+        assert_eq!(read_all(&fb), pattern(2 * b.cluster_size(), 24));
+        drop((fa, fb));
+        let a = a.reopen();
+        let file = a.root().find_by_name("same.bin").unwrap();
+        assert_eq!(read_all(&file), pattern(2 * a.cluster_size(), 23));
+    }
+
+    #[test]
+    fn dropping_fs_evicts_its_cached_blocks() {
+        let t = TestFs::exclusive();
+        let data = pattern(t.cluster_size(), 25);
+        let file = t.create_file("a.bin", &data);
+        let block_id = t.fs.read().cluster_to_block(file.first_cluster() as u32);
+        // 保留带有该文件系统 cache_id 的设备
+        let device = t.fs.read().device();
+        drop(file);
+        drop(t.fs);
+
+        // 卸载时已写回
+        let mut raw = [0u8; BLOCK_SIZE];
+        t.device
+            .read_blocks(&mut raw, block_id * BLOCK_SIZE, 1)
+            .unwrap();
+        assert_eq!(&raw[..], &data[..]);
+
+        // 缓存中没有旧块: 再次读取看到设备上的新内容
+        let changed = pattern(BLOCK_SIZE, 26);
+        t.device
+            .write_blocks(&changed, block_id * BLOCK_SIZE, 1)
+            .unwrap();
+        let cached = get_block_cache(block_id, device)
+            .read()
+            .read(0, |block: &[u8; BLOCK_SIZE]| block.to_vec());
+        assert_eq!(cached, changed);
+    }
 }
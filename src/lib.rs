@@ -3,7 +3,9 @@
 use alloc::{string::String, vec::Vec};
 use core::{
     convert::TryInto,
+    hash::Hasher,
     iter::Iterator,
+    option::Option,
     option::Option::{None, Some},
     str,
 };
@@ -15,9 +17,13 @@ pub mod cache;
 pub mod device;
 pub mod dir;
 pub mod entry;
+pub mod error;
 pub mod fat;
 pub mod file;
 pub mod fs;
+pub mod mbr;
+#[cfg(test)]
+mod test_util;
 pub mod vfs;
 
 pub use bpb::*;
@@ -25,15 +31,19 @@ pub use cache::*;
 pub use device::*;
 pub use dir::*;
 pub use entry::*;
+pub use error::*;
 pub use fat::*;
 pub use file::*;
 pub use fs::*;
+pub use mbr::*;
 pub use vfs::*;
 
 // Signature
 pub const LEAD_SIGNATURE: u32 = 0x41615252;
 pub const STRUCT_SIGNATURE: u32 = 0x61417272;
 pub const TRAIL_SIGNATURE: u32 = 0xAA550000;
+/// FSInfo free count / next free value meaning "unknown"
+pub const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
 
 // Cluster
 pub const FREE_CLUSTER: u32 = 0x00000000;
@@ -63,11 +73,18 @@ pub const BAD_CLUSTER: u32 = 0x0FFF_FFF7;
 //  这样在 next() 中也判断是否为 EOC
 // pub const END_OF_CLUSTER: u32 = 0x0FFFFFFF; linux mkfs fat32 再 mount 后发现 EOC 的值为 0x0FFFFFF8
 pub const END_OF_CLUSTER: u32 = 0x0FFF_FFF8;
+/// End-of-chain mark written by default (the value mkfs.fat uses); any value from
+/// [`END_OF_CLUSTER`] up is read as the end of a chain
+pub const EOC_MARK: u32 = 0x0FFF_FFFF;
 pub const CLUSTER_MASK: u32 = 0x0FFF_FFFF;
 
 pub const NEW_VIR_FILE_CLUSTER: u32 = 0;
 // 标记为根目录项的簇号(跟目录项实际不保存在磁盘上)
 pub const ROOT_DIR_ENTRY_CLUSTER: u32 = 0;
+// 标记为无目录项文件 (通过首簇号打开) 的簇号, 1 为保留簇号, 不会与数据区的簇号冲突
+pub const DETACHED_DIR_ENTRY_CLUSTER: u32 = 1;
+// 根目录的 inode 号 (根目录没有目录项, 故使用保留值)
+pub const ROOT_INODE: u64 = 1;
 
 pub const ATTR_READ_ONLY: u8 = 0x01;
 pub const ATTR_HIDDEN: u8 = 0x02;
@@ -79,6 +96,9 @@ pub const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR
 
 pub const DIRENT_SIZE: usize = 32;
 
+// 目录项中 file_size 为 32 位, 文件最大 4 GiB - 1
+pub const MAX_FILE_SIZE: usize = u32::MAX as usize;
+
 // Cache Limit
 pub const BLOCK_CACHE_LIMIT: usize = 64;
 
@@ -92,8 +112,8 @@ pub const SPACE: u8 = 0x20;
 pub const DOT: u8 = 0x2E;
 pub const ROOT: u8 = 0x2F;
 
-// For Test
-pub const BLOCK_NUM: u32 = 0x4000;
+// For Test: FileSystem::create 默认格式化的卷的扇区数, FAT32 至少需要 65525 个簇, 因此默认每簇一个扇区
+pub const BLOCK_NUM: u32 = 0x11000;
 pub const ROOT_DIR_CLUSTER: u32 = 2;
 
 /// BPB Bytes Per Sector
@@ -103,8 +123,17 @@ pub const FAT_BUFFER_SIZE: usize = 512;
 pub const DIR_BUFFER_SIZE: usize = 512;
 pub const FILE_BUFFER_SIZE: usize = 512;
 
+// 引导扇区中 BPB (含 BPB32) 之后到 0x1FE 签名之前的引导代码区域
+pub const BOOT_CODE_OFFSET: usize = 0x5A;
+pub const BOOT_CODE_LEN: usize = BOOT_SIGNATURE_OFFSET - BOOT_CODE_OFFSET;
+// 引导扇区以 0x55 0xAA 结尾
+pub const BOOT_SIGNATURE_OFFSET: usize = 0x1FE;
+pub const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+
 // Directory Entry Name Length Capicity
 pub const LONG_NAME_LEN_CAP: usize = 13;
+/// Longest long name, in UTF-16 code units (not counting the trailing NUL)
+pub const LONG_NAME_MAX_LEN: usize = 255;
 pub const SHORT_NAME_LEN_CAP: usize = 11;
 
 /// For Short Directory Entry Name[0] and Long Directory Entry Ord
@@ -126,6 +155,9 @@ pub const DIR_ENTRY_LAST_AND_UNUSED: u8 = 0x00;
 ///    multiple long directory entries are required.
 pub const LAST_LONG_ENTRY: u8 = 0x40;
 
+/// 1980-01-01, the earliest date a FAT directory entry can hold
+pub const FAT_EPOCH_DATE: u16 = (1 << 5) | 1;
+
 pub const MAX_CLUSTER_FAT12: usize = 4085;
 pub const MAX_CLUSTER_FAT16: usize = 65525;
 pub const MAX_CLUSTER_FAT32: usize = 268435445;
@@ -138,15 +170,16 @@ pub const MAX_CLUSTER_FAT32: usize = 268435445;
 pub const CLN_SHUT_BIT_MASK_FAT32: u32 = 0x08000000;
 pub const HRD_ERR_BIT_MASK_FAT32: u32 = 0x04000000;
 
+// 输入长度不足时返回 None, 而不是 panic
 #[allow(unused)]
-pub(crate) fn read_le_u16(input: &[u8]) -> u16 {
-    let (int_bytes, _) = input.split_at(core::mem::size_of::<u16>());
-    u16::from_le_bytes(int_bytes.try_into().unwrap())
+pub(crate) fn read_le_u16(input: &[u8]) -> Option<u16> {
+    let int_bytes = input.get(..core::mem::size_of::<u16>())?;
+    Some(u16::from_le_bytes(int_bytes.try_into().ok()?))
 }
 
-pub(crate) fn read_le_u32(input: &[u8]) -> u32 {
-    let (int_bytes, _) = input.split_at(core::mem::size_of::<u32>());
-    u32::from_le_bytes(int_bytes.try_into().unwrap())
+pub(crate) fn read_le_u32(input: &[u8]) -> Option<u32> {
+    let int_bytes = input.get(..core::mem::size_of::<u32>())?;
+    Some(u32::from_le_bytes(int_bytes.try_into().ok()?))
 }
 
 #[allow(unused)]
@@ -160,6 +193,98 @@ pub(crate) fn is_illegal(chs: &str) -> bool {
     false
 }
 
+// 不区分大小写比较两个名字, a 以字符迭代器给出以免构造 String
+pub(crate) fn name_eq_ignore_case(a: impl Iterator<Item = char>, b: &str) -> bool {
+    a.flat_map(char::to_lowercase)
+        .eq(b.chars().flat_map(char::to_lowercase))
+}
+
+// 通配符匹配, 支持 * (任意个字符) 与 ? (单个字符), 不区分大小写
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let char_eq = |a: char, b: char| a.to_lowercase().eq(b.to_lowercase());
+
+    let (mut p, mut n) = (0, 0);
+    // 最近一个 * 的位置及其当前匹配到的 name 位置, 用于回溯
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len()
+            && (pattern[p] == '?' || (pattern[p] != '*' && char_eq(pattern[p], name[n])))
+        {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // * 多匹配一个字符
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// CRC-32 (IEEE 802.3, 反射多项式 0xEDB88320) 查找表, 编译期生成
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 (the zlib / PNG variant) as a [`core::hash::Hasher`]; `finish()` holds the CRC in the
+/// low 32 bits.
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self { crc: 0xFFFF_FFFF }
+    }
+
+    pub fn value(&self) -> u32 {
+        !self.crc
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hasher for Crc32 {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.crc = CRC32_TABLE[((self.crc ^ byte as u32) & 0xFF) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.value() as u64
+    }
+}
+
 #[allow(unused)]
 pub(crate) fn sfn_or_lfn(name: &str) -> NameType {
     let (name, extension) = match name.find('.') {
@@ -181,24 +306,26 @@ pub(crate) fn sfn_or_lfn(name: &str) -> NameType {
     }
 }
 
-#[allow(unused)]
-/// 根据文件名, 返回需要的长目录项数目
-pub(crate) fn get_lde_cnt(value_str: &str) -> usize {
-    // eg. value = "hello, 你好!" -> value.chars().count() = 10
-    let num_char = value_str.chars().count();
-    // 向上取整
-    (num_char + LONG_NAME_LEN_CAP - 1) / LONG_NAME_LEN_CAP
+/// Number of long directory entries needed to store `name`: 13 UTF-16 code units per entry, so
+/// characters outside the BMP (e.g. emoji) count twice
+pub fn lfn_entry_count(name: &str) -> usize {
+    // eg. "hello, 你好!" -> 10 个 UTF-16 单元, "😀" -> 2 个
+    name.encode_utf16().count().div_ceil(LONG_NAME_LEN_CAP)
 }
 
 #[allow(unused)]
 /// 根据文件名, 获取对应的第 count 个长目录项的名字对应于文件名的下标
 pub(crate) fn get_lfn_index(value_str: &str, count: usize) -> usize {
-    let end = 13 * (count - 1);
+    // 与 lfn_entry_count 一致, 按 UTF-16 单元计数
+    let end = LONG_NAME_LEN_CAP * (count - 1);
+    let mut units = 0;
     let mut len = 0;
-    for (index, ch) in value_str.chars().enumerate() {
-        if index < end {
-            len += ch.len_utf8();
+    for ch in value_str.chars() {
+        if units >= end {
+            break;
         }
+        units += ch.len_utf16();
+        len += ch.len_utf8();
     }
     len
 }
@@ -207,14 +334,39 @@ pub(crate) fn get_needed_sector(value: usize) -> usize {
     (value + BLOCK_SIZE - 1) / BLOCK_SIZE
 }
 
+// 由各长名目录项的 UTF-16 片段 (按磁盘顺序, 即序号从大到小) 解码出完整的长文件名.
+// 先拼接再解码, 以免跨越两个目录项的代理对 (如 emoji) 被拆开解码
+pub(crate) fn lfn_name_from_parts(parts: &[[u16; LONG_NAME_LEN_CAP]]) -> String {
+    let units: Vec<u16> = parts
+        .iter()
+        .rev()
+        .flatten()
+        .copied()
+        .take_while(|&unit| unit != 0)
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+// parts 组成的长文件名 (同 lfn_name_from_parts) 是否与 name 相同, 不区分大小写, 不分配内存
+pub(crate) fn lfn_name_eq_ignore_case(parts: &[[u16; LONG_NAME_LEN_CAP]], name: &str) -> bool {
+    let units = parts
+        .iter()
+        .rev()
+        .flatten()
+        .copied()
+        .take_while(|&unit| unit != 0);
+    let chars = char::decode_utf16(units).map(|ch| ch.unwrap_or(char::REPLACEMENT_CHARACTER));
+    name_eq_ignore_case(chars, name)
+}
+
 /// 将长文件名拆分, 返回字符串数组
 pub fn long_name_split(name: &str) -> Vec<[u16; 13]> {
+    // 计算需要几个目录项, 向上取整
+    // 以 13 个 UTF-16 单元为单位进行切割, 每一组占据一个目录项
+    let lfn_cnt = lfn_entry_count(name);
     let mut name: Vec<u16> = name.encode_utf16().collect();
     let len = name.len(); // 注意: 要有 \0
 
-    // 计算需要几个目录项, 向上取整
-    // 以 13个字符为单位进行切割, 每一组占据一个目录项
-    let lfn_cnt = (len + LONG_NAME_LEN_CAP - 1) / LONG_NAME_LEN_CAP;
     if len < lfn_cnt * LONG_NAME_LEN_CAP {
         name.push(0x0000);
         while name.len() < (lfn_cnt * LONG_NAME_LEN_CAP) as usize {
@@ -230,19 +382,13 @@ pub fn long_name_split(name: &str) -> Vec<[u16; 13]> {
         .collect()
 }
 
-/// 拆分文件名和后缀
+/// 拆分文件名和后缀, 后缀为最后一个 . 之后的部分 (如 "archive.tar.gz" 拆分为 "archive.tar" 与 "gz")
 pub fn split_name_ext(name: &str) -> (&str, &str) {
     match name {
-        "." => return (".", ""),
-        ".." => return ("..", ""),
-        _ => {
-            let mut name_and_ext: Vec<&str> = name.split(".").collect(); // 按 . 进行分割
-            if name_and_ext.len() == 1 {
-                // 如果没有后缀名则推入一个空值
-                name_and_ext.push("");
-            }
-            (name_and_ext[0], name_and_ext[1])
-        }
+        "." => (".", ""),
+        ".." => ("..", ""),
+        // 没有后缀名时后缀为空
+        _ => name.rsplit_once('.').unwrap_or((name, "")),
     }
 }
 
@@ -270,35 +416,116 @@ pub fn short_name_format(name: &str) -> ([u8; 8], [u8; 3]) {
     (f_name, f_ext)
 }
 
-// 由长文件名生成短文件名
+// 由长文件名生成短文件名 (数字尾 ~1)
 pub fn generate_short_name(long_name: &str) -> String {
-    let (name_, ext_) = split_name_ext(long_name);
-    let name = name_.as_bytes();
-    let extension = ext_.as_bytes();
-    let mut short_name = String::new();
-    // 取长文件名的前6个字符加上"~1"形成短文件名, 扩展名不变,
-    // 目前不支持重名, 即"~2""~3"; 支持重名与在目录下查找文件的方法绑定
-    for i in 0..6 {
-        short_name.push((name[i] as char).to_ascii_uppercase())
-    }
-    short_name.push('~');
-    short_name.push('1');
-    let ext_len = extension.len();
-    for i in 0..3 {
-        // fill extension
-        if i >= ext_len {
-            short_name.push(0x20 as char); // 不足的用 0x20 进行填充
-        } else {
-            short_name.push((extension[i] as char).to_ascii_uppercase());
-        }
+    generate_short_name_n(long_name, 1)
+}
+
+/// Short name alias for `long_name` with the numeric tail `~n`, e.g. `LONGNA~1.TXT`
+///
+/// The basis is the part before the last `.`, without spaces and dots, upper-cased, with
+/// characters not allowed in short names replaced by `_`, and cut so that the basis and the tail
+/// fit in 8 characters. The extension is the first 3 characters after the last `.`.
+pub fn generate_short_name_n(long_name: &str, n: usize) -> String {
+    let (base, ext) = match long_name.rfind('.') {
+        Some(i) if i > 0 => (&long_name[..i], &long_name[i + 1..]),
+        _ => (long_name, ""),
+    };
+    let tail = alloc::format!("~{}", n);
+    let basis_len = 8usize.saturating_sub(tail.len());
+    let mut short_name: String = base
+        .chars()
+        .filter(|&c| c != ' ' && c != '.')
+        .map(short_name_char)
+        .take(basis_len)
+        .collect();
+    short_name.push_str(&tail);
+    let ext: String = ext
+        .chars()
+        .filter(|&c| c != ' ')
+        .map(short_name_char)
+        .take(3)
+        .collect();
+    if !ext.is_empty() {
+        short_name.push('.');
+        short_name.push_str(&ext);
     }
-    // 返回一个长度为 11 的string数组
     short_name
 }
 
+/// Checksum of an 11-byte 8.3 name (as stored in a short entry, space padded, no dot), which
+/// every long entry of the set records so an orphaned LFN run can be detected.
+///
+/// ```
+/// use fat32::lfn_checksum;
+///
+/// assert_eq!(lfn_checksum(b"README  TXT"), 0x73);
+/// assert_eq!(lfn_checksum(b"LONGNA~1TXT"), 0xF4);
+/// assert_eq!(lfn_checksum(b"ARCHIV~1GZ "), 0x11);
+/// ```
+pub fn lfn_checksum(name_8_3: &[u8; 11]) -> u8 {
+    // 每一步循环右移一位再加上下一个字节
+    name_8_3
+        .iter()
+        .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+// 短文件名中不允许的字符替换为 '_'
+fn short_name_char(c: char) -> char {
+    if c.is_ascii_alphanumeric() || "$%'-_@~`!(){}^#&".contains(c) {
+        c.to_ascii_uppercase()
+    } else {
+        '_'
+    }
+}
+
 // TODO
 // 1. 修改文件名
 // 2. 时间处理
-// 3. 长短名转化(~n)(目前只有~1)
-// 4. 虽然罗列了很多错误类型, 但是目前仅判断与处理了部分错误
-// 5. 提供更完善的错误信息以及错误处理
+// 3. 虽然罗列了很多错误类型, 但是目前仅判断与处理了部分错误
+// 4. 提供更完善的错误信息以及错误处理
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::LongDirEntry;
+
+    #[test]
+    fn le_readers_reject_short_input() {
+        assert_eq!(read_le_u16(&[0x34, 0x12, 0xFF]), Some(0x1234));
+        assert_eq!(read_le_u32(&[0x78, 0x56, 0x34, 0x12]), Some(0x1234_5678));
+        assert_eq!(read_le_u16(&[0x34]), None);
+        assert_eq!(read_le_u32(&[0x78, 0x56, 0x34]), None);
+        assert_eq!(read_le_u32(&[]), None);
+    }
+
+    #[test]
+    fn long_entry_keeps_at_most_13_chars() {
+        let lde = LongDirEntry::new(1, 0, "abcdefghijklmnopqrst");
+        assert_eq!(lde.name(), "abcdefghijklm");
+        let lde = LongDirEntry::new(1, 0, "short");
+        assert_eq!(lde.name(), "short");
+    }
+
+    #[test]
+    fn in_place_name_comparison_ignores_case() {
+        assert!(name_eq_ignore_case("Readme.TXT".chars(), "README.txt"));
+        assert!(name_eq_ignore_case("Äpfel".chars(), "äPFEL"));
+        assert!(!name_eq_ignore_case("readme".chars(), "readme.txt"));
+
+        // 各段按磁盘顺序 (最后一段在前) 给出
+        let name = "A Long Name Spanning Entries \u{1F600}.txt";
+        let mut parts = long_name_split(name);
+        parts.reverse();
+        assert!(lfn_name_eq_ignore_case(&parts, name));
+        assert!(lfn_name_eq_ignore_case(
+            &parts,
+            "a long name spanning entries \u{1F600}.TXT"
+        ));
+        assert!(!lfn_name_eq_ignore_case(
+            &parts,
+            "A Long Name Spanning Entries"
+        ));
+        assert_eq!(lfn_name_from_parts(&parts), name);
+    }
+}
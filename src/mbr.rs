@@ -0,0 +1,194 @@
+//! MBR 分区表
+//!
+//! 许多 SD 卡镜像的 0 号扇区是 MBR, FAT32 分区从某个 LBA 开始.
+//! 分区表位于 MBR 的 0x1BE 处, 共 4 个 16 字节的表项, 扇区以 0x55AA 结尾.
+//!
+//! 分区表项布局:
+//!
+//! | Offset | Size | Description          |
+//! |--------|------|----------------------|
+//! | 0x00   | 1    | Boot indicator       |
+//! | 0x01   | 3    | Starting CHS         |
+//! | 0x04   | 1    | Partition type       |
+//! | 0x05   | 3    | Ending CHS           |
+//! | 0x08   | 4    | Starting LBA         |
+//! | 0x0C   | 4    | Number of sectors    |
+
+use alloc::sync::Arc;
+use core::{
+    option::Option,
+    option::Option::{None, Some},
+};
+
+use super::{device::BlockDevice, read_le_u32, BLOCK_SIZE};
+
+pub const MBR_PARTITION_TABLE_OFFSET: usize = 0x1BE;
+pub const MBR_PARTITION_ENTRY_SIZE: usize = 16;
+pub const MBR_PARTITION_CNT: usize = 4;
+pub const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+
+// FAT32 分区类型: 0x0B (CHS), 0x0C (LBA)
+pub const PARTITION_TYPE_FAT32_CHS: u8 = 0x0B;
+pub const PARTITION_TYPE_FAT32_LBA: u8 = 0x0C;
+
+/// One entry of the MBR partition table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MbrPartition {
+    pub boot_indicator: u8,
+    pub partition_type: u8,
+    pub start_lba: u32,
+    pub sector_cnt: u32,
+}
+
+impl MbrPartition {
+    pub fn is_fat32(&self) -> bool {
+        self.partition_type == PARTITION_TYPE_FAT32_CHS
+            || self.partition_type == PARTITION_TYPE_FAT32_LBA
+    }
+
+    /// Parse the partition table of an MBR sector. Returns `None` if the sector does not end
+    /// with the 0x55AA signature; unused entries (type 0) are `None`.
+    pub fn parse(sector: &[u8; BLOCK_SIZE]) -> Option<[Option<MbrPartition>; MBR_PARTITION_CNT]> {
+        if sector[510..512] != MBR_SIGNATURE {
+            return None;
+        }
+        let mut table = [None; MBR_PARTITION_CNT];
+        for (i, part) in table.iter_mut().enumerate() {
+            let start = MBR_PARTITION_TABLE_OFFSET + i * MBR_PARTITION_ENTRY_SIZE;
+            let entry = &sector[start..start + MBR_PARTITION_ENTRY_SIZE];
+            if entry[0x04] == 0 {
+                continue;
+            }
+            *part = Some(MbrPartition {
+                boot_indicator: entry[0x00],
+                partition_type: entry[0x04],
+                start_lba: read_le_u32(&entry[0x08..])?,
+                sector_cnt: read_le_u32(&entry[0x0C..])?,
+            });
+        }
+        Some(table)
+    }
+
+    /// Read the partition table from sector 0 of `device`.
+    ///
+    /// 直接读取设备而不经过块缓存, 避免缓存中留下绝对编号的 0 号块
+    pub fn read_table(
+        device: &Arc<dyn BlockDevice>,
+    ) -> Option<[Option<MbrPartition>; MBR_PARTITION_CNT]> {
+        let mut sector = [0u8; BLOCK_SIZE];
+        if device.read_blocks(&mut sector, 0, 1).is_err() {
+            return None;
+        }
+        Self::parse(&sector)
+    }
+
+    /// The first FAT32 (type 0x0B / 0x0C) partition on `device`
+    pub fn first_fat32(device: &Arc<dyn BlockDevice>) -> Option<MbrPartition> {
+        Self::read_table(device)?
+            .iter()
+            .flatten()
+            .find(|part| part.is_fat32())
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::{MemBlockDevice, OffsetDevice},
+        test_util::{pattern, read_all, CacheGuard},
+        Dir, FileSystem, FsError, VirtFileType, BLOCK_NUM,
+    };
+
+    const PART_START: usize = 2048;
+
+    // 0 号扇区为 MBR, 第 index 个表项指向从 PART_START 开始的 FAT32 分区
+    fn partitioned_image(index: usize, data: &[u8]) -> Arc<MemBlockDevice> {
+        let mem = Arc::new(MemBlockDevice::new(PART_START + BLOCK_NUM as usize));
+        let fs = FileSystem::create(Arc::new(OffsetDevice::new(mem.clone(), PART_START)));
+        let file = fs
+            .read()
+            .root()
+            .create("part.bin", VirtFileType::File)
+            .unwrap();
+        assert_eq!(file.write_at(0, data), data.len());
+        drop(file);
+        drop(fs);
+
+        let mut mbr = [0u8; BLOCK_SIZE];
+        let entry = MBR_PARTITION_TABLE_OFFSET + index * MBR_PARTITION_ENTRY_SIZE;
+        mbr[entry + 0x04] = PARTITION_TYPE_FAT32_LBA;
+        mbr[entry + 0x08..entry + 0x0C].copy_from_slice(&(PART_START as u32).to_le_bytes());
+        mbr[entry + 0x0C..entry + 0x10].copy_from_slice(&BLOCK_NUM.to_le_bytes());
+        mbr[510..512].copy_from_slice(&MBR_SIGNATURE);
+        mem.write_blocks(&mbr, 0, 1).unwrap();
+        mem
+    }
+
+    #[test]
+    fn parse_partition_table() {
+        let _cache = CacheGuard::shared();
+        let mem: Arc<dyn BlockDevice> = partitioned_image(1, b"x");
+        let table = MbrPartition::read_table(&mem).unwrap();
+        assert_eq!(table[0], None);
+        assert_eq!(
+            table[1],
+            Some(MbrPartition {
+                boot_indicator: 0,
+                partition_type: PARTITION_TYPE_FAT32_LBA,
+                start_lba: PART_START as u32,
+                sector_cnt: BLOCK_NUM,
+            })
+        );
+        assert_eq!(MbrPartition::first_fat32(&mem), table[1]);
+    }
+
+    #[test]
+    fn open_partition_by_index() {
+        let _cache = CacheGuard::shared();
+        let data = pattern(3000, 13);
+        let mem = partitioned_image(1, &data);
+
+        let fs = FileSystem::open_partition(mem.clone(), 1).unwrap();
+        let file = fs.read().root().find(vec!["part.bin"]).unwrap();
+        assert_eq!(read_all(&file), data);
+        drop(file);
+        drop(fs);
+
+        // 空表项
+        assert_eq!(
+            FileSystem::open_partition(mem.clone(), 0).err(),
+            Some(FsError::PartitionNotFound)
+        );
+        assert_eq!(
+            FileSystem::open_partition(mem.clone(), MBR_PARTITION_CNT).err(),
+            Some(FsError::PartitionNotFound)
+        );
+    }
+
+    #[test]
+    fn open_partition_requires_mbr_and_fat32_type() {
+        let _cache = CacheGuard::shared();
+        let mem = partitioned_image(0, b"x");
+        let mut mbr = [0u8; BLOCK_SIZE];
+        mem.read_blocks(&mut mbr, 0, 1).unwrap();
+
+        // 非 FAT32 分区类型 (0x83, Linux)
+        mbr[MBR_PARTITION_TABLE_OFFSET + 0x04] = 0x83;
+        mem.write_blocks(&mbr, 0, 1).unwrap();
+        assert_eq!(
+            FileSystem::open_partition(mem.clone(), 0).err(),
+            Some(FsError::PartitionNotFound)
+        );
+
+        // 没有 0x55AA 签名
+        mbr[MBR_PARTITION_TABLE_OFFSET + 0x04] = PARTITION_TYPE_FAT32_LBA;
+        mbr[510..512].fill(0);
+        mem.write_blocks(&mbr, 0, 1).unwrap();
+        assert_eq!(
+            FileSystem::open_partition(mem, 0).err(),
+            Some(FsError::PartitionNotFound)
+        );
+    }
+}
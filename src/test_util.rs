@@ -0,0 +1,121 @@
+//! 单元测试共用的工具: 在内存块设备上格式化的文件系统
+
+// 并非每个测试模块都用到全部工具
+#![allow(dead_code)]
+
+use alloc::sync::Arc;
+use spin::RwLock;
+use std::sync::{RwLock as StdRwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::{
+    device::{BlockDevice, MemBlockDevice},
+    dir::Dir,
+    fs::{FileSystem, FormatOptions},
+    vfs::{VirtFile, VirtFileType},
+    BLOCK_NUM,
+};
+
+// 块缓存是全局的, 并行运行的测试会互相挤出缓存中的块.
+// 依赖缓存内容的测试 (如统计设备读次数) 独占缓存, 其余测试共享
+static CACHE_LOCK: StdRwLock<()> = StdRwLock::new(());
+
+pub(crate) enum CacheGuard {
+    Shared(RwLockReadGuard<'static, ()>),
+    Exclusive(RwLockWriteGuard<'static, ()>),
+}
+
+impl CacheGuard {
+    // 其他测试 panic 不影响本测试.
+    // 不经过 TestFs 挂载文件系统的测试也需持有
+    pub(crate) fn shared() -> Self {
+        CacheGuard::Shared(CACHE_LOCK.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    fn exclusive() -> Self {
+        CacheGuard::Exclusive(CACHE_LOCK.write().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+pub(crate) fn mem_device() -> Arc<MemBlockDevice> {
+    Arc::new(MemBlockDevice::new(BLOCK_NUM as usize))
+}
+
+/// A file system formatted on a [`MemBlockDevice`]
+pub(crate) struct TestFs {
+    pub fs: Arc<RwLock<FileSystem>>,
+    pub device: Arc<MemBlockDevice>,
+    cache: CacheGuard,
+}
+
+impl TestFs {
+    pub fn new() -> Self {
+        Self::with_options(FormatOptions::default())
+    }
+
+    pub fn with_options(options: FormatOptions) -> Self {
+        Self::format(options, CacheGuard::shared())
+    }
+
+    /// Like [`TestFs::new`], but no other test touches the block cache while this one is alive
+    pub fn exclusive() -> Self {
+        Self::format(FormatOptions::default(), CacheGuard::exclusive())
+    }
+
+    fn format(options: FormatOptions, cache: CacheGuard) -> Self {
+        let device = mem_device();
+        let fs = FileSystem::create_with(device.clone(), options);
+        Self { fs, device, cache }
+    }
+
+    /// Mount an image formatted by an earlier `TestFs`
+    pub fn open(device: Arc<MemBlockDevice>) -> Self {
+        let fs = FileSystem::open(device.clone() as Arc<dyn BlockDevice>);
+        Self {
+            fs,
+            device,
+            cache: CacheGuard::shared(),
+        }
+    }
+
+    /// Unmount (every handle must have been dropped) and open the image again
+    pub fn reopen(self) -> Self {
+        let Self { fs, device, cache } = self;
+        assert_eq!(Arc::strong_count(&fs), 1, "file system still in use");
+        drop(fs);
+        let fs = FileSystem::open(device.clone() as Arc<dyn BlockDevice>);
+        Self { fs, device, cache }
+    }
+
+    pub fn root(&self) -> Arc<VirtFile> {
+        self.fs.read().root()
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        self.fs.read().cluster_size()
+    }
+
+    pub fn free_cluster_cnt(&self) -> usize {
+        self.fs.read().free_cluster_cnt()
+    }
+
+    /// Create `name` in the root directory holding `data`
+    pub fn create_file(&self, name: &str, data: &[u8]) -> VirtFile {
+        let file = self.root().create(name, VirtFileType::File).unwrap();
+        assert_eq!(file.write_at(0, data), data.len());
+        file
+    }
+}
+
+/// `len` bytes of a recognizable pattern, different for each `seed`
+pub(crate) fn pattern(len: usize, seed: u8) -> alloc::vec::Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+        .collect()
+}
+
+/// Whole content of a file, through `read_at`
+pub(crate) fn read_all(file: &VirtFile) -> alloc::vec::Vec<u8> {
+    let mut buf = alloc::vec![0u8; file.file_size()];
+    assert_eq!(file.read_at(0, &mut buf), buf.len());
+    buf
+}
@@ -1,8 +1,10 @@
-use alloc::{string::String, sync::Arc, vec::Vec};
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
 use core::{
     assert, assert_ne,
     clone::Clone,
-    ops::FnOnce,
+    hash::Hasher,
+    iter,
+    ops::{FnOnce, Range},
     option::Option,
     option::Option::{None, Some},
     todo,
@@ -10,22 +12,34 @@ use core::{
 use spin::RwLock;
 
 use super::{
-    cache::{get_block_cache, Cache},
+    cache::{get_block_cache, try_get_block_cache, Cache},
+    device::{BlockDevice, DeviceErr},
     entry::{LongDirEntry, ShortDirEntry},
     fat::ClusterChain,
+    file::FileError,
     fs::FileSystem,
-    ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_LONG_NAME, BLOCK_SIZE, DIRENT_SIZE, END_OF_CLUSTER,
-    NEW_VIR_FILE_CLUSTER, ROOT_DIR_ENTRY_CLUSTER,
+    Crc32, ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_HIDDEN, ATTR_LONG_NAME, ATTR_READ_ONLY, ATTR_SYSTEM,
+    ATTR_VOLUME_ID, BLOCK_CACHE_LIMIT, BLOCK_SIZE, DETACHED_DIR_ENTRY_CLUSTER, DIRENT_SIZE,
+    END_OF_CLUSTER, MAX_FILE_SIZE, NEW_VIR_FILE_CLUSTER, ROOT_DIR_ENTRY_CLUSTER, ROOT_INODE,
 };
 
+/// One cluster's worth of file data, as yielded by [`VirtFile::read_clusters`]
+pub type ClusterBuf = Vec<u8>;
+
 #[derive(Clone)]
 pub struct VirtFile {
     pub(crate) name: String,
     pub(crate) sde_pos: DirEntryPos,
     pub(crate) lde_pos: Vec<DirEntryPos>,
     pub(crate) fs: Arc<RwLock<FileSystem>>,
+    // 已挂载文件系统的块设备不会改变, 缓存以免每次读写都获取 fs 的锁
+    pub(crate) device: Arc<dyn BlockDevice>,
     pub(crate) cluster_chain: Arc<RwLock<ClusterChain>>,
     pub(crate) attr: VirtFileType,
+    // 通过首簇号打开的文件没有目录项, 其短目录项仅保存在内存中
+    pub(crate) detached_sde: Option<Arc<RwLock<ShortDirEntry>>>,
+    // 有目录项的普通文件在句柄存活期间持有其文件锁, 锁的强引用计数即为打开的句柄数
+    pub(crate) open_lock: Option<Arc<RwLock<()>>>,
 }
 
 pub fn root(fs: Arc<RwLock<FileSystem>>) -> VirtFile {
@@ -37,7 +51,7 @@ pub fn root(fs: Arc<RwLock<FileSystem>>) -> VirtFile {
     let cluster_chain = Arc::new(RwLock::new(ClusterChain::new(
         root_dir_cluster as u32,
         Arc::clone(&device),
-        fs.read().bpb.fat1_offset(),
+        fs.read().bpb.active_fat_offset(),
     )));
 
     VirtFile::new(
@@ -60,7 +74,7 @@ pub enum VirtFileType {
     File = ATTR_ARCHIVE,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct DirEntryPos {
     pub(crate) cluster: u32,
     pub(crate) offset_in_cluster: usize,
@@ -73,6 +87,79 @@ impl DirEntryPos {
             offset_in_cluster,
         }
     }
+
+    // 高 32 位为目录项所在簇号, 低 32 位为簇内偏移
+    pub(crate) fn inode(&self) -> u64 {
+        ((self.cluster as u64) << 32) | self.offset_in_cluster as u64
+    }
+}
+
+/// Type of a directory entry, with the values of the POSIX `d_type` field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DType {
+    /// DT_UNKNOWN, the volume label has no POSIX equivalent
+    Volume = 0,
+    /// DT_DIR
+    Dir = 4,
+    /// DT_REG
+    File = 8,
+}
+
+impl DType {
+    pub fn from_attr(attr: u8) -> Self {
+        if attr & ATTR_VOLUME_ID != 0 {
+            DType::Volume
+        } else if attr & ATTR_DIRECTORY != 0 {
+            DType::Dir
+        } else {
+            DType::File
+        }
+    }
+}
+
+/// A directory entry as returned by [`VirtFile::read_dir_from`], shaped for `getdents`.
+#[derive(Debug, Clone)]
+pub struct DirEnt {
+    pub name: String,
+    /// Offset in the directory file to resume reading from (the next entry)
+    pub offset: usize,
+    /// Same value as [`VirtFile::inode`] of the entry
+    pub inode: u64,
+    pub d_type: DType,
+}
+
+impl DirEnt {
+    /// Whether this is the "." entry of a subdirectory
+    pub fn is_dot_entry(&self) -> bool {
+        self.name == "."
+    }
+
+    /// Whether this is the ".." entry of a subdirectory
+    pub fn is_dotdot_entry(&self) -> bool {
+        self.name == ".."
+    }
+}
+
+/// Resume position for [`VirtFile::read_dir_at`], for paged directory reads.
+///
+/// The value is opaque; save it with [`DirCursor::to_raw`] and restore it with
+/// [`DirCursor::from_raw`]. A cursor stays usable after the directory is modified: reading
+/// resumes at the next complete entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DirCursor(u64);
+
+impl DirCursor {
+    /// The start of the directory
+    pub const START: DirCursor = DirCursor(0);
+
+    pub fn to_raw(self) -> u64 {
+        self.0
+    }
+
+    pub fn from_raw(raw: u64) -> Self {
+        Self(raw)
+    }
 }
 
 impl VirtFile {
@@ -84,28 +171,36 @@ impl VirtFile {
         cluster_chain: Arc<RwLock<ClusterChain>>,
         attr: VirtFileType,
     ) -> Self {
+        let device = fs.read().device();
+        let has_entry = sde_pos.cluster != ROOT_DIR_ENTRY_CLUSTER
+            && sde_pos.cluster != DETACHED_DIR_ENTRY_CLUSTER;
+        let open_lock =
+            (attr == VirtFileType::File && has_entry).then(|| fs.read().file_lock(sde_pos.inode()));
         Self {
             name,
             sde_pos,
             lde_pos,
             fs,
+            device,
             cluster_chain,
             attr,
+            detached_sde: None,
+            open_lock,
         }
     }
 
     // Dir Func
     /// 传入 sde 在目录文件中的偏移量, 进而计算出其所在的 block_id 和 offset_in_block, 进而得到 sde 对应文件的 first_cluster, 构造出 cluster_chain
     pub fn file_cluster_chain(&self, sde_offset: usize) -> ClusterChain {
-        let fat_offset = self.fs.read().bpb.fat1_offset();
+        let fat_offset = self.fs.read().bpb.active_fat_offset();
         let (block_id, offset_in_block) = self.offset_block_pos(sde_offset).unwrap();
 
-        let device = self.fs.read().device();
+        let device = Arc::clone(&self.device);
         let start_cluster: u32 = get_block_cache(block_id, device)
             .read()
             .read(offset_in_block, |sde: &ShortDirEntry| sde.first_cluster());
 
-        let device = self.fs.read().device();
+        let device = Arc::clone(&self.device);
         ClusterChain::new(start_cluster, device, fat_offset)
     }
 
@@ -113,13 +208,32 @@ impl VirtFile {
         self.name.as_str()
     }
 
+    /// Stable inode number, derived from the on-disk location of the short directory entry.
+    ///
+    /// The high 32 bits hold the cluster of the entry and the low 32 bits its offset in that cluster,
+    /// so the value is unique within the volume for as long as the entry is not moved.
+    /// Note that rename/move changes the inode, as it changes the entry location.
+    ///
+    /// - Root directory: [`ROOT_INODE`]
+    /// - Handles from [`FileSystem::file_from_cluster`]: derived from the first cluster instead,
+    ///   which does not match the inode of the same file opened by path
+    pub fn inode(&self) -> u64 {
+        if self.detached_sde.is_some() {
+            let first_cluster = self.cluster_chain.read().start_cluster;
+            return ((DETACHED_DIR_ENTRY_CLUSTER as u64) << 32) | first_cluster as u64;
+        }
+        if self.sde_pos.cluster == ROOT_DIR_ENTRY_CLUSTER {
+            return ROOT_INODE;
+        }
+        self.sde_pos.inode()
+    }
+
     pub fn sde_pos(&self) -> (usize, usize) {
         assert!(self.sde_pos.cluster < END_OF_CLUSTER);
         let cluster_id = self.sde_pos.cluster;
-        let cluster_offset = self.fs.read().bpb.offset(cluster_id);
-        let offset = self.sde_pos.offset_in_cluster + cluster_offset;
-        let offset_in_block = offset % BLOCK_SIZE;
-        let block_id = offset / BLOCK_SIZE;
+        let offset_in_cluster = self.sde_pos.offset_in_cluster;
+        let offset_in_block = offset_in_cluster % BLOCK_SIZE;
+        let block_id = self.fs.read().cluster_to_block(cluster_id) + offset_in_cluster / BLOCK_SIZE;
 
         (block_id, offset_in_block)
     }
@@ -127,15 +241,17 @@ impl VirtFile {
     pub fn lde_pos(&self, index: usize) -> (usize, usize) {
         assert!(self.lde_pos[index].cluster < END_OF_CLUSTER);
         let cluster_id = self.lde_pos[index].cluster;
-        let cluster_offset = self.fs.read().bpb.offset(cluster_id);
-        let offset = self.lde_pos[index].offset_in_cluster + cluster_offset;
-        let offset_in_block = offset % BLOCK_SIZE;
-        let block_id = offset / BLOCK_SIZE;
+        let offset_in_cluster = self.lde_pos[index].offset_in_cluster;
+        let offset_in_block = offset_in_cluster % BLOCK_SIZE;
+        let block_id = self.fs.read().cluster_to_block(cluster_id) + offset_in_cluster / BLOCK_SIZE;
 
         (block_id, offset_in_block)
     }
 
     pub fn read_sde<V>(&self, f: impl FnOnce(&ShortDirEntry) -> V) -> V {
+        if let Some(sde) = &self.detached_sde {
+            return f(&sde.read());
+        }
         // fat32 实际上不存在根目录的 sde, 故特殊处理
         if self.sde_pos.cluster == ROOT_DIR_ENTRY_CLUSTER {
             let root_dir_entry = self.fs.read().root_dir_entry();
@@ -145,13 +261,16 @@ impl VirtFile {
 
         let (block_id, offset_in_block) = self.sde_pos();
 
-        let device = self.fs.read().device();
+        let device = Arc::clone(&self.device);
         get_block_cache(block_id, device)
             .read()
             .read(offset_in_block, f)
     }
 
     pub fn modify_sde<V>(&self, f: impl FnOnce(&mut ShortDirEntry) -> V) -> V {
+        if let Some(sde) = &self.detached_sde {
+            return f(&mut sde.write());
+        }
         // fat32 实际上不存在根目录的 sde, 故特殊处理
         if self.sde_pos.cluster == ROOT_DIR_ENTRY_CLUSTER {
             let root_dir_entry = self.fs.read().root_dir_entry();
@@ -161,7 +280,7 @@ impl VirtFile {
 
         let (block_id, offset_in_block) = self.sde_pos();
 
-        let device = self.fs.read().device();
+        let device = Arc::clone(&self.device);
         get_block_cache(block_id, device)
             .write()
             .modify(offset_in_block, f)
@@ -170,7 +289,7 @@ impl VirtFile {
     pub fn read_lde<V>(&self, index: usize, f: impl FnOnce(&LongDirEntry) -> V) -> V {
         let (block_id, offset_in_block) = self.lde_pos(index);
 
-        let device = self.fs.read().device();
+        let device = Arc::clone(&self.device);
         get_block_cache(block_id, device)
             .read()
             .read(offset_in_block, f)
@@ -179,16 +298,87 @@ impl VirtFile {
     pub fn modify_lde<V>(&self, index: usize, f: impl FnOnce(&mut LongDirEntry) -> V) -> V {
         let (block_id, offset_in_block) = self.lde_pos(index);
 
-        let device = self.fs.read().device();
+        let device = Arc::clone(&self.device);
         get_block_cache(block_id, device)
             .write()
             .modify(offset_in_block, f)
     }
 
+    // 读取目录文件 offset 处的长名目录项到 entry (经由字节数组解码), 返回读取的字节数
+    pub(crate) fn read_lde_at(&self, offset: usize, entry: &mut LongDirEntry) -> usize {
+        let mut buf = [0u8; DIRENT_SIZE];
+        let read_size = self.read_at(offset, &mut buf);
+        *entry = LongDirEntry::from_bytes(&buf);
+        read_size
+    }
+
+    // 读取目录文件 offset 处的短目录项到 sde, 返回读取的字节数
+    pub(crate) fn read_sde_at(&self, offset: usize, sde: &mut ShortDirEntry) -> usize {
+        let mut buf = [0u8; DIRENT_SIZE];
+        let read_size = self.read_at(offset, &mut buf);
+        *sde = ShortDirEntry::from_bytes(&buf);
+        read_size
+    }
+
+    /// The raw bytes of this file's directory entries in on-disk order: the long entries from the
+    /// highest ordinal down, then the short entry. Empty for the root and for detached handles,
+    /// which have no on-disk entries.
+    pub fn raw_entries(&self) -> Vec<[u8; DIRENT_SIZE]> {
+        if self.detached_sde.is_some() || self.sde_pos.cluster == ROOT_DIR_ENTRY_CLUSTER {
+            return Vec::new();
+        }
+        let mut entries: Vec<[u8; DIRENT_SIZE]> = (0..self.lde_pos.len())
+            .map(|i| self.read_lde(i, |lde| lde.to_bytes_array()))
+            .collect();
+        entries.push(self.read_sde(|sde| sde.to_bytes_array()));
+        entries
+    }
+
     pub fn file_size(&self) -> usize {
         self.read_sde(|sde| sde.file_size() as usize)
     }
 
+    /// Bytes actually allocated to the file ("size on disk"), i.e. the length of its cluster chain
+    /// times the cluster size. Distinct from the logical [`VirtFile::file_size`].
+    pub fn allocated_size(&self) -> usize {
+        let first_cluster = self.first_cluster() as u32;
+        // 空文件没有分配簇
+        if first_cluster == NEW_VIR_FILE_CLUSTER {
+            return 0;
+        }
+        let cluster_size = self.fs.read().cluster_size();
+        let cluster_cnt = self.fs.read().fat.read().cluster_chain_len(first_cluster) as usize;
+        cluster_cnt * cluster_size
+    }
+
+    // 可读写的范围: fat32 规定目录文件大小为 0, 目录以已分配大小为准
+    fn logical_size(&self) -> usize {
+        if self.is_dir() {
+            self.allocated_size()
+        } else {
+            self.file_size()
+        }
+    }
+
+    /// Byte ranges of the volume occupied by the file's clusters, one per run of consecutive
+    /// clusters (see [`FileSystem::chain_extents`]), in chain order. Covers whole clusters, so
+    /// the total may exceed `file_size`; directory entries are not included.
+    pub fn data_byte_ranges(&self) -> Vec<Range<usize>> {
+        let first_cluster = self.first_cluster() as u32;
+        if first_cluster == NEW_VIR_FILE_CLUSTER {
+            return Vec::new();
+        }
+        let fs = self.fs.read();
+        let cluster_size = fs.cluster_size();
+        fs.chain_extents(first_cluster)
+            .into_iter()
+            .map(|(start, count)| {
+                let offset = fs.cluster_offset(start);
+                offset..offset + count as usize * cluster_size
+            })
+            .collect()
+    }
+
     pub fn is_dir(&self) -> bool {
         self.attr == VirtFileType::Dir
     }
@@ -197,53 +387,95 @@ impl VirtFile {
         self.attr == VirtFileType::File
     }
 
-    /// 给出目录项 (sde/lde) 在目录文件中的偏移, 返回其在磁盘中的位置 (block_id, offset_in_block)
-    pub fn offset_block_pos(&self, offset: usize) -> Option<(usize, usize)> {
-        // fat32 规定目录文件大小为 0
-        // if offset > self.file_size() {
-        //     return None;
-        // }
+    pub fn is_readonly(&self) -> bool {
+        self.has_attr(ATTR_READ_ONLY)
+    }
 
-        let cluster_size = self.fs.read().cluster_size();
-        let cluster_index = offset / cluster_size;
-        let offset_in_cluster = offset % cluster_size;
+    pub fn is_hidden(&self) -> bool {
+        self.has_attr(ATTR_HIDDEN)
+    }
 
-        let start_cluster = self.first_cluster();
-        let cluster = self
-            .fs
-            .read()
-            .fat
-            .read()
-            .get_cluster_at(start_cluster as u32, cluster_index as u32)
-            .unwrap();
-        let offset_in_disk = self.fs.read().bpb.offset(cluster);
+    pub fn is_system(&self) -> bool {
+        self.has_attr(ATTR_SYSTEM)
+    }
 
-        let block_id = offset_in_disk / BLOCK_SIZE + offset_in_cluster / BLOCK_SIZE;
-        assert!(offset_in_disk % BLOCK_SIZE == 0);
-        let offset_in_block = offset_in_cluster % BLOCK_SIZE;
+    pub fn is_archive(&self) -> bool {
+        self.has_attr(ATTR_ARCHIVE)
+    }
+
+    /// Case flags of the short name (the DIR_NTRes byte), see [`ShortDirEntry::nt_reserved`]
+    pub fn name_case_flags(&self) -> u8 {
+        self.read_sde(|sde| sde.nt_reserved())
+    }
+
+    /// Set or clear ATTR_READ_ONLY, leaving the other attribute bits unchanged
+    pub fn set_readonly(&self, on: bool) {
+        self.set_attr_bit(ATTR_READ_ONLY, on);
+    }
+
+    /// Set or clear ATTR_HIDDEN, leaving the other attribute bits unchanged
+    pub fn set_hidden(&self, on: bool) {
+        self.set_attr_bit(ATTR_HIDDEN, on);
+    }
+
+    /// Set or clear ATTR_SYSTEM, leaving the other attribute bits unchanged
+    pub fn set_system(&self, on: bool) {
+        self.set_attr_bit(ATTR_SYSTEM, on);
+    }
+
+    /// Set or clear ATTR_ARCHIVE, leaving the other attribute bits unchanged
+    pub fn set_archive(&self, on: bool) {
+        self.set_attr_bit(ATTR_ARCHIVE, on);
+    }
+
+    fn has_attr(&self, bit: u8) -> bool {
+        self.read_sde(|sde| sde.attr() & bit != 0)
+    }
+
+    // 只修改短目录项属性中的 bit 位, 其余属性位不变
+    fn set_attr_bit(&self, bit: u8, on: bool) {
+        self.modify_sde(|sde| {
+            let attr = sde.attr();
+            sde.set_attr(if on { attr | bit } else { attr & !bit });
+        });
+    }
+
+    /// 给出目录项 (sde/lde) 在目录文件中的偏移, 返回其在磁盘中的位置 (block_id, offset_in_block)
+    /// 超出目录已分配的簇时返回 None
+    pub fn offset_block_pos(&self, offset: usize) -> Option<(usize, usize)> {
+        self.block_for_offset(offset)
+    }
+
+    /// Disk location `(block_id, offset_in_block)` of byte `offset` of the file, following its
+    /// cluster chain. `None` past the allocated clusters (or if none are allocated yet).
+    pub fn block_for_offset(&self, offset: usize) -> Option<(usize, usize)> {
+        let pos = self.dir_entry_pos(offset)?;
+        let block_id =
+            self.fs.read().cluster_to_block(pos.cluster) + pos.offset_in_cluster / BLOCK_SIZE;
+        let offset_in_block = pos.offset_in_cluster % BLOCK_SIZE;
 
         Some((block_id, offset_in_block))
     }
 
     /// 给出目录项 (sde/lde) 在目录文件中的偏移, 返回其在目录文件中的位置 (cluster_id, offset_in_cluster)
+    ///
+    /// 超出目录已分配的簇 (或目录尚未分配簇) 时返回 None
     pub fn dir_entry_pos(&self, offset: usize) -> Option<DirEntryPos> {
-        // fat32 规定目录文件大小为 0
-        // if offset > self.file_size() {
-        //     return None;
-        // }
-
+        // fat32 规定目录文件大小为 0, 以簇链长度为界
         let cluster_size = self.fs.read().cluster_size();
         let cluster_index = offset / cluster_size;
         let offset_in_cluster = offset % cluster_size;
 
-        let start_cluster = self.first_cluster();
+        let start_cluster = self.first_cluster() as u32;
+        if start_cluster == NEW_VIR_FILE_CLUSTER {
+            return None;
+        }
         let cluster = self
             .fs
             .read()
             .fat
             .read()
-            .get_cluster_at(start_cluster as u32, cluster_index as u32)
-            .unwrap();
+            .get_cluster_at(start_cluster, cluster_index as u32)?;
 
         Some(DirEntryPos::new(cluster, offset_in_cluster))
     }
@@ -260,259 +492,550 @@ impl VirtFile {
         self.read_sde(|sde| sde.first_cluster() as usize)
     }
 
-    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
-        let spc = self.fs.read().bpb.sectors_per_cluster();
-        let cluster_size = self.fs.read().cluster_size();
-
-        let mut index = offset;
+    /// Fill the whole `buf` from `offset`, like `std::io::Read::read_exact`.
+    ///
+    /// Returns `ReadOutOfBound` (reading nothing) if fewer than `buf.len()` bytes are available
+    /// past `offset`: the file size for files, the allocated size for directories.
+    pub fn read_exact_at(&self, offset: usize, buf: &mut [u8]) -> Result<(), FileError> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let size = self.logical_size();
+        if offset + buf.len() > size {
+            return Err(FileError::ReadOutOfBound);
+        }
+        if self.read_at(offset, buf) != buf.len() {
+            return Err(FileError::ReadOutOfBound);
+        }
+        Ok(())
+    }
 
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let lock = self.file_lock();
+        let _guard = lock.as_ref().map(|lock| lock.read());
         // fat32 规定目录文件大小为 0
         // let file_size = self.file_size();
         // let end = (offset + buf.len()).min(file_size);
         // if offset > file_size || buf.len() == 0 {return 0;}
 
-        let end = offset + buf.len();
-
-        if buf.len() == 0 {
-            return 0;
+        let device = Arc::clone(&self.device);
+        let update_atime = self.fs.read().update_atime();
+        let already_read = self.for_each_block_in_range(
+            offset,
+            buf.len(),
+            |block_id, offset_in_block, len, done| {
+                get_block_cache(block_id, Arc::clone(&device)).read().read(
+                    0,
+                    |cache: &[u8; BLOCK_SIZE]| {
+                        let dst = &mut buf[done..done + len];
+                        let src = &cache[offset_in_block..offset_in_block + len];
+                        dst.copy_from_slice(src);
+                    },
+                );
+            },
+        );
+
+        // 簇链比 file_size 短 (损坏的镜像) 时, 缺失部分填 0 而不是保留 buf 中原有的内容
+        if !self.is_dir() && already_read < buf.len() {
+            let hole_end = (offset + buf.len()).min(self.file_size());
+            if offset + already_read < hole_end {
+                buf[already_read..hole_end - offset].fill(0);
+            }
         }
 
-        let pre_cluster_cnt = offset / cluster_size;
-        let mut curr_cluster = self.first_cluster() as u32;
-
-        let mut clus_chain = self.cluster_chain.read().clone().next().unwrap();
-
-        assert_ne!(clus_chain.start_cluster, NEW_VIR_FILE_CLUSTER);
-
-        for _ in 0..pre_cluster_cnt {
-            if let Some(clus_chain) = clus_chain.next() {
-                // curr_cluster = self
-                //     .fs
-                //     .read()
-                //     .fat
-                //     .read()
-                //     .get_next_cluster(curr_cluster)
-                //     .unwrap();
-
-                // clus_chain = clus_chain.next().unwrap();
-                // assert_eq!(curr_cluster, clus_chain.current_cluster);
-                curr_cluster = clus_chain.current_cluster;
-            } else {
-                // 说明 offset 在最后一个簇的最后的位置
-                let first_cluster = self.first_cluster();
-                let clus_len = self
-                    .fs
-                    .read()
-                    .fat
-                    .read()
-                    .cluster_chain_len(first_cluster as u32);
-
-                assert!(offset == clus_len as usize * cluster_size);
-                return 0;
-            }
+        // 目录的读取多为内部查找, 不更新访问日期
+        if already_read > 0 && !self.is_dir() && update_atime {
+            self.touch_atime();
         }
 
-        let mut left = pre_cluster_cnt * cluster_size;
-        let mut right = left + BLOCK_SIZE;
-        let mut already_read = 0;
+        already_read
+    }
 
-        while index < end {
-            let cluster_offset_in_disk = self.fs.read().bpb.offset(curr_cluster);
+    /// Load the blocks covering `[offset, offset + len)` into the block cache ahead of a read,
+    /// so the following `read_at` on that region does not touch the device.
+    ///
+    /// To leave room for blocks still in use, at most half of the cache
+    /// ([`BLOCK_CACHE_LIMIT`] / 2 blocks) is filled; the region is cut at the end of the file
+    /// (or of the cluster chain). Returns how many bytes from `offset` were prefetched.
+    pub fn prefetch(&self, offset: usize, len: usize) -> Result<usize, FileError> {
+        if self.first_cluster() as u32 == NEW_VIR_FILE_CLUSTER {
+            return Ok(0);
+        }
+        let len = len.min(self.logical_size().saturating_sub(offset));
+        // 只预读到 BLOCK_CACHE_LIMIT / 2 个块为止, 以免挤出缓存中仍在使用的块,
+        // 也避免预读的块相互挤出
+        let max_blocks = BLOCK_CACHE_LIMIT / 2;
+        let mut blocks = Vec::new();
+        let mut covered = 0;
+        self.for_each_block_in_range(offset, len, |block_id, _, len, _| {
+            if blocks.len() < max_blocks {
+                blocks.push(block_id);
+                covered += len;
+            }
+        });
 
-            let start_block_id = cluster_offset_in_disk / BLOCK_SIZE;
+        for block_id in blocks {
+            try_get_block_cache(block_id, Arc::clone(&self.device))
+                .map_err(|_| FileError::ReadError)?;
+        }
+        Ok(covered)
+    }
 
-            for block_id in start_block_id..start_block_id + spc {
-                if index >= left && index < right && index < end {
-                    let offset_in_block = index - left;
-                    let len = (BLOCK_SIZE - offset_in_block).min(end - index);
+    // 同一文件所有句柄共享的锁 (见 FileSystem::file_lock); 目录不加锁, 返回 None
+    pub(crate) fn file_lock(&self) -> Option<Arc<RwLock<()>>> {
+        if self.is_dir() {
+            return None;
+        }
+        if let Some(lock) = &self.open_lock {
+            return Some(Arc::clone(lock));
+        }
+        Some(self.fs.read().file_lock(self.inode()))
+    }
 
-                    let device = self.fs.read().device();
-                    get_block_cache(block_id, device)
-                        .read()
-                        .read(0, |cache: &[u8; BLOCK_SIZE]| {
-                            let dst = &mut buf[already_read..already_read + len];
-                            let src = &cache[offset_in_block..offset_in_block + len];
-                            dst.copy_from_slice(src);
-                        });
+    /// Set the last-access date in the short entry to the file system clock's current date
+    pub fn touch_atime(&self) {
+        let (date, _) = self.fs.read().now();
+        self.modify_sde(|sde| sde.set_last_access_date(date));
+    }
 
-                    index += len;
-                    already_read += len;
+    pub fn last_access_date(&self) -> u16 {
+        self.read_sde(|sde| sde.last_access_date())
+    }
 
-                    if index >= end {
-                        break;
+    /// Stream the data one cluster at a time, starting at the `from_cluster_index`th cluster,
+    /// without buffering the whole file.
+    ///
+    /// Each item is an owned buffer of one cluster; for files the last one is cut to `file_size`.
+    /// Yields `BadClusterChain` (and then stops) if the chain ends before the data does, or
+    /// `ReadError` if the device fails.
+    pub fn read_clusters(
+        &self,
+        from_cluster_index: usize,
+    ) -> impl Iterator<Item = Result<ClusterBuf, FileError>> {
+        let size = self.logical_size();
+        let first_cluster = self.first_cluster() as u32;
+        let fs = Arc::clone(&self.fs);
+        let (cluster_size, mut chain, device) = {
+            let fs = fs.read();
+            (
+                fs.cluster_size(),
+                fs.cluster_chain(first_cluster).skip(from_cluster_index),
+                fs.device(),
+            )
+        };
+        let mut remaining = size.saturating_sub(from_cluster_index * cluster_size);
+
+        iter::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
+            let cluster = match chain.next() {
+                Some(cluster) => cluster,
+                None => {
+                    remaining = 0;
+                    return Some(Err(FileError::BadClusterChain));
+                }
+            };
+            let len = remaining.min(cluster_size);
+            let mut buf = vec![0u8; len];
+            let blocks = fs.read().cluster_blocks(cluster);
+            for (i, block_id) in blocks.enumerate() {
+                let start = i * BLOCK_SIZE;
+                if start >= len {
+                    break;
+                }
+                let end = (start + BLOCK_SIZE).min(len);
+                match try_get_block_cache(block_id, Arc::clone(&device)) {
+                    Ok(block_cache) => block_cache.read().read(0, |block: &[u8; BLOCK_SIZE]| {
+                        buf[start..end].copy_from_slice(&block[..end - start]);
+                    }),
+                    Err(_) => {
+                        remaining = 0;
+                        return Some(Err(FileError::ReadError));
                     }
                 }
-
-                left += BLOCK_SIZE;
-                right += BLOCK_SIZE;
             }
+            remaining -= len;
+            Some(Ok(buf))
+        })
+    }
 
-            if index >= end {
-                break;
-            }
+    /// Copy the file out through `sink`, one cluster at a time and bounded to `file_size`, so
+    /// only a single cluster is held in memory. Returns the number of bytes fed to `sink`.
+    ///
+    /// Files only (`Unsupported` for directories). Stops with `WriteError` when `sink` fails, or
+    /// with the error of [`VirtFile::read_clusters`] when reading fails.
+    pub fn export_to(
+        &self,
+        sink: &mut impl FnMut(&[u8]) -> Result<(), ()>,
+    ) -> Result<usize, FileError> {
+        if self.is_dir() {
+            return Err(FileError::Unsupported);
+        }
+        let mut exported = 0;
+        for buf in self.read_clusters(0) {
+            let buf = buf?;
+            sink(&buf).map_err(|_| FileError::WriteError)?;
+            exported += buf.len();
+        }
+        Ok(exported)
+    }
 
-            // curr_cluster = self
-            //     .fs
-            //     .read()
-            //     .fat
-            //     .read()
-            //     .get_cluster_at(curr_cluster, 1)
-            //     .unwrap();
+    /// Feed the whole content through `hasher`, one cluster at a time
+    pub fn digest(&self, hasher: &mut impl Hasher) -> Result<(), FileError> {
+        for buf in self.read_clusters(0) {
+            hasher.write(&buf?);
+        }
+        Ok(())
+    }
 
-            clus_chain = clus_chain.next().unwrap();
-            // assert_eq!(curr_cluster, clus_chain.current_cluster);
+    /// CRC-32 of the whole content, see [`Crc32`]
+    pub fn crc32(&self) -> Result<u32, FileError> {
+        let mut hasher = Crc32::new();
+        self.digest(&mut hasher)?;
+        Ok(hasher.value())
+    }
 
-            curr_cluster = clus_chain.current_cluster;
+    /// Write the whole `buf` at `offset`, or fail with `NoSpace` if the volume cannot hold it.
+    ///
+    /// On `NoSpace` the part that fits is still written and `file_size` covers exactly that part.
+    /// Fails with `FileTooLarge` (writing nothing) if the file would grow past [`MAX_FILE_SIZE`].
+    pub fn write_all_at(&self, offset: usize, buf: &[u8]) -> Result<(), FileError> {
+        if buf.is_empty() {
+            return Ok(());
         }
-
-        already_read
+        if offset + buf.len() > MAX_FILE_SIZE {
+            return Err(FileError::FileTooLarge);
+        }
+        let cluster_size = self.fs.read().cluster_size();
+        // 已分配的空间加上剩余的空闲簇
+        let capacity = self.allocated_size() + self.fs.read().free_cluster_cnt() * cluster_size;
+        if offset + buf.len() <= capacity {
+            if self.write_at(offset, buf) != buf.len() {
+                return Err(FileError::WriteError);
+            }
+            return Ok(());
+        }
+        // 空间不足, 写入能容纳的部分
+        if offset < capacity {
+            self.write_at(offset, &buf[..capacity - offset]);
+        }
+        Err(FileError::NoSpace)
     }
 
-    pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
-        let spc = self.fs.read().bpb.sectors_per_cluster();
+    /// Write the bytes produced by `iter` starting at `offset`, growing the file as needed,
+    /// without collecting them into one buffer first. Returns the number of bytes written.
+    ///
+    /// Bytes are buffered one cluster at a time. Fails with `NoSpace` when the volume fills up,
+    /// after writing what fits.
+    pub fn write_from_iter(
+        &self,
+        offset: usize,
+        iter: impl Iterator<Item = u8>,
+    ) -> Result<usize, FileError> {
         let cluster_size = self.fs.read().cluster_size();
+        let mut iter = iter.peekable();
+        let mut pos = offset;
+        // 第一块只写到簇边界, 之后每次写入整簇
+        let mut chunk_len = cluster_size - offset % cluster_size;
+        let mut chunk: Vec<u8> = Vec::with_capacity(cluster_size);
+        while iter.peek().is_some() {
+            chunk.clear();
+            chunk.extend(iter.by_ref().take(chunk_len));
+            self.write_all_at(pos, &chunk)?;
+            pos += chunk.len();
+            chunk_len = cluster_size;
+        }
+        Ok(pos - offset)
+    }
 
+    /// Write `buf` at `offset`, growing the file as needed. Returns the number of bytes written.
+    ///
+    /// Nothing is written if the file would grow past [`MAX_FILE_SIZE`]; use
+    /// [`VirtFile::write_all_at`] to get a `FileTooLarge` error instead.
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
         if buf.len() == 0 {
             return 0;
         }
+        // file_size 只有 32 位, 超出时不写入, 以免 file_size 回绕
+        if offset + buf.len() > MAX_FILE_SIZE {
+            return 0;
+        }
 
-        let mut index = offset;
-        let end = offset + buf.len();
-
+        let lock = self.file_lock();
+        let _guard = lock.as_ref().map(|lock| lock.write());
         let new_size = offset + buf.len();
 
         // TODO
         // self.modify_size(new_size);
-        self.incerase_size(new_size);
+        // 写入从已分配区域内 (或其末尾) 开始时, 新分配的簇会被本次写入覆盖, 无需预先清零;
+        // 目录依赖 0x00 目录项, 仍需清零
+        let overwrite_new_clusters = !self.is_dir() && offset <= self.allocated_size();
+        if self.grow_chain(new_size, !overwrite_new_clusters).is_err() {
+            return 0;
+        }
+        let written = self.write_allocated_at(offset, buf);
+        // 数据写入之后再更新 file_size
+        self.raise_file_size(offset + written);
+        written
+    }
 
+    /// Overwrite `buf.len()` bytes at `offset` inside the file without touching its size or
+    /// cluster chain, for fixed-size record files.
+    ///
+    /// Returns `ReadOutOfBound` (writing nothing) if the region goes past the file size (the
+    /// allocated size for directories).
+    pub fn write_in_bounds_at(&self, offset: usize, buf: &[u8]) -> Result<(), FileError> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let size = self.logical_size();
+        if offset + buf.len() > size {
+            return Err(FileError::ReadOutOfBound);
+        }
+        let lock = self.file_lock();
+        let _guard = lock.as_ref().map(|lock| lock.write());
+        self.write_allocated_at(offset, buf);
+        Ok(())
+    }
+
+    // 写入已分配的区域, 调用者需保证 [offset, offset + buf.len()) 在簇链范围内.
+    // 按规范, 文件被写入后设置 ATTR_ARCHIVE, 以便备份工具发现改动
+    fn write_allocated_at(&self, offset: usize, buf: &[u8]) -> usize {
+        let device = Arc::clone(&self.device);
+        let written = self.for_each_block_in_range(
+            offset,
+            buf.len(),
+            |block_id, offset_in_block, len, done| {
+                get_block_cache(block_id, Arc::clone(&device))
+                    .write()
+                    .modify(0, |cache: &mut [u8; BLOCK_SIZE]| {
+                        let src = &buf[done..done + len];
+                        let dst = &mut cache[offset_in_block..offset_in_block + len];
+                        dst.copy_from_slice(src);
+                    });
+            },
+        );
+        // 已设置时不再修改目录项, 避免每次写入都弄脏目录项所在的块
+        if written > 0 && !self.is_dir() && !self.is_archive() {
+            self.set_archive(true);
+        }
+        written
+    }
+
+    // 沿簇链按块遍历文件中 [offset, offset + len) 的区域, read_at 与 write_at 共用.
+    // 对每个块内的一段调用 f(block_id, offset_in_block, len, 之前各段的总长度), 返回遍历的总字节数.
+    // 区域超出簇链时在簇链末尾停止
+    fn for_each_block_in_range(
+        &self,
+        offset: usize,
+        len: usize,
+        mut f: impl FnMut(usize, usize, usize, usize),
+    ) -> usize {
+        if len == 0 {
+            return 0;
+        }
+        // 循环中不再获取 fs 的锁: 簇号到块号的换算只需要 BPB
+        let bpb = self.fs.read().bpb();
+        let cluster_size = bpb.cluster_size();
         let pre_cluster_cnt = offset / cluster_size;
 
         let mut clus_chain = self.cluster_chain.read().clone().next().unwrap();
+        assert_ne!(clus_chain.start_cluster, NEW_VIR_FILE_CLUSTER);
 
-        let mut curr_cluster = self.first_cluster() as u32;
+        let mut curr_cluster = clus_chain.current_cluster;
         for _ in 0..pre_cluster_cnt {
-            // curr_cluster = self
-            //     .fs
-            //     .read()
-            //     .fat
-            //     .read()
-            //     .get_next_cluster(curr_cluster)
-            //     .unwrap();
-
-            clus_chain = clus_chain.next().unwrap();
-            // assert_eq!(curr_cluster, clus_chain.current_cluster);
-
-            curr_cluster = clus_chain.current_cluster;
-        }
-
-        let mut left = pre_cluster_cnt * cluster_size;
-        let mut right = left + BLOCK_SIZE;
-        let mut already_write = 0;
-
-        while index < end {
-            let cluster_offset_in_disk = self.fs.read().bpb.offset(curr_cluster);
-            let start_block_id = cluster_offset_in_disk / BLOCK_SIZE;
-
-            for block_id in start_block_id..start_block_id + spc {
-                if index >= left && index < right && index < end {
-                    let offset_in_block = index - left;
-                    let len = (BLOCK_SIZE - offset_in_block).min(end - index);
-
-                    let device = self.fs.read().device();
-                    get_block_cache(block_id, device).write().modify(
-                        0,
-                        |cache: &mut [u8; BLOCK_SIZE]| {
-                            let src = &buf[already_write..already_write + len];
-                            let dst = &mut cache[offset_in_block..offset_in_block + len];
-                            dst.copy_from_slice(src);
-                        },
-                    );
-
-                    index += len;
-                    already_write += len;
-
-                    if index >= end {
-                        break;
-                    }
-                }
-
-                left += BLOCK_SIZE;
-                right += BLOCK_SIZE;
+            match clus_chain.next() {
+                Some(next) => curr_cluster = next.current_cluster,
+                // offset 在簇链末尾或之后 (簇链比 file_size 短)
+                None => return 0,
             }
+        }
 
+        let end = offset + len;
+        let mut index = offset;
+        let mut done = 0;
+        loop {
+            let first_block = bpb.offset(curr_cluster) / BLOCK_SIZE;
+            // 当前簇在文件中的起始偏移
+            let cluster_start = index / cluster_size * cluster_size;
+            while index < end && index < cluster_start + cluster_size {
+                let offset_in_cluster = index - cluster_start;
+                let offset_in_block = offset_in_cluster % BLOCK_SIZE;
+                let len = (BLOCK_SIZE - offset_in_block).min(end - index);
+                f(
+                    first_block + offset_in_cluster / BLOCK_SIZE,
+                    offset_in_block,
+                    len,
+                    done,
+                );
+                index += len;
+                done += len;
+            }
             if index >= end {
-                break;
+                return done;
+            }
+            // 簇链提前结束时只返回已遍历的部分
+            match clus_chain.next() {
+                Some(next) => curr_cluster = next.current_cluster,
+                None => return done,
             }
-
-            // curr_cluster = self
-            //     .fs
-            //     .read()
-            //     .fat
-            //     .read()
-            //     .get_cluster_at(curr_cluster, 1)
-            //     .unwrap();
-
-            clus_chain = clus_chain.next().unwrap();
-            // assert_eq!(curr_cluster, clus_chain.current_cluster);
-            curr_cluster = clus_chain.current_cluster;
         }
+    }
 
-        already_write
+    pub(crate) fn incerase_size(&self, new_size: usize) -> Result<(), FileError> {
+        self.incerase_size_with(new_size, true)
     }
 
-    fn incerase_size(&self, new_size: usize) {
-        let first_cluster = self.first_cluster() as u32;
-        // fat32 规定目录文件的大小为 0
-        let old_size = self.file_size();
-        if new_size <= old_size {
-            return;
-        }
+    // 扩大文件到 new_size, 顺序固定为:
+    // 1. 分配新簇 (清零), 在 FAT 中链成以 EOC 结尾的链
+    // 2. 将新链接到簇链末尾 (空文件则写入目录项的首簇号)
+    // 3. 最后更新目录项中的 file_size (write_at 在 2 与 3 之间写入数据)
+    // 开启 ordered writes 时, 目录项被修改前 FAT 的改动已写回设备 (见 link_new_clusters),
+    // 断电后最多留下未被引用的簇 (可由 reclaim_lost 回收), 不会出现 file_size 超出簇链的情况.
+    // 空间不足或写回失败时不修改 file_size
+    fn incerase_size_with(&self, new_size: usize, clear: bool) -> Result<(), FileError> {
+        self.grow_chain(new_size, clear)?;
+        self.raise_file_size(new_size);
+        Ok(())
+    }
 
+    // 上述步骤 1, 2: 使簇链能容纳 new_size, 不修改 file_size
+    // clear 为 false 时新分配的簇不清零 (最后一个簇只写入一部分时仍会清零).
+    // 空闲簇不足时返回 NoSpace, 写回失败时返回 WriteError
+    fn grow_chain(&self, new_size: usize, clear: bool) -> Result<(), FileError> {
+        let first_cluster = self.first_cluster() as u32;
         let need_cluster_cnt = self
             .fs
             .read()
             .count_needed_clusters(new_size, first_cluster);
-
         if need_cluster_cnt == 0 {
-            // FIX fat32 规定目录文件的大小为 0
-            if !self.is_dir() {
-                self.modify_sde(|sde| {
-                    sde.set_file_size(new_size as u32);
-                });
-            }
-            return;
+            return Ok(());
         }
 
-        let option = self
-            .fs
-            .write()
-            .alloc_cluster(need_cluster_cnt, first_cluster);
+        match self.link_new_clusters(need_cluster_cnt, clear) {
+            Ok(true) => {}
+            Ok(false) => return Err(FileError::NoSpace),
+            Err(_) => return Err(FileError::WriteError),
+        }
+        let cluster_size = self.fs.read().cluster_size();
+        if !clear && !new_size.is_multiple_of(cluster_size) {
+            let first_cluster = self.first_cluster() as u32;
+            let last_cluster = self.fs.read().fat.read().cluster_chain_tail(first_cluster);
+            self.fs.read().clear_cluster(last_cluster);
+        }
+        Ok(())
+    }
 
-        if let Some(start_cluster) = option {
-            if first_cluster == NEW_VIR_FILE_CLUSTER {
-                self.cluster_chain.write().refresh(start_cluster);
+    // 上述步骤 3; fat32 规定目录文件的大小为 0
+    fn raise_file_size(&self, new_size: usize) {
+        if !self.is_dir() && new_size > self.file_size() {
+            self.modify_sde(|sde| {
+                sde.set_file_size(new_size as u32);
+            });
+        }
+    }
 
-                self.modify_sde(|sde| {
-                    sde.set_first_cluster(start_cluster);
-                });
-            } else {
-                let last_cluster = self.fs.read().fat.read().cluster_chain_tail(first_cluster);
-                assert_ne!(last_cluster, NEW_VIR_FILE_CLUSTER);
-                self.fs
-                    .write()
-                    .fat
-                    .write()
-                    .set_next_cluster(last_cluster, start_cluster);
-            }
+    // 分配 need_cluster_cnt 个簇并链接到簇链末尾, 空间不足返回 Ok(false)
+    // 修改目录项 (空文件的首簇号) 之前经过 write barrier, 写回失败时新簇不被引用
+    fn link_new_clusters(&self, need_cluster_cnt: usize, clear: bool) -> Result<bool, DeviceErr> {
+        let first_cluster = self.first_cluster() as u32;
+        let option = if clear {
+            self.fs
+                .write()
+                .alloc_cluster(need_cluster_cnt, first_cluster)
+        } else {
+            self.fs
+                .write()
+                .alloc_cluster_no_clear(need_cluster_cnt, first_cluster)
+        };
+        let start_cluster = match option {
+            Some(start_cluster) => start_cluster,
+            None => return Ok(false),
+        };
+
+        // 根目录在格式化时已分配首簇, 总是链接到簇链末尾, 不会修改其 (内存中的) 目录项
+        if first_cluster == NEW_VIR_FILE_CLUSTER {
+            self.fs.read().write_barrier()?;
+            self.cluster_chain.write().refresh(start_cluster);
 
-            if !self.is_dir() {
-                self.modify_sde(|sde| {
-                    sde.set_file_size(new_size as u32);
-                });
-            }
+            self.modify_sde(|sde| {
+                sde.set_first_cluster(start_cluster);
+            });
         } else {
-            panic!("Alloc Cluster Failed! Out of Space!");
+            let last_cluster = self.fs.read().fat.read().cluster_chain_tail(first_cluster);
+            assert_ne!(last_cluster, NEW_VIR_FILE_CLUSTER);
+            self.fs
+                .write()
+                .fat
+                .write()
+                .set_next_cluster(last_cluster, start_cluster);
+            // 之后的 file_size 更新依赖于此处的链接
+            self.fs.read().write_barrier()?;
         }
+        Ok(true)
+    }
+
+    /// Reserve clusters so the file can hold `size` bytes without allocating on later writes
+    /// (like `fallocate`). With `keep_size` the logical file size is left unchanged; otherwise
+    /// it is raised to `size`. Directories always keep a size of 0.
+    ///
+    /// Fails with `FileTooLarge` if `size` is over [`MAX_FILE_SIZE`], and with `NoSpace` (reserving
+    /// nothing) if the volume has too few free clusters.
+    pub fn preallocate(&self, size: usize, keep_size: bool) -> Result<(), FileError> {
+        if size > MAX_FILE_SIZE {
+            return Err(FileError::FileTooLarge);
+        }
+        let lock = self.file_lock();
+        let _guard = lock.as_ref().map(|lock| lock.write());
+        let first_cluster = self.first_cluster() as u32;
+        let need_cluster_cnt = self.fs.read().count_needed_clusters(size, first_cluster);
+
+        if need_cluster_cnt > 0 {
+            if need_cluster_cnt > self.fs.read().free_cluster_cnt() {
+                return Err(FileError::NoSpace);
+            }
+            match self.link_new_clusters(need_cluster_cnt, true) {
+                Ok(true) => {}
+                Ok(false) => return Err(FileError::NoSpace),
+                Err(_) => return Err(FileError::WriteError),
+            }
+        }
+
+        if !keep_size && !self.is_dir() && size > self.file_size() {
+            self.modify_sde(|sde| {
+                sde.set_file_size(size as u32);
+            });
+        }
+        Ok(())
+    }
+
+    /// Truncate a file to length 0, freeing all of its clusters. Returns the number of clusters
+    /// freed.
+    ///
+    /// Directories and handles without a directory entry (see [`FileSystem::file_from_cluster`])
+    /// are `Unsupported`.
+    pub fn truncate_to_zero(&self) -> Result<usize, FileError> {
+        if self.is_dir() || self.detached_sde.is_some() {
+            return Err(FileError::Unsupported);
+        }
+        let lock = self.file_lock();
+        let _guard = lock.as_ref().map(|lock| lock.write());
+        let first_cluster = self.first_cluster() as u32;
+        let clusters: Vec<u32> = if first_cluster == NEW_VIR_FILE_CLUSTER {
+            Vec::new()
+        } else {
+            self.fs.read().cluster_chain(first_cluster).collect()
+        };
+        // 与新建的空文件一致: 首簇号为 0, 大小为 0
+        self.modify_sde(|sde| {
+            sde.set_first_cluster(NEW_VIR_FILE_CLUSTER);
+            sde.set_file_size(0);
+        });
+        self.cluster_chain.write().refresh(NEW_VIR_FILE_CLUSTER);
+        let cluster_cnt = clusters.len();
+        self.fs.read().dealloc_cluster(clusters);
+        Ok(cluster_cnt)
     }
 
     #[allow(unused)]
@@ -523,7 +1046,7 @@ impl VirtFile {
 
         // 对于 目录文件 old_size = 0
         if new_size >= old_size {
-            self.incerase_size(new_size);
+            let _ = self.incerase_size(new_size);
         } else {
             let left = (new_size + cluster_size - 1) / cluster_size;
             let right = (old_size + cluster_size - 1) / cluster_size;
@@ -556,17 +1079,31 @@ impl VirtFile {
                 .get_cluster_at(first_cluster, left as u32 - 1)
                 .unwrap();
             assert!(last_clus >= 2);
-            self.fs
-                .write()
-                .fat
-                .write()
-                .set_next_cluster(last_clus, END_OF_CLUSTER);
+            self.fs.write().fat.write().set_end_of_chain(last_clus);
         }
     }
 
-    // 删除自身
-    pub fn clear(&self) -> usize {
+    // 删除自身, 返回释放的簇数. 没有目录项的句柄无法删除
+    pub fn clear(&self) -> Result<usize, FileError> {
+        if self.detached_sde.is_some() {
+            return Err(FileError::Unsupported);
+        }
+        let lock = self.file_lock();
+        let _guard = lock.as_ref().map(|lock| lock.write());
         let first_cluster = self.first_cluster() as u32;
+        self.delete_entries();
+        if first_cluster >= 2 && first_cluster < END_OF_CLUSTER {
+            let all_clusters = self.fs.read().fat.read().get_all_cluster_id(first_cluster);
+            let cluster_cnt = all_clusters.len();
+            self.fs.write().dealloc_cluster(all_clusters);
+            Ok(cluster_cnt)
+        } else {
+            Ok(0)
+        }
+    }
+
+    // 仅将目录项标记为删除, 不释放簇
+    pub(crate) fn delete_entries(&self) {
         for i in 0..self.lde_pos.len() {
             self.modify_lde(i, |lde: &mut LongDirEntry| {
                 lde.delete();
@@ -575,14 +1112,72 @@ impl VirtFile {
         self.modify_sde(|sde: &mut ShortDirEntry| {
             sde.delete();
         });
-        if first_cluster >= 2 && first_cluster < END_OF_CLUSTER {
-            let all_clusters = self.fs.read().fat.read().get_all_cluster_id(first_cluster);
-            let cluster_cnt = all_clusters.len();
-            self.fs.write().dealloc_cluster(all_clusters);
-            cluster_cnt
-        } else {
-            0
+    }
+
+    /// Move the file's data to one contiguous run of clusters if its chain is fragmented.
+    ///
+    /// The data is copied and flushed to the device before the short entry is pointed at the new
+    /// run, and the old clusters are freed only after that, so a crash leaves either the old or
+    /// the new chain referenced.
+    ///
+    /// Only regular files with a directory entry and no other open handle are supported
+    /// (`Unsupported` otherwise), as other handles would keep reading the freed clusters;
+    /// `NoSpace` if no free run is long enough.
+    pub fn defragment(&self) -> Result<(), FileError> {
+        if self.is_dir() || self.detached_sde.is_some() {
+            return Err(FileError::Unsupported);
+        }
+        // 其他句柄缓存了旧簇链, 搬移后它们会读写已释放的簇
+        let other_handles = match &self.open_lock {
+            Some(lock) => Arc::strong_count(lock) > 1,
+            None => true,
+        };
+        if other_handles {
+            return Err(FileError::Unsupported);
+        }
+        let lock = self.file_lock();
+        let _guard = lock.as_ref().map(|lock| lock.write());
+        let first_cluster = self.first_cluster() as u32;
+        if first_cluster == NEW_VIR_FILE_CLUSTER {
+            return Ok(());
+        }
+        let fs = self.fs.read();
+        if fs.chain_extents(first_cluster).len() <= 1 {
+            return Ok(());
         }
+        let old_clusters: Vec<u32> = fs.cluster_chain(first_cluster).collect();
+        let cluster_cnt = old_clusters.len();
+        let new_first = fs.alloc_contiguous(cluster_cnt).ok_or(FileError::NoSpace)?;
+
+        // 逐块复制数据
+        let device = fs.device();
+        let copy_block = |src: usize, dst: usize| -> Result<(), DeviceErr> {
+            let mut buf = [0u8; BLOCK_SIZE];
+            try_get_block_cache(src, Arc::clone(&device))?
+                .read()
+                .read(0, |cache: &[u8; BLOCK_SIZE]| buf.copy_from_slice(cache));
+            try_get_block_cache(dst, Arc::clone(&device))?
+                .write()
+                .modify(0, |cache: &mut [u8; BLOCK_SIZE]| {
+                    cache.copy_from_slice(&buf)
+                });
+            Ok(())
+        };
+        let copied = old_clusters.iter().enumerate().all(|(i, &old)| {
+            fs.cluster_blocks(old)
+                .zip(fs.cluster_blocks(new_first + i as u32))
+                .all(|(src, dst)| copy_block(src, dst).is_ok())
+        });
+        // 新数据写回磁盘后才修改目录项, 失败时释放新分配的簇
+        if !copied || fs.sync().is_err() {
+            fs.dealloc_cluster((new_first..new_first + cluster_cnt as u32).collect());
+            return Err(FileError::WriteError);
+        }
+
+        self.modify_sde(|sde| sde.set_first_cluster(new_first));
+        self.cluster_chain.write().refresh(new_first);
+        fs.dealloc_cluster(old_clusters);
+        Ok(())
     }
 
     /// 返回: (st_size, st_blksize, st_blocks, is_dir, time)
@@ -604,45 +1199,983 @@ impl VirtFile {
         })
     }
 
-    // 返回 (d_name, d_off, d_type)
+    // 返回 (d_name, d_off, first_cluster, d_type), d_off 为下一个目录项在目录文件中的偏移
     pub fn dir_info(&self, offset: usize) -> Option<(String, usize, usize, usize)> {
         if !self.is_dir() {
             return None;
         }
         let mut entry = LongDirEntry::empty();
         let mut index = offset;
-        let mut name = String::new();
-        let mut is_long = false;
         loop {
-            let read_size = self.read_at(index, entry.as_bytes_mut());
+            let read_size = self.read_lde_at(index, &mut entry);
             if read_size != DIRENT_SIZE || entry.is_empty() {
                 return None;
             }
-            if entry.is_deleted() {
+            // 跳过已删除及卷标目录项 (卷标不含长名目录项)
+            if entry.is_deleted()
+                || (entry.attr() != ATTR_LONG_NAME && entry.attr() & ATTR_VOLUME_ID != 0)
+            {
                 index += DIRENT_SIZE;
-                name.clear();
-                is_long = false;
                 continue;
             }
-            // 名称拼接
-            if entry.attr() != ATTR_LONG_NAME {
-                let sde: ShortDirEntry = unsafe { core::mem::transmute(entry) };
-                if !is_long {
-                    name = sde.get_name_lowercase();
+            let (name, sde, next_offset) = if entry.attr() == ATTR_LONG_NAME {
+                // 与 scan_entries 一致: 长名目录项必是长文件名最后的那一段,
+                // 序号或校验和不对的目录项组逐个目录项跳过, 而不是当作目录结束
+                match self.parse_lfn_run(index) {
+                    Some(run) => run,
+                    None => {
+                        index += DIRENT_SIZE;
+                        continue;
+                    }
                 }
-                let attribute = sde.attr();
-                let first_cluster = sde.first_cluster();
-                index += DIRENT_SIZE;
-                return Some((name, index, first_cluster as usize, attribute as usize));
             } else {
-                is_long = true;
-                name.insert_str(0, &entry.name().as_str());
+                let sde = ShortDirEntry::from_bytes(&entry.to_bytes_array());
+                (sde.get_name_lowercase(), sde, index + DIRENT_SIZE)
+            };
+            return Some((
+                name,
+                next_offset,
+                sde.first_cluster() as usize,
+                sde.attr() as usize,
+            ));
+        }
+    }
+
+    /// Read the next real entry of the directory starting at `offset` (getdents-style).
+    ///
+    /// Deleted and volume-id entries are skipped. Use [`DirEnt::offset`] of the result to continue,
+    /// `None` means the end of the directory.
+    pub fn read_dir_from(&self, offset: usize) -> Option<DirEnt> {
+        // dir_info 已跳过已删除及卷标目录项
+        let (name, next_offset, _, attr) = self.dir_info(offset)?;
+        // dir_info 返回的偏移为短目录项之后的位置
+        let sde_pos = self.dir_entry_pos(next_offset - DIRENT_SIZE)?;
+        Some(DirEnt {
+            name,
+            offset: next_offset,
+            inode: sde_pos.inode(),
+            d_type: DType::from_attr(attr as u8),
+        })
+    }
+
+    /// Read the next entry of the directory at `cursor`, returning it with the cursor to
+    /// continue from. `None` means the end of the directory.
+    ///
+    /// If the directory was modified since `cursor` was taken and it now points into the
+    /// middle of a long name entry set, that entry is skipped and reading resumes after its
+    /// short entry.
+    pub fn read_dir_at(&self, cursor: DirCursor) -> Option<(DirEnt, DirCursor)> {
+        if !self.is_dir() {
+            return None;
+        }
+        // 对齐到目录项边界
+        let mut offset = (cursor.0 as usize).div_ceil(DIRENT_SIZE) * DIRENT_SIZE;
+        let mut entry = LongDirEntry::empty();
+        if self.read_lde_at(offset, &mut entry) != DIRENT_SIZE || entry.is_empty() {
+            return None;
+        }
+        // 落在长名目录项序列中间: 跳到该序列的短目录项之后
+        if entry.attr() == ATTR_LONG_NAME && !entry.is_deleted() && !entry.is_lde_end() {
+            loop {
+                offset += DIRENT_SIZE;
+                if self.read_lde_at(offset, &mut entry) != DIRENT_SIZE || entry.is_empty() {
+                    return None;
+                }
+                // 序列被删除项打断时, 由 read_dir_from 从此处继续
+                if entry.is_deleted() {
+                    break;
+                }
+                if entry.attr() != ATTR_LONG_NAME {
+                    offset += DIRENT_SIZE;
+                    break;
+                }
             }
-            index += DIRENT_SIZE;
         }
+        let dirent = self.read_dir_from(offset)?;
+        let next = DirCursor(dirent.offset as u64);
+        Some((dirent, next))
     }
 
     pub fn set_time(&self, _sec: u64, _nsec: u64) {
         todo!("set_time");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dir::Dir,
+        lfn_checksum,
+        test_util::{pattern, read_all, TestFs},
+        LAST_LONG_ENTRY,
+    };
+
+    // 从 offset 0 开始用 dir_info 遍历目录, 返回所有名字
+    fn dir_info_names(dir: &VirtFile) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut offset = 0;
+        while let Some((name, next_offset, _, _)) = dir.dir_info(offset) {
+            names.push(name);
+            offset = next_offset;
+        }
+        names
+    }
+
+    #[test]
+    fn dir_info_skips_corrupt_lfn_run() {
+        let t = TestFs::new();
+        t.create_file("first file.txt", b"1");
+        t.create_file("broken name.txt", b"2");
+        t.create_file("third file.txt", b"3");
+        let root = t.root();
+
+        // 第二个文件的目录项组紧跟第一个文件的短目录项
+        let (_, broken_offset, _, _) = root.dir_info(0).unwrap();
+        // 破坏最后一段长名目录项的校验和 (偏移 13)
+        let mut checksum = [0u8; 1];
+        root.read_at(broken_offset + 13, &mut checksum);
+        root.write_in_bounds_at(broken_offset + 13, &[checksum[0] ^ 0xFF])
+            .unwrap();
+
+        // 损坏的长名被跳过, 之后的短目录项与其余文件照常列出
+        let names = dir_info_names(&root);
+        assert_eq!(names, ["first file.txt", "broken~1.txt", "third file.txt"]);
+        assert_eq!(names, root.ls().unwrap());
+    }
+
+    #[test]
+    fn concurrent_writes_to_one_file() {
+        const THREADS: usize = 4;
+        const ROUNDS: usize = 8;
+        let t = TestFs::new();
+        let chunk = t.cluster_size() + 100;
+        t.create_file("shared.bin", b"");
+
+        // 每个线程各自打开文件, 写入交错的块, 写入中文件不断增长
+        std::thread::scope(|scope| {
+            for id in 0..THREADS {
+                let root = t.root();
+                scope.spawn(move || {
+                    let file = root.find(vec!["shared.bin"]).unwrap();
+                    for round in 0..ROUNDS {
+                        let index = round * THREADS + id;
+                        let data = pattern(chunk, index as u8);
+                        assert_eq!(file.write_at(index * chunk, &data), chunk);
+                    }
+                });
+            }
+        });
+
+        let a = t.root().find(vec!["shared.bin"]).unwrap();
+        let b = t.root().find(vec!["shared.bin"]).unwrap();
+        assert!(Arc::ptr_eq(
+            &a.file_lock().unwrap(),
+            &b.file_lock().unwrap()
+        ));
+        let content = read_all(&a);
+        assert_eq!(content.len(), THREADS * ROUNDS * chunk);
+        for (index, data) in content.chunks(chunk).enumerate() {
+            assert_eq!(data, pattern(chunk, index as u8));
+        }
+        let clusters = t.fs.read().chain_len_checked(a.first_cluster() as u32);
+        assert_eq!(clusters, Some(content.len().div_ceil(t.cluster_size())));
+    }
+
+    #[test]
+    fn preallocate_on_full_volume_returns_no_space() {
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        // 只留下 10 个空闲簇
+        let free = t.free_cluster_cnt();
+        assert!(t.fs.read().alloc_cluster_no_clear(free - 10, 0).is_some());
+
+        let file = t.create_file("fill.bin", b"");
+        let chunk = pattern(3 * cluster_size, 14);
+        let mut offset = 0;
+        let err = loop {
+            match file.write_all_at(offset, &chunk) {
+                Ok(()) => offset += chunk.len(),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(err, FileError::NoSpace);
+        // 写入了能容纳的部分
+        assert_eq!(file.file_size(), 10 * cluster_size);
+        assert_eq!(t.free_cluster_cnt(), 0);
+
+        let other = t.create_file("other.bin", b"");
+        assert_eq!(
+            other.preallocate(cluster_size, false),
+            Err(FileError::NoSpace)
+        );
+        assert_eq!(other.file_size(), 0);
+        assert_eq!(
+            file.preallocate(file.file_size() + 1, true),
+            Err(FileError::NoSpace)
+        );
+    }
+
+    #[test]
+    fn inode_identifies_entry() {
+        let t = TestFs::new();
+        t.create_file("one.txt", b"1");
+        t.create_file("two.txt", b"2");
+        let root = t.root();
+        let one = root.find(vec!["one.txt"]).unwrap();
+        let one_again = root.find(vec!["one.txt"]).unwrap();
+        let two = root.find(vec!["two.txt"]).unwrap();
+
+        assert_eq!(one.inode(), one_again.inode());
+        assert_ne!(one.inode(), two.inode());
+        assert_eq!(root.inode(), ROOT_INODE);
+        assert_eq!(root.read_dir_from(0).unwrap().inode, one.inode());
+
+        // 重新挂载后不变
+        let inode = one.inode();
+        drop((one, one_again, two, root));
+        let t = t.reopen();
+        assert_eq!(t.root().find(vec!["one.txt"]).unwrap().inode(), inode);
+    }
+
+    #[test]
+    fn allocated_size_counts_whole_clusters() {
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        let empty = t.create_file("empty.txt", b"");
+        assert_eq!(empty.allocated_size(), 0);
+
+        let file = t.create_file("ten.txt", &[7u8; 10]);
+        assert_eq!(file.file_size(), 10);
+        assert_eq!(file.allocated_size(), cluster_size);
+        assert_eq!(
+            t.fs.read().chain_len_checked(file.first_cluster() as u32),
+            Some(1)
+        );
+
+        assert_eq!(file.write_at(10, &pattern(cluster_size, 17)), cluster_size);
+        assert_eq!(file.file_size(), cluster_size + 10);
+        assert_eq!(file.allocated_size(), 2 * cluster_size);
+    }
+
+    #[test]
+    fn dir_info_assembles_multi_entry_long_name() {
+        let t = TestFs::new();
+        // 40 个字符, 占用 4 个长名目录项
+        let name = "a long file name of exactly forty chars!";
+        assert_eq!(name.len(), 40);
+        let file = t.create_file(name, b"data");
+        let root = t.root();
+
+        let (read_name, next_offset, first_cluster, attr) = root.dir_info(0).unwrap();
+        assert_eq!(read_name, name);
+        assert_eq!(first_cluster, file.first_cluster());
+        assert_eq!(attr as u8 & ATTR_DIRECTORY, 0);
+        // 4 个长名目录项与 1 个短目录项
+        assert_eq!(next_offset, 5 * DIRENT_SIZE);
+        assert_eq!(root.dir_info(next_offset), None);
+    }
+
+    #[test]
+    fn read_dir_from_walks_entries_with_types() {
+        let t = TestFs::new();
+        let dir = t.root().create("dir", VirtFileType::Dir).unwrap();
+        let file = dir.create("file.txt", VirtFileType::File).unwrap();
+        let sub = dir.create("sub", VirtFileType::Dir).unwrap();
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while let Some(ent) = dir.read_dir_from(offset) {
+            assert!(ent.offset > offset);
+            offset = ent.offset;
+            entries.push(ent);
+        }
+        let summary: Vec<(&str, DType)> = entries
+            .iter()
+            .map(|ent| (ent.name.as_str(), ent.d_type))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (".", DType::Dir),
+                ("..", DType::Dir),
+                ("file.txt", DType::File),
+                ("sub", DType::Dir)
+            ]
+        );
+        assert!(entries[0].is_dot_entry());
+        assert_eq!(entries[2].inode, file.inode());
+        assert_eq!(entries[3].inode, sub.inode());
+        // 从中间的偏移继续读取
+        assert_eq!(dir.read_dir_from(entries[2].offset).unwrap().name, "sub");
+    }
+
+    #[test]
+    fn preallocate_reserves_clusters() {
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        let free = t.free_cluster_cnt();
+
+        let kept = t.create_file("kept.bin", b"abc");
+        kept.preallocate(4 * cluster_size, true).unwrap();
+        assert_eq!(kept.file_size(), 3);
+        assert_eq!(kept.allocated_size(), 4 * cluster_size);
+        assert_eq!(t.free_cluster_cnt(), free - 4);
+
+        // 在预留的范围内写入不再分配簇
+        let data = pattern(3 * cluster_size, 9);
+        assert_eq!(kept.write_at(cluster_size, &data), data.len());
+        assert_eq!(t.free_cluster_cnt(), free - 4);
+
+        let grown = t.create_file("grown.bin", b"");
+        grown.preallocate(cluster_size + 1, false).unwrap();
+        assert_eq!(grown.file_size(), cluster_size + 1);
+        assert_eq!(read_all(&grown), vec![0; cluster_size + 1]);
+        assert_eq!(t.free_cluster_cnt(), free - 6);
+
+        // 不会缩小文件
+        grown.preallocate(1, false).unwrap();
+        assert_eq!(grown.file_size(), cluster_size + 1);
+        assert_eq!(
+            grown.preallocate(MAX_FILE_SIZE + 1, false),
+            Err(FileError::FileTooLarge)
+        );
+    }
+
+    #[test]
+    fn new_clusters_never_expose_stale_data() {
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        let covered = t.create_file("covered.bin", b"");
+        let sparse = t.create_file("sparse.bin", b"");
+
+        // 接下来分配的簇中残留旧数据
+        let hint = t.fs.read().next_free_hint();
+        for cluster in hint..hint + 8 {
+            for block_id in t.fs.read().cluster_blocks(cluster) {
+                let stale = [0xA5u8; BLOCK_SIZE];
+                t.device
+                    .write_blocks(&stale, block_id * BLOCK_SIZE, 1)
+                    .unwrap();
+            }
+        }
+
+        // 最后一个簇只写入一半, 其余部分被清零
+        let data = pattern(cluster_size + cluster_size / 2, 4);
+        assert_eq!(covered.write_at(0, &data), data.len());
+        t.fs.read().sync().unwrap();
+        let last =
+            t.fs.read()
+                .cluster_chain(covered.first_cluster() as u32)
+                .nth(1)
+                .unwrap();
+        let mut on_disk = Vec::new();
+        for block_id in t.fs.read().cluster_blocks(last) {
+            let mut block = [0u8; BLOCK_SIZE];
+            t.device
+                .read_blocks(&mut block, block_id * BLOCK_SIZE, 1)
+                .unwrap();
+            on_disk.extend_from_slice(&block);
+        }
+        assert!(on_disk[cluster_size / 2..].iter().all(|&b| b == 0));
+
+        // 跳过的簇读出为 0
+        assert_eq!(sparse.write_at(3 * cluster_size, b"tail"), 4);
+        let content = read_all(&sparse);
+        assert!(content[..3 * cluster_size].iter().all(|&b| b == 0));
+        assert_eq!(&content[3 * cluster_size..], b"tail");
+        assert_eq!(read_all(&covered), data);
+    }
+
+    #[test]
+    fn read_updates_last_access_date_when_enabled() {
+        // 2024-05-17
+        const DATE: u16 = (44 << 9) | (5 << 5) | 17;
+        let t = TestFs::new();
+        t.fs.write().set_clock(|| (DATE, 0));
+        let file = t.create_file("atime.txt", b"hello");
+        let epoch = file.last_access_date();
+        assert_ne!(epoch, DATE);
+
+        // 默认关闭
+        let mut buf = [0u8; 5];
+        assert_eq!(file.read_at(0, &mut buf), 5);
+        assert_eq!(file.last_access_date(), epoch);
+
+        t.fs.write().set_update_atime(true);
+        assert_eq!(file.read_at(0, &mut buf), 5);
+        assert_eq!(file.last_access_date(), DATE);
+        let found = t.root().find(vec!["atime.txt"]).unwrap();
+        assert_eq!(found.read_sde(|sde| sde.last_access_date()), DATE);
+    }
+
+    #[test]
+    fn raw_entries_in_on_disk_order() {
+        let t = TestFs::new();
+        // 30 个字符, 需要 3 个长名目录项
+        let file = t.create_file("a name spanning three entries", b"x");
+        let entries = file.raw_entries();
+        assert_eq!(entries.len(), 4);
+
+        let sde = entries[3];
+        let short_name: [u8; 11] = sde[..11].try_into().unwrap();
+        assert_eq!(&short_name, b"ANAMES~1   ");
+        for (i, lde) in entries[..3].iter().enumerate() {
+            let order = 3 - i as u8;
+            let expected = if i == 0 {
+                order | LAST_LONG_ENTRY
+            } else {
+                order
+            };
+            assert_eq!(lde[0], expected);
+            assert_eq!(lde[11], ATTR_LONG_NAME);
+            assert_eq!(lde[13], lfn_checksum(&short_name));
+        }
+
+        assert!(t.root().raw_entries().is_empty());
+    }
+
+    #[test]
+    fn read_clusters_streams_the_file() {
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        let data = pattern(2 * cluster_size + cluster_size / 2, 12);
+        let file = t.create_file("stream.bin", &data);
+
+        let bufs: Vec<ClusterBuf> = file.read_clusters(0).map(Result::unwrap).collect();
+        let lens: Vec<usize> = bufs.iter().map(Vec::len).collect();
+        assert_eq!(lens, [cluster_size, cluster_size, cluster_size / 2]);
+        assert_eq!(bufs.concat(), data);
+        let tail: Vec<ClusterBuf> = file.read_clusters(1).map(Result::unwrap).collect();
+        assert_eq!(tail.concat(), &data[cluster_size..]);
+        assert_eq!(file.read_clusters(3).count(), 0);
+
+        // 簇链比文件大小短
+        let first = file.first_cluster() as u32;
+        t.fs.read()
+            .fat
+            .write()
+            .set_next_cluster(first, END_OF_CLUSTER);
+        let mut clusters = file.read_clusters(0);
+        assert_eq!(clusters.next().unwrap().unwrap(), &data[..cluster_size]);
+        assert_eq!(clusters.next(), Some(Err(FileError::BadClusterChain)));
+        assert_eq!(clusters.next(), None);
+    }
+
+    #[test]
+    fn crc32_of_file_contents() {
+        let t = TestFs::new();
+        // CRC-32 的标准校验值
+        let check = t.create_file("check.txt", b"123456789");
+        assert_eq!(check.crc32(), Ok(0xCBF4_3926));
+        assert_eq!(t.create_file("empty.txt", b"").crc32(), Ok(0));
+
+        // 跨越多个簇时与一次性计算的结果一致
+        let data = pattern(3 * t.cluster_size() + 17, 6);
+        let file = t.create_file("multi.bin", &data);
+        let mut hasher = Crc32::new();
+        hasher.write(&data);
+        assert_eq!(file.crc32(), Ok(hasher.value()));
+        let mut digest = Crc32::default();
+        file.digest(&mut digest).unwrap();
+        assert_eq!(digest.finish(), hasher.value() as u64);
+    }
+
+    #[test]
+    fn write_in_bounds_at_never_resizes() {
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        let mut data = pattern(cluster_size + 10, 3);
+        let file = t.create_file("records.bin", &data);
+        let free = t.free_cluster_cnt();
+
+        // 跨越簇边界覆盖写入
+        file.write_in_bounds_at(cluster_size - 4, b"RECORD!!")
+            .unwrap();
+        data[cluster_size - 4..cluster_size + 4].copy_from_slice(b"RECORD!!");
+        assert_eq!(read_all(&file), data);
+
+        // 越过文件大小时什么也不写, 即使仍在已分配的簇内
+        assert_eq!(
+            file.write_in_bounds_at(cluster_size + 8, b"xyz"),
+            Err(FileError::ReadOutOfBound)
+        );
+        assert_eq!(file.file_size(), cluster_size + 10);
+        assert_eq!(read_all(&file), data);
+        assert_eq!(t.free_cluster_cnt(), free);
+        assert_eq!(file.write_in_bounds_at(data.len(), b""), Ok(()));
+    }
+
+    #[test]
+    fn write_from_iter_streams_bytes() {
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        let data = pattern(2 * cluster_size + cluster_size / 2, 8);
+        let file = t.create_file("iter.bin", b"head");
+
+        // 从簇中间开始写入
+        let written = file.write_from_iter(4, data.iter().copied()).unwrap();
+        assert_eq!(written, data.len());
+        let mut expected = b"head".to_vec();
+        expected.extend_from_slice(&data);
+        assert_eq!(read_all(&file), expected);
+        assert_eq!(file.write_from_iter(0, iter::empty()), Ok(0));
+
+        // 空间不足时写入能容纳的部分
+        let free = t.free_cluster_cnt();
+        assert!(t.fs.read().alloc_cluster_no_clear(free - 1, 0).is_some());
+        let full = t.create_file("full.bin", b"");
+        assert_eq!(
+            full.write_from_iter(0, iter::repeat_n(7, 3 * cluster_size)),
+            Err(FileError::NoSpace)
+        );
+        assert_eq!(read_all(&full), vec![7; cluster_size]);
+    }
+
+    #[test]
+    fn read_write_at_cross_block_boundaries() {
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        let len = 3 * cluster_size;
+        let mut expected = pattern(len, 9);
+        let file = t.create_file("span.bin", &expected);
+
+        // 跨越块 (簇) 边界的读写
+        for &(offset, n) in &[
+            (0, 1),
+            (BLOCK_SIZE - 3, 7),
+            (cluster_size - 1, cluster_size + 2),
+            (2 * cluster_size + 5, cluster_size - 5),
+        ] {
+            let mut buf = vec![0u8; n];
+            assert_eq!(file.read_at(offset, &mut buf), n);
+            assert_eq!(buf, &expected[offset..offset + n]);
+
+            let patch = pattern(n, offset as u8);
+            assert_eq!(file.write_at(offset, &patch), n);
+            expected[offset..offset + n].copy_from_slice(&patch);
+            assert_eq!(read_all(&file), expected);
+        }
+
+        // 越过文件末尾只读到剩余部分, 恰在末尾读到 0 字节
+        let mut buf = vec![0u8; 10];
+        assert_eq!(file.read_at(len - 4, &mut buf), 4);
+        assert_eq!(&buf[..4], &expected[len - 4..]);
+        assert_eq!(file.read_at(len, &mut buf), 0);
+    }
+
+    #[test]
+    fn read_past_short_cluster_chain_zeroes_the_hole() {
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        let data = pattern(cluster_size, 10);
+        let file = t.create_file("short.bin", &data);
+        // 模拟损坏的镜像: file_size 大于簇链长度
+        file.set_file_size(3 * cluster_size);
+
+        let mut buf = vec![0xAA; 3 * cluster_size];
+        assert_eq!(file.read_at(0, &mut buf), cluster_size);
+        assert_eq!(&buf[..cluster_size], &data[..]);
+        assert!(buf[cluster_size..].iter().all(|&b| b == 0));
+
+        // 起点在簇链之后
+        let mut buf = vec![0xAA; 16];
+        assert_eq!(file.read_at(2 * cluster_size, &mut buf), 0);
+        assert_eq!(buf, vec![0; 16]);
+    }
+
+    #[test]
+    fn attribute_bits_are_independent() {
+        let t = TestFs::new();
+        let file = t.create_file("attr.txt", b"x");
+        let dir = t.root().create("attrdir", VirtFileType::Dir).unwrap();
+        assert!(!file.is_readonly() && !file.is_hidden() && !file.is_system());
+
+        file.set_readonly(true);
+        file.set_hidden(true);
+        file.set_system(true);
+        file.set_archive(false);
+        assert!(file.is_readonly() && file.is_hidden() && file.is_system());
+        assert!(!file.is_archive());
+        file.set_system(false);
+        assert!(file.is_readonly() && file.is_hidden() && !file.is_system());
+        dir.set_hidden(true);
+        assert_eq!(dir.read_sde(|sde| sde.attr()), ATTR_DIRECTORY | ATTR_HIDDEN);
+
+        // 设置了其他属性位后仍能正确区分文件与目录
+        drop((file, dir));
+        let t = t.reopen();
+        let root = t.root();
+        let file = root.find_by_name("attr.txt").unwrap();
+        assert!(file.is_file() && file.is_readonly() && file.is_hidden());
+        assert!(file.read_sde(|sde| sde.is_file() && !sde.is_dir()));
+        let dir = root.find_by_name("attrdir").unwrap();
+        assert!(dir.is_dir() && dir.is_hidden());
+        assert!(dir.read_sde(|sde| sde.is_dir() && !sde.is_file()));
+    }
+
+    #[test]
+    fn defragment_moves_file_onto_one_run() {
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        let a = t.create_file("a.bin", b"");
+        let b = t.create_file("b.bin", b"");
+        // 交替追加使两个文件的簇链互相穿插
+        let mut a_data = Vec::new();
+        let mut b_data = Vec::new();
+        for i in 0..4 {
+            let chunk = pattern(cluster_size, i);
+            assert_eq!(a.write_at(a_data.len(), &chunk), cluster_size);
+            a_data.extend_from_slice(&chunk);
+            let chunk = pattern(cluster_size, 100 + i);
+            assert_eq!(b.write_at(b_data.len(), &chunk), cluster_size);
+            b_data.extend_from_slice(&chunk);
+        }
+        let extents = |file: &VirtFile| t.fs.read().chain_extents(file.first_cluster() as u32);
+        assert_eq!(extents(&a).len(), 4);
+
+        let free = t.free_cluster_cnt();
+        a.defragment().unwrap();
+        assert_eq!(extents(&a), vec![(a.first_cluster() as u32, 4)]);
+        assert_eq!(t.free_cluster_cnt(), free);
+        assert_eq!(read_all(&a), a_data);
+        assert_eq!(read_all(&b), b_data);
+
+        // 已连续时不做任何事
+        let first = a.first_cluster();
+        a.defragment().unwrap();
+        assert_eq!(a.first_cluster(), first);
+
+        // 目录不支持
+        let dir = t.root().create("dir", VirtFileType::Dir).unwrap();
+        assert_eq!(dir.defragment(), Err(FileError::Unsupported));
+    }
+
+    #[test]
+    fn defragment_refuses_while_other_handles_are_open() {
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        let a = t.create_file("a.bin", b"");
+        let b = t.create_file("b.bin", b"");
+        let mut a_data = Vec::new();
+        for i in 0..3 {
+            let chunk = pattern(cluster_size, i);
+            assert_eq!(a.write_at(a_data.len(), &chunk), cluster_size);
+            a_data.extend_from_slice(&chunk);
+            assert_eq!(b.write_at(i as usize * cluster_size, &chunk), cluster_size);
+        }
+
+        let other = t.root().find_by_name("a.bin").unwrap();
+        let first = a.first_cluster();
+        assert_eq!(a.defragment(), Err(FileError::Unsupported));
+        assert_eq!(a.first_cluster(), first);
+        assert_eq!(read_all(&other), a_data);
+
+        // 其他句柄关闭后即可整理
+        drop(other);
+        a.defragment().unwrap();
+        assert_eq!(t.fs.read().chain_extents(a.first_cluster() as u32).len(), 1);
+        assert_eq!(read_all(&a), a_data);
+    }
+
+    #[test]
+    fn entry_pos_is_none_past_the_chain() {
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        let dir = t.root().create("dir", VirtFileType::Dir).unwrap();
+        let first = dir.first_cluster() as u32;
+        assert_eq!(t.fs.read().chain_extents(first), vec![(first, 1)]);
+
+        let last = cluster_size - DIRENT_SIZE;
+        let pos = dir.dir_entry_pos(last).unwrap();
+        assert_eq!((pos.cluster, pos.offset_in_cluster), (first, last));
+        let first_block = t.fs.read().cluster_to_block(first);
+        assert_eq!(
+            dir.offset_block_pos(last),
+            Some((first_block + last / BLOCK_SIZE, last % BLOCK_SIZE))
+        );
+        assert!(dir.dir_entry_pos(cluster_size).is_none());
+        assert!(dir.offset_block_pos(cluster_size).is_none());
+
+        // 尚未分配簇
+        let empty = t.create_file("empty", b"");
+        assert_eq!(empty.first_cluster() as u32, NEW_VIR_FILE_CLUSTER);
+        assert!(empty.dir_entry_pos(0).is_none());
+        assert!(empty.offset_block_pos(0).is_none());
+    }
+
+    #[test]
+    fn block_for_offset_locates_file_bytes_on_disk() {
+        use crate::device::BlockDevice;
+
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        let data = pattern(2 * cluster_size + 100, 11);
+        let file = t.create_file("map.bin", &data);
+        t.fs.read().sync().unwrap();
+
+        for offset in [0, 1, BLOCK_SIZE + 7, cluster_size + 300, data.len() - 1] {
+            let (block_id, offset_in_block) = file.block_for_offset(offset).unwrap();
+            let mut block = [0u8; BLOCK_SIZE];
+            t.device
+                .read_blocks(&mut block, block_id * BLOCK_SIZE, 1)
+                .unwrap();
+            assert_eq!(block[offset_in_block], data[offset]);
+        }
+        // 已分配但超出文件大小的部分仍有位置, 超出簇链则没有
+        assert!(file.block_for_offset(data.len()).is_some());
+        assert!(file.block_for_offset(file.allocated_size()).is_none());
+    }
+
+    #[test]
+    fn read_dir_at_resumes_from_saved_cursor() {
+        let t = TestFs::new();
+        let root = t.root();
+        let long = "a rather long name for a file.txt";
+        for name in ["first.txt", long, "last.txt"] {
+            root.create(name, VirtFileType::File).unwrap();
+        }
+
+        // 每次只读一项, 游标经 raw 值保存与恢复
+        let mut names = Vec::new();
+        let mut raw = DirCursor::START.to_raw();
+        while let Some((dirent, next)) = root.read_dir_at(DirCursor::from_raw(raw)) {
+            names.push(dirent.name);
+            raw = next.to_raw();
+        }
+        assert_eq!(names, vec!["first.txt", long, "last.txt"]);
+
+        // 游标落在长名目录项序列中间 (或未对齐) 时跳过该项
+        let (first, after_first) = root.read_dir_at(DirCursor::START).unwrap();
+        assert_eq!(first.name, "first.txt");
+        let long_start = after_first.to_raw();
+        for raw in [long_start + DIRENT_SIZE as u64, long_start + 5] {
+            let (dirent, _) = root.read_dir_at(DirCursor::from_raw(raw)).unwrap();
+            assert_eq!(dirent.name, "last.txt");
+        }
+        assert!(root.read_dir_at(DirCursor::from_raw(raw)).is_none());
+    }
+
+    #[test]
+    fn export_to_streams_cluster_sized_chunks() {
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        let data = pattern(2 * cluster_size + 77, 16);
+        let file = t.create_file("export.bin", &data);
+
+        let mut out = Vec::new();
+        let mut chunks = Vec::new();
+        let exported = file
+            .export_to(&mut |buf: &[u8]| {
+                chunks.push(buf.len());
+                out.extend_from_slice(buf);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(exported, data.len());
+        assert_eq!(out, data);
+        // 最后一块以 file_size 为界
+        assert_eq!(chunks, vec![cluster_size, cluster_size, 77]);
+
+        // sink 失败时停止
+        let mut calls = 0;
+        let result = file.export_to(&mut |_: &[u8]| {
+            calls += 1;
+            Err(())
+        });
+        assert_eq!(result, Err(FileError::WriteError));
+        assert_eq!(calls, 1);
+
+        let dir = t.root().create("dir", VirtFileType::Dir).unwrap();
+        assert_eq!(
+            dir.export_to(&mut |_: &[u8]| Ok(())),
+            Err(FileError::Unsupported)
+        );
+    }
+
+    #[test]
+    fn concurrent_reads_of_a_fragmented_file() {
+        const THREADS: usize = 4;
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        let file = t.create_file("frag.bin", b"");
+        let other = t.create_file("other.bin", b"");
+        // 与另一个文件交替分配, 使簇链不连续
+        let mut data = Vec::new();
+        for i in 0..6 {
+            let chunk = pattern(cluster_size, i);
+            assert_eq!(file.write_at(data.len(), &chunk), cluster_size);
+            data.extend_from_slice(&chunk);
+            let filler = pattern(cluster_size, 50 + i);
+            assert_eq!(
+                other.write_at(i as usize * cluster_size, &filler),
+                cluster_size
+            );
+        }
+        assert!(t.fs.read().chain_extents(file.first_cluster() as u32).len() > 1);
+
+        // 多个线程同时读取不同区间
+        std::thread::scope(|scope| {
+            for id in 0..THREADS {
+                let root = t.root();
+                let data = &data;
+                scope.spawn(move || {
+                    let file = root.find(vec!["frag.bin"]).unwrap();
+                    let offset = id * cluster_size + id * 37;
+                    let mut buf = vec![0u8; data.len() - offset];
+                    for _ in 0..4 {
+                        assert_eq!(file.read_at(offset, &mut buf), buf.len());
+                        assert_eq!(buf, &data[offset..]);
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn prefetch_fills_the_cache_ahead_of_reads() {
+        use crate::device::FaultDevice;
+
+        let t = TestFs::exclusive();
+        let data = pattern(4 * BLOCK_SIZE + 10, 20);
+        t.create_file("small.bin", &data);
+        let big = pattern(40 * BLOCK_SIZE, 21);
+        t.create_file("big.bin", &big);
+        t.fs.read().sync().unwrap();
+        drop(t.fs);
+
+        // 经 FaultDevice 重新挂载以统计设备读次数 (新设备在缓存中没有任何块)
+        let fault = Arc::new(FaultDevice::new(t.device.clone()));
+        let fs = FileSystem::open(fault.clone());
+        fs.write().set_update_atime(false);
+        let root = fs.read().root();
+        let file = root.find_by_name("small.bin").unwrap();
+
+        assert_eq!(file.prefetch(0, data.len()), Ok(data.len()));
+        let reads = fault.read_count();
+        assert_eq!(read_all(&file), data);
+        assert_eq!(fault.read_count(), reads);
+
+        // 以文件末尾为界
+        assert_eq!(file.prefetch(data.len() - 10, 100), Ok(10));
+        assert_eq!(file.prefetch(data.len(), 100), Ok(0));
+        // 最多预读缓存容量的一半
+        let big_file = root.find_by_name("big.bin").unwrap();
+        assert_eq!(
+            big_file.prefetch(0, big.len()),
+            Ok(BLOCK_CACHE_LIMIT / 2 * BLOCK_SIZE)
+        );
+    }
+
+    #[test]
+    fn writes_past_max_file_size_are_rejected() {
+        use crate::file::{File, WriteType};
+
+        let t = TestFs::new();
+        let free = t.free_cluster_cnt();
+        let file = t.create_file("huge.bin", b"data");
+
+        assert_eq!(file.write_at(MAX_FILE_SIZE - 2, b"abc"), 0);
+        assert_eq!(
+            file.write_all_at(MAX_FILE_SIZE - 2, b"abc"),
+            Err(FileError::FileTooLarge)
+        );
+        assert_eq!(
+            file.preallocate(MAX_FILE_SIZE + 1, true),
+            Err(FileError::FileTooLarge)
+        );
+        // 没有写入任何内容, 也没有分配簇
+        assert_eq!(read_all(&file), b"data");
+        assert_eq!(t.free_cluster_cnt(), free - 1);
+
+        // 追加写以当前 file_size 为起点
+        file.set_file_size(MAX_FILE_SIZE - 1);
+        assert_eq!(
+            file.write(b"ab", WriteType::Append),
+            Err(FileError::FileTooLarge)
+        );
+        assert_eq!(file.file_size(), MAX_FILE_SIZE - 1);
+    }
+
+    #[test]
+    fn data_byte_ranges_cover_the_file_on_the_volume() {
+        use crate::device::BlockDevice;
+
+        let t = TestFs::new();
+        let cluster_size = t.cluster_size();
+        let file = t.create_file("ranges.bin", &pattern(cluster_size, 27));
+        t.create_file("between.bin", &pattern(cluster_size, 28));
+        // 追加一个半簇, 与第一个簇不相邻
+        let mut data = pattern(cluster_size, 27);
+        let tail = pattern(cluster_size + cluster_size / 2, 29);
+        assert_eq!(file.write_at(data.len(), &tail), tail.len());
+        data.extend_from_slice(&tail);
+        t.fs.read().sync().unwrap();
+
+        let ranges = file.data_byte_ranges();
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(ranges[0].len(), cluster_size);
+        let total: usize = ranges.iter().map(|range| range.len()).sum();
+        assert_eq!(total, file.allocated_size());
+
+        // 按顺序拼接设备上的这些字节, 前 file_size 个字节即文件内容
+        let mut on_disk = Vec::new();
+        for range in ranges {
+            let mut buf = vec![0u8; range.len()];
+            t.device
+                .read_blocks(&mut buf, range.start, range.len() / BLOCK_SIZE)
+                .unwrap();
+            on_disk.extend_from_slice(&buf);
+        }
+        assert_eq!(&on_disk[..data.len()], &data[..]);
+
+        assert!(t.create_file("empty", b"").data_byte_ranges().is_empty());
+    }
+
+    #[test]
+    fn writing_data_sets_the_archive_bit() {
+        let t = TestFs::new();
+        let file = t.create_file("backup.txt", b"v1");
+        file.set_archive(false);
+        assert_eq!(file.write_at(0, b""), 0);
+        assert!(!file.is_archive());
+
+        assert_eq!(file.write_at(2, b" v2"), 3);
+        assert!(file.is_archive());
+        file.set_archive(false);
+        file.write_in_bounds_at(0, b"V").unwrap();
+        assert!(file.is_archive());
+        file.set_archive(false);
+        file.write_from_iter(5, b"!".iter().copied()).unwrap();
+        assert!(file.is_archive());
+        // 其他属性位不受影响
+        file.set_archive(false);
+        file.set_readonly(true);
+        assert_eq!(file.write_at(0, b"v"), 1);
+        assert!(file.is_archive() && file.is_readonly());
+
+        // 目录写入目录项不设置
+        let dir = t.root().create("dir", VirtFileType::Dir).unwrap();
+        dir.create("inner.txt", VirtFileType::File).unwrap();
+        assert!(!dir.is_archive());
+    }
+
+    #[test]
+    fn name_case_flags_read_the_nt_res_byte() {
+        let t = TestFs::new();
+        let root = t.root();
+        let file = root.create("readme.txt", VirtFileType::File).unwrap();
+        assert_eq!(file.name_case_flags(), crate::ALL_UPPER_CASE);
+
+        // DIR_NTRes 位于短目录项偏移 12
+        file.modify_sde(|sde| sde.set_name_case(crate::ALL_LOWER_CASE));
+        assert_eq!(
+            file.read_sde(|sde| sde.as_bytes()[12]),
+            crate::ALL_LOWER_CASE
+        );
+        drop((file, root));
+        let t = t.reopen();
+        let file = t.root().find_by_name("readme.txt").unwrap();
+        assert_eq!(file.name_case_flags(), crate::ALL_LOWER_CASE);
+    }
+}